@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Any input must run to completion: faults are reported, never panics.
+fuzz_target!(|data: &[u8]| {
+    chip8::fuzz::run(data);
+});
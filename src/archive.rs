@@ -0,0 +1,119 @@
+//! ROMs inside ZIP archives.
+//!
+//! An entry is addressed by the archive's path and the entry's name joined
+//! with a colon, e.g. `assets/chp8_220.zip:CHIP8/GAMES/PONG`.
+
+use crate::asm::MAX_SIZE;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+pub fn is_archive(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("zip"),
+        None => false,
+    }
+}
+
+/// Splits `archive.zip:NAME` into the archive and the entry name.
+pub fn split(path: &Path) -> Option<(PathBuf, String)> {
+    let text = path.to_str()?;
+    // ASCII only, so offsets into the lowercased text hold for `text`.
+    let end = text.to_ascii_lowercase().find(".zip:")? + ".zip".len();
+    Some((PathBuf::from(&text[..end]), text[end + 1..].to_string()))
+}
+
+/// The path of entry `name` in `archive`.
+pub fn join(archive: &Path, name: &str) -> PathBuf {
+    PathBuf::from(format!("{}:{}", archive.display(), name))
+}
+
+fn open(archive: &Path) -> io::Result<ZipArchive<File>> {
+    ZipArchive::new(File::open(archive)?).map_err(|e| {
+        let message = format!("{}: {}", archive.display(), e);
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+/// Reads at most `limit` bytes of an entry, or `None` when it holds more.
+/// The size in the header is not trusted for an allocation.
+fn read_entry(file: impl Read, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    file.take(limit as u64 + 1).read_to_end(&mut data)?;
+    Ok(if data.len() > limit { None } else { Some(data) })
+}
+
+/// Every file in `archive` small enough to be a ROM with its contents, in
+/// archive order.
+pub fn entries(archive: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut zip = open(archive)?;
+    let mut entries = Vec::new();
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        if file.is_dir() || file.size() > MAX_SIZE as u64 {
+            continue;
+        }
+        let name = file.name().to_string();
+        if let Some(data) = read_entry(file, MAX_SIZE)? {
+            entries.push((name, data));
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a file, or an entry when `path` points into an archive. Entries
+/// larger than `limit` bytes are rejected; files are read whole.
+pub fn read(path: &Path, limit: usize) -> io::Result<Vec<u8>> {
+    let (archive, name) = match split(path) {
+        Some(parts) => parts,
+        None => return fs::read(path),
+    };
+    let mut zip = open(&archive)?;
+    let file = zip.by_name(&name).map_err(|_| {
+        let message = format!("{} has no entry {}", archive.display(), name);
+        io::Error::new(io::ErrorKind::NotFound, message)
+    })?;
+    read_entry(file, limit)?.ok_or_else(|| {
+        let message = format!("{} is larger than {} bytes", path.display(), limit);
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PACK: &str = "assets/chp8_220.zip";
+
+    #[test]
+    fn test_paths() {
+        let path = join(Path::new(PACK), "CHIP8/GAMES/PONG");
+        assert_eq!(path, Path::new("assets/chp8_220.zip:CHIP8/GAMES/PONG"));
+        let (archive, name) = split(&path).unwrap();
+        assert_eq!(
+            (archive.as_path(), name.as_str()),
+            (Path::new(PACK), "CHIP8/GAMES/PONG")
+        );
+        assert_eq!(split(Path::new("assets/games/PONG2")), None);
+        assert_eq!(
+            split(Path::new("İ.zip:GAME")),
+            Some((PathBuf::from("İ.zip"), "GAME".to_string()))
+        );
+        assert!(is_archive(Path::new("ROMS.ZIP")));
+    }
+
+    #[test]
+    fn test_read() {
+        let pong = read(&join(Path::new(PACK), "CHIP8/GAMES/PONG"), MAX_SIZE).unwrap();
+        assert_eq!(pong, fs::read("assets/CHIP8/GAMES/PONG").unwrap());
+        let missing = read(&join(Path::new(PACK), "CHIP8/GAMES/NOPE"), MAX_SIZE);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let docs = read(&join(Path::new(PACK), "CHIP8/DOCS/CHIP8.DOC"), MAX_SIZE);
+        assert_eq!(docs.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let entries = entries(Path::new(PACK)).unwrap();
+        assert!(entries.iter().any(|(name, _)| name == "CHIP8/SGAMES/ALIEN"));
+        assert!(entries.iter().all(|(name, _)| !name.ends_with('/')));
+        assert!(entries.iter().all(|(_, data)| data.len() <= MAX_SIZE));
+    }
+}
@@ -0,0 +1,717 @@
+//! Front end for the Chipper V2.11 assembly language, the syntax of the
+//! bundled `.SRC` files.
+//!
+//! Symbols are case insensitive, every source line is word aligned unless
+//! `ALIGN OFF` is in effect, and expressions use Chipper's own operator
+//! priorities. The source is assembled in two passes so that labels can be
+//! used before they are defined.
+
+use super::{AsmError, Program, PROGRAM_START};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+type Result<T> = std::result::Result<T, AsmError>;
+
+const MNEMONICS: [&str; 26] = [
+    "ADD", "AND", "CALL", "CLS", "DRW", "EXIT", "HIGH", "JP", "LD", "LOW", "OR", "RET", "RND",
+    "SCD", "SCL", "SCR", "SE", "SHL", "SHR", "SKNP", "SKP", "SNE", "SUB", "SUBN", "SYS", "XOR",
+];
+
+const DIRECTIVES: [&str; 20] = [
+    "=", "ALIGN", "DA", "DB", "DEFINE", "DS", "DW", "ELSE", "END", "ENDIF", "EQU", "IFDEF",
+    "IFUND", "INCLUDE", "OPTION", "ORG", "UNDEF", "USED", "XREF", "",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Expr(String),
+}
+
+/// A source line split into its fields.
+struct Line {
+    label: Option<String>,
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+fn symbol_name(text: &str) -> String {
+    let text = text.trim_end_matches(':');
+    let text = text.strip_prefix('_').unwrap_or(text);
+    text.to_uppercase()
+}
+
+fn is_keyword(word: &str) -> bool {
+    let word = word.to_uppercase();
+    MNEMONICS.contains(&word.as_str()) || DIRECTIVES.contains(&word.as_str())
+}
+
+/// Removes the comment of a line, leaving semicolons inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ',' if !quoted => operands.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    operands.push(current);
+    let mut operands: Vec<String> = operands.iter().map(|o| o.trim().to_string()).collect();
+    // An empty line has no operands and a trailing comma is tolerated.
+    if operands.last().is_some_and(|o| o.is_empty()) {
+        operands.pop();
+    }
+    operands
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], &text[end..]),
+        None => (text, ""),
+    }
+}
+
+fn parse_line(line: &str) -> Line {
+    let code = strip_comment(line);
+    let starts_in_column_0 = code.starts_with(|c: char| !c.is_whitespace());
+    let (first, rest) = split_word(code);
+    let (second, _) = split_word(rest);
+    let is_label = first.ends_with(':')
+        || (starts_in_column_0 && !first.is_empty() && !is_keyword(first))
+        || second == "="
+        || second.eq_ignore_ascii_case("EQU");
+    let (label, rest) = if is_label {
+        (Some(symbol_name(first)), rest)
+    } else {
+        (None, code)
+    };
+    let (mnemonic, operands) = split_word(rest);
+    Line {
+        label,
+        mnemonic: mnemonic.to_uppercase(),
+        operands: split_operands(operands),
+    }
+}
+
+/// Decodes a Chipper string: apostrophes delimit literal text, two
+/// apostrophes in a row produce one, and unquoted text is upper cased.
+fn parse_string(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            if chars.peek() == Some(&'\'') {
+                chars.next();
+                bytes.push(b'\'');
+            } else {
+                quoted = !quoted;
+            }
+        } else if quoted {
+            bytes.push(c as u8);
+        } else {
+            bytes.push(c.to_ascii_uppercase() as u8);
+        }
+    }
+    bytes
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Here,
+    Op(char),
+}
+
+fn digits(chars: &[char], pos: &mut usize, radix: u32) -> Option<i64> {
+    let start = *pos;
+    let mut value: i64 = 0;
+    while let Some(c) = chars.get(*pos) {
+        let digit = if *c == '.' {
+            Some(0)
+        } else {
+            c.to_digit(radix)
+        };
+        match digit {
+            Some(d) => value = value.wrapping_mul(radix as i64).wrapping_add(d as i64),
+            None => break,
+        }
+        *pos += 1;
+    }
+    if *pos == start {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn lex(text: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+        let token = match c {
+            '#' | '$' | '@' => {
+                pos += 1;
+                let radix = match c {
+                    '#' => 16,
+                    '$' => 2,
+                    _ => 8,
+                };
+                Token::Number(digits(&chars, &mut pos, radix)?)
+            }
+            '0'..='9' => Token::Number(digits(&chars, &mut pos, 10)?),
+            '"' => {
+                let rest: String = chars[pos + 1..].iter().collect();
+                let (value, used) = if let Some(quoted) = rest.strip_prefix('\'') {
+                    let end = quoted.find('\'')? + 2;
+                    (parse_string(&rest[..end]), end)
+                } else {
+                    (rest.chars().take(1).map(|c| c as u8).collect(), 1)
+                };
+                pos += 1 + used;
+                Token::Number(*value.first()? as i64)
+            }
+            '?' => {
+                pos += 1;
+                Token::Here
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                    pos += 1;
+                }
+                let name: String = chars[start..pos].iter().collect();
+                Token::Symbol(symbol_name(&name))
+            }
+            '(' | ')' | '+' | '-' | '~' | '!' | '<' | '>' | '*' | '/' | '&' | '|' | '^' | '\\'
+            | '%' => {
+                pos += 1;
+                Token::Op(c)
+            }
+            _ => return None,
+        };
+        tokens.push(token);
+    }
+    Some(tokens)
+}
+
+/// Binary operators from the lowest to the highest priority.
+const PRIORITIES: [&[char]; 5] = [
+    &['\\', '%'],
+    &['&', '|', '^'],
+    &['+', '-'],
+    &['*', '/'],
+    &['!', '<', '>'],
+];
+
+struct Evaluator<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    symbols: &'a HashMap<String, i64>,
+    here: u16,
+    /// Set when an undefined symbol was evaluated as zero.
+    undefined: Option<String>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn binary(&mut self, level: usize) -> Option<i64> {
+        if level == PRIORITIES.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            if !PRIORITIES[level].contains(op) {
+                break;
+            }
+            let op = *op;
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            value = match op {
+                '\\' | '/' => value.checked_div(rhs)?,
+                '%' => value.checked_rem(rhs)?,
+                '&' => value & rhs,
+                '|' => value | rhs,
+                '^' => value ^ rhs,
+                '+' => value.wrapping_add(rhs),
+                '-' => value.wrapping_sub(rhs),
+                '*' => value.wrapping_mul(rhs),
+                '!' => value.wrapping_pow(rhs as u32),
+                '<' => value.wrapping_shl(rhs as u32),
+                _ => value.wrapping_shr(rhs as u32),
+            };
+        }
+        Some(value)
+    }
+
+    fn unary(&mut self) -> Option<i64> {
+        let token = self.tokens.get(self.pos)?.clone();
+        self.pos += 1;
+        match token {
+            Token::Op('+') => self.unary(),
+            Token::Op('-') => self.unary().map(|v| v.wrapping_neg()),
+            Token::Op('~') => self.unary().map(|v| !v),
+            Token::Op('(') => {
+                let value = self.binary(0)?;
+                if self.tokens.get(self.pos) != Some(&Token::Op(')')) {
+                    return None;
+                }
+                self.pos += 1;
+                Some(value)
+            }
+            Token::Number(n) => Some(n),
+            Token::Here => Some(self.here as i64),
+            Token::Symbol(name) => match self.symbols.get(&name) {
+                Some(value) => Some(*value),
+                None => {
+                    self.undefined = Some(name);
+                    Some(0)
+                }
+            },
+            Token::Op(_) => None,
+        }
+    }
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let bytes = text.as_bytes();
+    if bytes.len() == 2 && (bytes[0] == b'v' || bytes[0] == b'V') {
+        return (bytes[1] as char).to_digit(16).map(|r| r as u8);
+    }
+    None
+}
+
+fn operand(text: &str) -> Operand {
+    if let Some(reg) = parse_register(text) {
+        return Operand::V(reg);
+    }
+    match text.to_uppercase().replace(' ', "").as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DT,
+        "ST" => Operand::ST,
+        "K" => Operand::K,
+        "F" | "LF" => Operand::F,
+        "HF" => Operand::HF,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        _ => Operand::Expr(text.to_string()),
+    }
+}
+
+struct Assembler {
+    final_pass: bool,
+    here: u16,
+    line: usize,
+    align: bool,
+    rom: Vec<u8>,
+    lines: BTreeMap<u16, usize>,
+    symbols: HashMap<String, i64>,
+    labels: HashSet<String>,
+    conditions: HashSet<String>,
+    /// Whether the enclosing IFDEF/IFUND blocks are assembled.
+    enabled: Vec<bool>,
+}
+
+impl Assembler {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(AsmError::new(self.line, message))
+    }
+
+    fn eval(&self, text: &str) -> Result<i64> {
+        let tokens = match lex(text) {
+            Some(tokens) => tokens,
+            None => return self.error(format!("Unable to evaluate parameter '{}'.", text)),
+        };
+        let mut evaluator = Evaluator {
+            tokens: &tokens,
+            pos: 0,
+            symbols: &self.symbols,
+            here: self.here,
+            undefined: None,
+        };
+        let value = evaluator.binary(0);
+        if value.is_none() || evaluator.pos != tokens.len() {
+            return self.error(format!("Unable to evaluate parameter '{}'.", text));
+        }
+        if let (true, Some(name)) = (self.final_pass, evaluator.undefined) {
+            return self.error(format!("Undefined symbol '{}'.", name));
+        }
+        Ok(value.unwrap())
+    }
+
+    fn ranged(&self, text: &str, min: i64, max: i64) -> Result<u16> {
+        let value = self.eval(text)?;
+        if self.final_pass && !(min..=max).contains(&value) {
+            return self.error(format!("Parameter out of range: {}.", value));
+        }
+        Ok((value & max) as u16)
+    }
+
+    fn addr(&self, text: &str) -> Result<u16> {
+        self.ranged(text, 0, 0xFFF)
+    }
+
+    fn byte_value(&self, text: &str) -> Result<u8> {
+        let value = self.eval(text)?;
+        if self.final_pass && !(-128..=255).contains(&value) {
+            return self.error(format!("Parameter out of range: {}.", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<()> {
+        if !(PROGRAM_START..=0xFFF).contains(&self.here) {
+            return self.error("Outside legal address range.");
+        }
+        let offset = (self.here - PROGRAM_START) as usize;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn word(&mut self, word: u16) -> Result<()> {
+        self.emit((word >> 8) as u8)?;
+        self.emit(word as u8)
+    }
+
+    fn define(&mut self, name: String, value: i64) -> Result<()> {
+        if !self.final_pass && self.labels.contains(&name) {
+            return self.error(format!("Existing symbol '{}' redefined.", name));
+        }
+        self.labels.insert(name.clone());
+        self.symbols.insert(name, value);
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[String]) -> Result<u16> {
+        use Operand::*;
+        let ops: Vec<Operand> = operands.iter().map(|o| operand(o)).collect();
+        let xy = |op: u16, x: u8, y: u8, n: u16| op | (x as u16) << 8 | (y as u16) << 4 | n;
+        Ok(match (mnemonic, ops.as_slice()) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("SCD", [Expr(n)]) => 0x00C0 | self.ranged(n, 0, 0xF)?,
+            ("SYS", [Expr(a)]) => self.addr(a)?,
+            ("JP", [Expr(a)]) => 0x1000 | self.addr(a)?,
+            ("JP", [V(0), Expr(a)]) => 0xB000 | self.addr(a)?,
+            ("CALL", [Expr(a)]) => 0x2000 | self.addr(a)?,
+            ("SE", [V(x), V(y)]) => xy(0x5000, *x, *y, 0),
+            ("SE", [V(x), Expr(b)]) => 0x3000 | (*x as u16) << 8 | self.byte_value(b)? as u16,
+            ("SNE", [V(x), V(y)]) => xy(0x9000, *x, *y, 0),
+            ("SNE", [V(x), Expr(b)]) => 0x4000 | (*x as u16) << 8 | self.byte_value(b)? as u16,
+            ("LD", [V(x), V(y)]) => xy(0x8000, *x, *y, 0),
+            ("LD", [V(x), DT]) => xy(0xF007, *x, 0, 0),
+            ("LD", [V(x), K]) => xy(0xF00A, *x, 0, 0),
+            ("LD", [V(x), IndirectI]) => xy(0xF065, *x, 0, 0),
+            ("LD", [V(x), R]) => xy(0xF085, *x, 0, 0),
+            ("LD", [V(x), Expr(b)]) => 0x6000 | (*x as u16) << 8 | self.byte_value(b)? as u16,
+            ("LD", [I, Expr(a)]) => 0xA000 | self.addr(a)?,
+            ("LD", [DT, V(x)]) => xy(0xF015, *x, 0, 0),
+            ("LD", [ST, V(x)]) => xy(0xF018, *x, 0, 0),
+            ("LD", [F, V(x)]) => xy(0xF029, *x, 0, 0),
+            ("LD", [HF, V(x)]) => xy(0xF030, *x, 0, 0),
+            ("LD", [B, V(x)]) => xy(0xF033, *x, 0, 0),
+            ("LD", [IndirectI, V(x)]) => xy(0xF055, *x, 0, 0),
+            ("LD", [R, V(x)]) => xy(0xF075, *x, 0, 0),
+            ("ADD", [I, V(x)]) => xy(0xF01E, *x, 0, 0),
+            ("ADD", [V(x), V(y)]) => xy(0x8004, *x, *y, 0),
+            ("ADD", [V(x), Expr(b)]) => 0x7000 | (*x as u16) << 8 | self.byte_value(b)? as u16,
+            ("OR", [V(x), V(y)]) => xy(0x8001, *x, *y, 0),
+            ("AND", [V(x), V(y)]) => xy(0x8002, *x, *y, 0),
+            ("XOR", [V(x), V(y)]) => xy(0x8003, *x, *y, 0),
+            ("SUB", [V(x), V(y)]) => xy(0x8005, *x, *y, 0),
+            ("SUBN", [V(x), V(y)]) => xy(0x8007, *x, *y, 0),
+            ("SHR", [V(x)]) => xy(0x8006, *x, 0, 0),
+            ("SHR", [V(x), V(y)]) => xy(0x8006, *x, *y, 0),
+            ("SHL", [V(x)]) => xy(0x800E, *x, 0, 0),
+            ("SHL", [V(x), V(y)]) => xy(0x800E, *x, *y, 0),
+            ("RND", [V(x), Expr(b)]) => 0xC000 | (*x as u16) << 8 | self.byte_value(b)? as u16,
+            ("DRW", [V(x), V(y), Expr(n)]) => xy(0xD000, *x, *y, self.ranged(n, 0, 0xF)?),
+            ("SKP", [V(x)]) => xy(0xE09E, *x, 0, 0),
+            ("SKNP", [V(x)]) => xy(0xE0A1, *x, 0, 0),
+            _ if MNEMONICS.contains(&mnemonic) => {
+                return self.error(format!("Incorrect parameters for {}.", mnemonic))
+            }
+            _ => return self.error(format!("No directive recognized: '{}'.", mnemonic)),
+        })
+    }
+
+    /// Handles the conditional assembly directives, which are processed even
+    /// while assembly is disabled.
+    fn condition(&mut self, line: &Line) -> Result<bool> {
+        let active = self.enabled.iter().all(|e| *e);
+        let name = line.operands.first().map(|o| symbol_name(o));
+        let conditional = ["IFDEF", "IFUND", "ELSE", "ENDIF", "DEFINE", "UNDEF"];
+        if let (true, Some(label)) = (active, &line.label) {
+            if conditional.contains(&line.mnemonic.as_str()) {
+                self.define(label.clone(), self.here as i64)?;
+            }
+        }
+        match line.mnemonic.as_str() {
+            "IFDEF" | "IFUND" => {
+                let defined = name.is_some_and(|n| self.conditions.contains(&n));
+                self.enabled.push(defined == (line.mnemonic == "IFDEF"));
+            }
+            "ELSE" => match self.enabled.last_mut() {
+                Some(enabled) => *enabled = !*enabled,
+                None => return self.error("No previous condition found."),
+            },
+            "ENDIF" => {
+                if self.enabled.pop().is_none() {
+                    return self.error("No previous condition found.");
+                }
+            }
+            "DEFINE" | "UNDEF" if active => {
+                let name = match name {
+                    Some(name) => name,
+                    None => return self.error("Incorrect number of parameters."),
+                };
+                if line.mnemonic == "DEFINE" {
+                    self.conditions.insert(name);
+                } else {
+                    self.conditions.remove(&name);
+                }
+            }
+            _ => return Ok(active),
+        }
+        Ok(false)
+    }
+
+    fn line(&mut self, text: &str) -> Result<()> {
+        let line = parse_line(text);
+        if !self.condition(&line)? {
+            return Ok(());
+        }
+        let start = self.here;
+        let emitted = self.rom.len();
+        match line.mnemonic.as_str() {
+            "=" | "EQU" => {
+                let name = match &line.label {
+                    Some(name) => name.clone(),
+                    None => return self.error("No symbol name specified."),
+                };
+                let value = match line.operands.first() {
+                    Some(expr) => self.eval(expr)?,
+                    None => return self.error("Incorrect number of parameters."),
+                };
+                return self.define(name, value);
+            }
+            _ => {
+                if let Some(label) = line.label.clone() {
+                    self.define(label, self.here as i64)?;
+                }
+            }
+        }
+        match line.mnemonic.as_str() {
+            "" | "END" | "OPTION" | "USED" | "XREF" => {}
+            "ALIGN" => match line.operands.first().map(|o| o.to_uppercase()).as_deref() {
+                Some("ON") => self.align = true,
+                Some("OFF") => self.align = false,
+                _ => return self.error("Option not recognized."),
+            },
+            "ORG" => {
+                let addr = self.eval(line.operands.first().map_or("", String::as_str))?;
+                self.here = addr as u16;
+            }
+            "DS" => {
+                let count = self.eval(line.operands.first().map_or("", String::as_str))?;
+                self.here = self.here.wrapping_add(count as u16);
+            }
+            "DA" => {
+                for operand in line.operands.iter() {
+                    for byte in parse_string(operand) {
+                        self.emit(byte)?;
+                    }
+                }
+            }
+            "DB" => {
+                for operand in line.operands.iter() {
+                    if operand.starts_with('\'') {
+                        for byte in parse_string(operand) {
+                            self.emit(byte)?;
+                        }
+                    } else {
+                        let byte = self.byte_value(operand)?;
+                        self.emit(byte)?;
+                    }
+                }
+            }
+            "DW" => {
+                for operand in line.operands.iter() {
+                    let word = self.eval(operand)?;
+                    self.word(word as u16)?;
+                }
+            }
+            "INCLUDE" => return self.error("INCLUDE is not supported."),
+            mnemonic => {
+                let opcode = self.instruction(mnemonic, &line.operands)?;
+                self.word(opcode)?;
+            }
+        }
+        if self.rom.len() != emitted || self.here != start {
+            self.lines.entry(start).or_insert(self.line);
+        }
+        if self.align && self.here % 2 == 1 {
+            self.here += 1;
+        }
+        Ok(())
+    }
+
+    fn pass(&mut self, source: &str) -> Result<()> {
+        self.here = PROGRAM_START;
+        self.align = true;
+        self.conditions.clear();
+        self.enabled.clear();
+        self.labels.clear();
+        for (index, text) in source.lines().enumerate() {
+            self.line = index + 1;
+            self.line(text)?;
+        }
+        if !self.enabled.is_empty() {
+            return self.error("Unbalanced condition matching in file.");
+        }
+        Ok(())
+    }
+}
+
+/// Assembles Chipper source code into a ROM image.
+pub fn assemble(source: &str) -> Result<Program> {
+    let mut assembler = Assembler {
+        final_pass: false,
+        here: PROGRAM_START,
+        line: 0,
+        align: true,
+        rom: Vec::new(),
+        lines: BTreeMap::new(),
+        symbols: HashMap::new(),
+        labels: HashSet::new(),
+        conditions: HashSet::new(),
+        enabled: Vec::new(),
+    };
+    assembler.pass(source)?;
+    assembler.final_pass = true;
+    assembler.rom.clear();
+    assembler.lines.clear();
+    assembler.pass(source)?;
+    let labels = assembler
+        .labels
+        .iter()
+        .filter_map(|name| {
+            let value = assembler.symbols[name];
+            let is_address = (PROGRAM_START as i64..=0xFFF).contains(&value);
+            if is_address {
+                Some((name.clone(), value as u16))
+            } else {
+                None
+            }
+        })
+        .collect();
+    Ok(Program {
+        rom: assembler.rom,
+        labels,
+        lines: assembler.lines,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_instructions() {
+        let program =
+            assemble("start:  LD V1, #2A\n  DRW V1, V2, 5\n  LD [I], V3\n  JP start\n  SHR VA")
+                .unwrap();
+        assert_eq!(
+            program.rom,
+            vec![0x61, 0x2A, 0xD1, 0x25, 0xF3, 0x55, 0x12, 0x00, 0x8A, 0x06]
+        );
+        assert_eq!(program.labels["START"], 0x200);
+        assert_eq!(program.lines[&0x202], 2);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let program = assemble(
+            "  DB \"'c' + @2 % #20\n  DB -3 * -( -7 + ~3 ) & #FF\n  DB ( 2 + 1 )! 2 ^ $1101 > 2\n  DB $..1.1...",
+        )
+        .unwrap();
+        assert_eq!(program.rom, vec![5, 0, 223, 0, 10, 0, 0x28]);
+    }
+
+    #[test]
+    fn test_align_and_strings() {
+        let program = assemble("ALIGN OFF\n  DB 1\n  DA 'Ab''c'\nEND").unwrap();
+        assert_eq!(program.rom, vec![1, b'A', b'b', b'\'', b'c']);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let source = "  DEFINE BIG\n  IFDEF BIG\n  LD V0, 1\n  ELSE\n  LD V0, 2\n  ENDIF\n  IFUND BIG\n  CLS\n  ENDIF";
+        assert_eq!(assemble(source).unwrap().rom, vec![0x60, 0x01]);
+    }
+
+    #[test]
+    fn test_undefined_symbol() {
+        let err = assemble("  CLS\n  JP nowhere").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.message, "Undefined symbol 'NOWHERE'.");
+    }
+
+    #[test]
+    fn test_bundled_sources() {
+        for name in [
+            "PONG", "BRIX", "MAZE", "UFO", "SYZYGY", "15PUZZLE", "PONG2", "BREAKOUT",
+        ]
+        .iter()
+        {
+            let source =
+                fs::read_to_string(format!("assets/CHIP8/GAMES/SOURCES/{}.SRC", name)).unwrap();
+            let rom = fs::read(format!("assets/CHIP8/GAMES/{}", name)).unwrap();
+            assert_eq!(assemble(&source).unwrap().rom, rom, "{} differs", name);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use crate::archive;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub mod chipper;
+pub mod octo;
+
+/// Address at which CHIP-8 programs are loaded.
+pub const PROGRAM_START: u16 = 0x200;
+
+/// Largest program that fits in memory after `PROGRAM_START`.
+pub const MAX_SIZE: usize = 0x1000 - PROGRAM_START as usize;
+
+/// Largest source file read from an archive.
+const MAX_SOURCE: usize = 1 << 20;
+
+/// Result of assembling a source file.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    /// ROM image, starting at `PROGRAM_START`.
+    pub rom: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+    /// Source line of the first byte emitted at each address.
+    pub lines: BTreeMap<u16, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    pub fn new(line: usize, message: impl Into<String>) -> AsmError {
+        AsmError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assembles `source` with the front end matching the file extension:
+/// `.8o` files are Octo, anything else is Chipper.
+pub fn assemble_source(path: &Path, source: &str) -> Result<Program, AsmError> {
+    let is_octo = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("8o"));
+    if is_octo {
+        octo::assemble(source)
+    } else {
+        chipper::assemble(source)
+    }
+}
+
+/// Whether `path` looks like assembly source rather than a ROM image.
+pub fn is_source(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ["8o", "src", "chp", "asm"]
+            .iter()
+            .any(|known| ext.eq_ignore_ascii_case(known)),
+        None => false,
+    }
+}
+
+/// Loads a ROM together with its debug map. Source files are assembled in
+/// memory; ROM images pick up the map stored next to them, if any. Either
+/// may be inside a ZIP archive, see `archive`.
+pub fn load(path: &Path) -> io::Result<(Vec<u8>, Option<DebugMap>)> {
+    let archived = archive::split(path).is_some();
+    if !is_source(path) {
+        let map = if archived {
+            None
+        } else {
+            DebugMap::load(path)?
+        };
+        return Ok((archive::read(path, MAX_SIZE)?, map));
+    }
+    let source = String::from_utf8(archive::read(path, MAX_SOURCE)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let program = assemble_source(path, &source).map_err(|e| {
+        let message = format!("{}: {}", path.display(), e);
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })?;
+    let map = DebugMap::new(path, &program);
+    Ok((program.rom, Some(map)))
+}
+
+/// Like `load`, but assembles the source named by the ROM's debug map
+/// instead when it was changed after the ROM was built.
+pub fn load_latest(path: &Path) -> io::Result<(Vec<u8>, Option<DebugMap>)> {
+    let (rom, map) = load(path)?;
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    if let Some(source) = map.as_ref().map(|map| map.source.clone()) {
+        if source != path && modified(&source) > modified(path) {
+            return load(&source);
+        }
+    }
+    Ok((rom, map))
+}
+
+/// Maps ROM addresses back to the source they were assembled from.
+///
+/// The map is stored as a text file next to the ROM (see `map_path`), one
+/// entry per line:
+///
+/// ```text
+/// source PONG.SRC
+/// label PADDLE 0x2f0
+/// line 0x200 14
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugMap {
+    pub source: PathBuf,
+    pub labels: BTreeMap<String, u16>,
+    pub lines: BTreeMap<u16, usize>,
+}
+
+/// Path of the debug map belonging to `rom`.
+pub fn map_path(rom: &Path) -> PathBuf {
+    let mut name = rom.as_os_str().to_owned();
+    name.push(".map");
+    PathBuf::from(name)
+}
+
+impl DebugMap {
+    pub fn new(source: &Path, program: &Program) -> DebugMap {
+        DebugMap {
+            source: source.to_path_buf(),
+            labels: program.labels.clone(),
+            lines: program.lines.clone(),
+        }
+    }
+
+    /// Parses a map, resolving the source path relative to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<DebugMap, AsmError> {
+        let mut map = DebugMap::default();
+        for (index, line) in text.lines().enumerate() {
+            let error = || AsmError::new(index + 1, format!("Malformed map entry '{}'.", line));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let address = |field: &str| u16::from_str_radix(field.trim_start_matches("0x"), 16);
+            match fields.as_slice() {
+                [] => {}
+                ["source", ..] => map.source = dir.join(line["source".len()..].trim()),
+                ["label", name, addr] => {
+                    let addr = address(addr).map_err(|_| error())?;
+                    map.labels.insert(name.to_string(), addr);
+                }
+                ["line", addr, number] => {
+                    let addr = address(addr).map_err(|_| error())?;
+                    let number = number.parse().map_err(|_| error())?;
+                    map.lines.insert(addr, number);
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(map)
+    }
+
+    /// Loads the map stored next to `rom`, if there is one.
+    pub fn load(rom: &Path) -> io::Result<Option<DebugMap>> {
+        let path = map_path(rom);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path)?;
+        let dir = rom.parent().unwrap_or_else(|| Path::new(""));
+        DebugMap::parse(&text, dir)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the map next to `rom`, naming the source relative to it when
+    /// possible.
+    pub fn save(&self, rom: &Path) -> io::Result<()> {
+        let rom = rom.canonicalize().unwrap_or_else(|_| rom.to_path_buf());
+        let dir = rom.parent().unwrap_or_else(|| Path::new(""));
+        let map = DebugMap {
+            source: self.source.canonicalize()?,
+            ..self.clone()
+        };
+        fs::write(map_path(&rom), map.to_text(dir))
+    }
+
+    fn to_text(&self, dir: &Path) -> String {
+        let source = self.source.strip_prefix(dir).unwrap_or(&self.source);
+        let mut text = format!("source {}\n", source.display());
+        for (name, addr) in self.labels.iter() {
+            text += &format!("label {} {:#05x}\n", name, addr);
+        }
+        for (addr, line) in self.lines.iter() {
+            text += &format!("line {:#05x} {}\n", addr, line);
+        }
+        text
+    }
+
+    /// Source line of the statement containing `addr`.
+    pub fn line_at(&self, addr: u16) -> Option<usize> {
+        self.lines.range(..=addr).next_back().map(|(_, line)| *line)
+    }
+
+    /// First address emitted for source line `line`.
+    pub fn address_of_line(&self, line: usize) -> Option<u16> {
+        self.lines
+            .iter()
+            .filter(|(_, l)| **l == line)
+            .map(|(addr, _)| *addr)
+            .min()
+    }
+
+    /// Label defined exactly at `addr`.
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, a)| **a == addr)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Address of a label, matched case-insensitively as Chipper does.
+    pub fn address_of_label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied().or_else(|| {
+            self.labels
+                .iter()
+                .find(|(label, _)| label.eq_ignore_ascii_case(name))
+                .map(|(_, addr)| *addr)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_map_round_trip() {
+        let program = octo::assemble(": main\n  v0 := 1\n: loop\n  v0 += 1\n  jump loop").unwrap();
+        let map = DebugMap::new(Path::new("dir/test.8o"), &program);
+        let text = map.to_text(Path::new("dir"));
+        assert!(text.starts_with("source test.8o\nlabel loop 0x202\n"));
+        assert_eq!(DebugMap::parse(&text, Path::new("dir")).unwrap(), map);
+        assert_eq!(map.line_at(0x203), Some(4));
+        assert_eq!(map.address_of_line(5), Some(0x204));
+        assert_eq!(map.label_at(0x202), Some("loop"));
+        assert_eq!(map.address_of_label("LOOP"), Some(0x202));
+    }
+
+    #[test]
+    fn test_front_end_by_extension() {
+        let octo = assemble_source(Path::new("a.8o"), ": main clear").unwrap();
+        let chipper = assemble_source(Path::new("A.SRC"), "  CLS").unwrap();
+        assert_eq!(octo.rom, chipper.rom);
+        assert!(is_source(Path::new("games/PONG.SRC")));
+        assert!(!is_source(Path::new("games/PONG")));
+    }
+
+    #[test]
+    fn test_load_latest() {
+        let touch = |path: &Path, secs| {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(time).unwrap();
+        };
+        let dir = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (source, rom) = (dir.join("test.8o"), dir.join("test.ch8"));
+        fs::write(&source, ": main\n  v0 := 1").unwrap();
+        let (program, map) = load(&source).unwrap();
+        fs::write(&rom, &program).unwrap();
+        map.unwrap().save(&rom).unwrap();
+        touch(&source, 1000);
+        touch(&rom, 2000);
+        assert_eq!(load_latest(&rom).unwrap().0, program);
+
+        fs::write(&source, ": main\n  v0 := 2").unwrap();
+        touch(&source, 3000);
+        let (latest, map) = load_latest(&rom).unwrap();
+        assert_eq!(latest, vec![0x60, 0x02]);
+        assert_eq!(map.unwrap().source, source.canonicalize().unwrap());
+        assert_eq!(load(&rom).unwrap().0, program);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1098 @@
+//! Front end for the Octo assembly language.
+//!
+//! Follows the semantics of the reference Octo compiler closely enough that
+//! the produced ROMs are byte for byte identical: the jump to `main` is only
+//! emitted when `main` is not the first thing in the program, conditionals
+//! compile to the inverse skip, and `:calc` expressions are evaluated right
+//! to left without operator precedence.
+
+use super::{AsmError, Program, PROGRAM_START};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+
+type Result<T> = std::result::Result<T, AsmError>;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// How a forward reference has to be patched once its label is defined.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    /// Low 12 bits of the instruction at the address.
+    Addr12,
+    /// Full 16 bit word at the address, used by `i := long`.
+    Long,
+    /// Operands of the two `vx := nn` emitted by `:unpack`.
+    Unpack(u8),
+    UnpackLong,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: u32,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    here: u16,
+    line: usize,
+    lines: BTreeMap<u16, usize>,
+    has_main: bool,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    protos: HashMap<String, Vec<(u16, Fixup, usize)>>,
+    branches: Vec<u16>,
+    loops: Vec<(u16, Vec<u16>)>,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        for word in code.split_whitespace() {
+            tokens.push_back(Token {
+                text: word.to_string(),
+                line: index + 1,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let bytes = text.as_bytes();
+    if bytes.len() == 2 && (bytes[0] == b'v' || bytes[0] == b'V') {
+        return (bytes[1] as char).to_digit(16).map(|r| r as u8);
+    }
+    None
+}
+
+fn is_identifier(text: &str) -> bool {
+    parse_number(text).is_none() && parse_register(text).is_none() && !text.starts_with(':')
+}
+
+impl Compiler {
+    fn new(source: &str) -> Compiler {
+        let mut aliases = HashMap::new();
+        aliases.insert("compare-temp".to_string(), 0xF);
+        aliases.insert("unpack-hi".to_string(), 0x0);
+        aliases.insert("unpack-lo".to_string(), 0x1);
+        Compiler {
+            tokens: tokenize(source),
+            rom: Vec::new(),
+            here: PROGRAM_START,
+            line: 1,
+            lines: BTreeMap::new(),
+            has_main: true,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases,
+            macros: HashMap::new(),
+            protos: HashMap::new(),
+            branches: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T> {
+        Err(AsmError::new(self.line, message))
+    }
+
+    fn next(&mut self) -> Result<String> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error("Unexpected end of file."),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        let token = self.next()?;
+        if token != expected {
+            return self.error(format!("Expected '{}', got '{}'.", expected, token));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        let offset = addr.wrapping_sub(PROGRAM_START) as usize;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let offset = addr.wrapping_sub(PROGRAM_START) as usize;
+        self.rom.get(offset).copied().unwrap_or(0)
+    }
+
+    fn byte(&mut self, byte: u8) -> Result<()> {
+        if self.here < PROGRAM_START {
+            return self.error(format!("Cannot emit data below {:#05x}.", PROGRAM_START));
+        }
+        if self.lines.range(..=self.here).next_back().map(|(_, l)| *l) != Some(self.line) {
+            self.lines.insert(self.here, self.line);
+        }
+        self.write(self.here, byte);
+        self.here = self.here.wrapping_add(1);
+        Ok(())
+    }
+
+    /// The address `offset` bytes after the current one.
+    fn ahead(&self, offset: u16) -> Result<u16> {
+        match self.here.checked_add(offset) {
+            Some(addr) => Ok(addr),
+            None => self.error("Outside legal address range."),
+        }
+    }
+
+    fn inst(&mut self, a: u8, b: u8) -> Result<()> {
+        self.byte(a)?;
+        self.byte(b)
+    }
+
+    fn fourop(&mut self, op: u8, x: u8, y: u8, n: u8) -> Result<()> {
+        self.inst((op << 4) | x, (y << 4) | n)
+    }
+
+    fn patch(&mut self, addr: u16, fixup: Fixup, target: u16) {
+        match fixup {
+            Fixup::Addr12 => {
+                let op = self.read(addr) & 0xF0;
+                self.write(addr, op | ((target >> 8) & 0xF) as u8);
+                self.write(addr + 1, target as u8);
+            }
+            Fixup::Long => {
+                self.write(addr, (target >> 8) as u8);
+                self.write(addr + 1, target as u8);
+            }
+            Fixup::Unpack(nybble) => {
+                self.write(addr + 1, (nybble << 4) | ((target >> 8) & 0xF) as u8);
+                self.write(addr + 3, target as u8);
+            }
+            Fixup::UnpackLong => {
+                self.write(addr + 1, (target >> 8) as u8);
+                self.write(addr + 3, target as u8);
+            }
+        }
+    }
+
+    fn is_register(&self) -> bool {
+        match self.peek() {
+            Some(text) => self.aliases.contains_key(text) || parse_register(text).is_some(),
+            None => false,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8> {
+        let token = self.next()?;
+        if let Some(reg) = self.aliases.get(&token) {
+            return Ok(*reg);
+        }
+        match parse_register(&token) {
+            Some(reg) => Ok(reg),
+            None => self.error(format!("Expected register, got '{}'.", token)),
+        }
+    }
+
+    /// Resolves a token that names a compile time number, if it does.
+    fn constant(&self, token: &str) -> Option<f64> {
+        if let Some(n) = parse_number(token) {
+            return Some(n as f64);
+        }
+        if let Some(value) = self.constants.get(token) {
+            return Some(*value);
+        }
+        self.labels.get(token).map(|addr| *addr as f64)
+    }
+
+    fn value(&mut self) -> Result<i64> {
+        let token = self.next()?;
+        if token == "{" {
+            return Ok(self.calc_block()?.floor() as i64);
+        }
+        match self.constant(&token) {
+            Some(value) => Ok(value.floor() as i64),
+            None => self.error(format!("Undefined name '{}'.", token)),
+        }
+    }
+
+    fn short_value(&mut self) -> Result<u8> {
+        let value = self.value()?;
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("Argument {} does not fit in a byte.", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn tiny_value(&mut self) -> Result<u8> {
+        let value = self.value()?;
+        if !(0..=15).contains(&value) {
+            return self.error(format!("Argument {} does not fit in a nybble.", value));
+        }
+        Ok(value as u8)
+    }
+
+    /// Reads an address operand, registering a forward reference at `addr`
+    /// when it names a label that has not been defined yet.
+    fn address(&mut self, addr: u16, fixup: Fixup) -> Result<u16> {
+        let token = self.next()?;
+        let limit = match fixup {
+            Fixup::Addr12 | Fixup::Unpack(_) => 0xFFF,
+            Fixup::Long | Fixup::UnpackLong => 0xFFFF,
+        };
+        let value = if token == "{" {
+            Some(self.calc_block()?)
+        } else {
+            self.constant(&token)
+        };
+        match value {
+            Some(value) => {
+                let value = value.floor() as i64;
+                if !(0..=limit).contains(&value) {
+                    return self.error(format!("Address {:#x} is out of range.", value));
+                }
+                Ok(value as u16)
+            }
+            None if is_identifier(&token) => {
+                let line = self.line;
+                self.protos
+                    .entry(token)
+                    .or_default()
+                    .push((addr, fixup, line));
+                Ok(0)
+            }
+            None => self.error(format!("Expected address, got '{}'.", token)),
+        }
+    }
+
+    fn jump(&mut self, op: u8) -> Result<()> {
+        let target = self.address(self.here, Fixup::Addr12)?;
+        self.inst(op | (target >> 8) as u8, target as u8)
+    }
+
+    fn define_label(&mut self, name: String, addr: u16) -> Result<()> {
+        if self.labels.contains_key(&name) {
+            return self.error(format!("The name '{}' has already been defined.", name));
+        }
+        if let Some(fixups) = self.protos.remove(&name) {
+            for (at, fixup, _) in fixups {
+                self.patch(at, fixup, addr);
+            }
+        }
+        self.labels.insert(name, addr);
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        let token = self.next()?;
+        if !is_identifier(&token) {
+            return self.error(format!("Invalid name '{}'.", token));
+        }
+        Ok(token)
+    }
+
+    fn calc_tokens(&mut self) -> Result<Vec<String>> {
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    /// Evaluates a `{ ... }` expression whose opening brace was consumed.
+    fn calc_block(&mut self) -> Result<f64> {
+        let tokens = self.calc_tokens()?;
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return self.error(format!("Unexpected '{}' in expression.", tokens[pos]));
+        }
+        Ok(value)
+    }
+
+    fn calc_expr(&self, tokens: &[String], pos: &mut usize) -> Result<f64> {
+        let left = self.calc_term(tokens, pos)?;
+        match tokens.get(*pos).map(String::as_str) {
+            None | Some(")") => Ok(left),
+            Some(op) => {
+                *pos += 1;
+                let right = self.calc_expr(tokens, pos)?;
+                let (a, b) = (left, right);
+                let shift = |f: fn(i64, u32) -> Option<i64>| match u32::try_from(b as i64) {
+                    Ok(count) => f(a as i64, count).map(|v| v as f64),
+                    Err(_) => None,
+                };
+                Ok(match op {
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    "/" => a / b,
+                    "%" => a % b,
+                    "&" => ((a as i64) & (b as i64)) as f64,
+                    "|" => ((a as i64) | (b as i64)) as f64,
+                    "^" => ((a as i64) ^ (b as i64)) as f64,
+                    "<<" | ">>" => {
+                        let value = match op {
+                            "<<" => shift(i64::checked_shl),
+                            _ => shift(i64::checked_shr),
+                        };
+                        match value {
+                            Some(value) => value,
+                            None => return self.error(format!("Cannot shift by {}.", b)),
+                        }
+                    }
+                    "pow" => a.powf(b),
+                    "min" => a.min(b),
+                    "max" => a.max(b),
+                    "<" => (a < b) as i64 as f64,
+                    ">" => (a > b) as i64 as f64,
+                    "<=" => (a <= b) as i64 as f64,
+                    ">=" => (a >= b) as i64 as f64,
+                    "==" => (a == b) as i64 as f64,
+                    "!=" => (a != b) as i64 as f64,
+                    _ => return self.error(format!("Unknown operator '{}'.", op)),
+                })
+            }
+        }
+    }
+
+    fn calc_term(&self, tokens: &[String], pos: &mut usize) -> Result<f64> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token.as_str(),
+            None => return self.error("Unexpected end of expression."),
+        };
+        *pos += 1;
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| -> Result<f64> {
+            Ok(f(self.calc_term(tokens, pos)?))
+        };
+        match token {
+            "(" => {
+                let value = self.calc_expr(tokens, pos)?;
+                if tokens.get(*pos).map(String::as_str) != Some(")") {
+                    return self.error("Expected ')' in expression.");
+                }
+                *pos += 1;
+                Ok(value)
+            }
+            "-" => unary(|a| -a, pos),
+            "~" => unary(|a| !(a as i64) as f64, pos),
+            "!" => unary(|a| (a == 0.0) as i64 as f64, pos),
+            "sin" => unary(f64::sin, pos),
+            "cos" => unary(f64::cos, pos),
+            "tan" => unary(f64::tan, pos),
+            "exp" => unary(f64::exp, pos),
+            "log" => unary(f64::ln, pos),
+            "abs" => unary(f64::abs, pos),
+            "sqrt" => unary(f64::sqrt, pos),
+            "sign" => unary(f64::signum, pos),
+            "ceil" => unary(f64::ceil, pos),
+            "floor" => unary(f64::floor, pos),
+            "@" => {
+                let addr = self.calc_term(tokens, pos)?;
+                Ok(self.read(addr as u16) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => match self.constant(token) {
+                Some(value) => Ok(value),
+                None => self.error(format!("Undefined name '{}'.", token)),
+            },
+        }
+    }
+
+    /// Emits the skip for a condition; with `negated` the skip is taken when
+    /// the condition holds instead of when it fails.
+    fn conditional(&mut self, negated: bool) -> Result<()> {
+        let reg = self.register()?;
+        let mut token = self.next()?;
+        if negated {
+            let inverse = match token.as_str() {
+                "<" => ">=",
+                ">" => "<=",
+                ">=" => "<",
+                "<=" => ">",
+                "!=" => "==",
+                "==" => "!=",
+                "key" => "-key",
+                "-key" => "key",
+                _ => return self.error(format!("Unknown comparison '{}'.", token)),
+            };
+            token = inverse.to_string();
+        }
+        let temp = self.aliases["compare-temp"];
+        match token.as_str() {
+            "==" => {
+                if self.is_register() {
+                    let other = self.register()?;
+                    self.fourop(0x9, reg, other, 0x0)
+                } else {
+                    let value = self.short_value()?;
+                    self.inst(0x40 | reg, value)
+                }
+            }
+            "!=" => {
+                if self.is_register() {
+                    let other = self.register()?;
+                    self.fourop(0x5, reg, other, 0x0)
+                } else {
+                    let value = self.short_value()?;
+                    self.inst(0x30 | reg, value)
+                }
+            }
+            "key" => self.inst(0xE0 | reg, 0xA1),
+            "-key" => self.inst(0xE0 | reg, 0x9E),
+            ">" | "<" | ">=" | "<=" => {
+                if self.is_register() {
+                    let other = self.register()?;
+                    self.fourop(0x8, temp, other, 0x0)?;
+                } else {
+                    let value = self.short_value()?;
+                    self.inst(0x60 | temp, value)?;
+                }
+                match token.as_str() {
+                    ">" => {
+                        self.fourop(0x8, temp, reg, 0x5)?;
+                        self.inst(0x3F, 1)
+                    }
+                    "<" => {
+                        self.fourop(0x8, temp, reg, 0x7)?;
+                        self.inst(0x3F, 1)
+                    }
+                    ">=" => {
+                        self.fourop(0x8, temp, reg, 0x7)?;
+                        self.inst(0x4F, 1)
+                    }
+                    _ => {
+                        self.fourop(0x8, temp, reg, 0x5)?;
+                        self.inst(0x4F, 1)
+                    }
+                }
+            }
+            _ => self.error(format!("Unknown comparison '{}'.", token)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<()> {
+        let op = self.next()?;
+        let binary = |c: &mut Compiler, n: u8| -> Result<()> {
+            let y = c.register()?;
+            c.fourop(0x8, x, y, n)
+        };
+        match op.as_str() {
+            ":=" => {
+                if self.is_register() {
+                    return binary(self, 0x0);
+                }
+                match self.peek() {
+                    Some("random") => {
+                        self.next()?;
+                        let mask = self.short_value()?;
+                        self.inst(0xC0 | x, mask)
+                    }
+                    Some("key") => {
+                        self.next()?;
+                        self.inst(0xF0 | x, 0x0A)
+                    }
+                    Some("delay") => {
+                        self.next()?;
+                        self.inst(0xF0 | x, 0x07)
+                    }
+                    _ => {
+                        let value = self.short_value()?;
+                        self.inst(0x60 | x, value)
+                    }
+                }
+            }
+            "+=" => {
+                if self.is_register() {
+                    return binary(self, 0x4);
+                }
+                let value = self.short_value()?;
+                self.inst(0x70 | x, value)
+            }
+            "-=" => {
+                if self.is_register() {
+                    return binary(self, 0x5);
+                }
+                let value = self.short_value()?;
+                self.inst(0x70 | x, value.wrapping_neg())
+            }
+            "|=" => binary(self, 0x1),
+            "&=" => binary(self, 0x2),
+            "^=" => binary(self, 0x3),
+            "=-" => binary(self, 0x7),
+            ">>=" => binary(self, 0x6),
+            "<<=" => binary(self, 0xE),
+            _ => self.error(format!("Unrecognized operator '{}'.", op)),
+        }
+    }
+
+    fn i_statement(&mut self) -> Result<()> {
+        let op = self.next()?;
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.inst(0xF0 | x, 0x29)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.inst(0xF0 | x, 0x30)
+                }
+                Some("long") => {
+                    self.next()?;
+                    let target = self.address(self.ahead(2)?, Fixup::Long)?;
+                    self.inst(0xF0, 0x00)?;
+                    self.inst((target >> 8) as u8, target as u8)
+                }
+                _ => self.jump(0xA0),
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x1E)
+            }
+            _ => self.error(format!("Unrecognized operator '{}'.", op)),
+        }
+    }
+
+    fn range_statement(&mut self, single: u8, range: u8) -> Result<()> {
+        let x = self.register()?;
+        if self.peek() == Some("-") {
+            self.next()?;
+            let y = self.register()?;
+            return self.fourop(0x5, x, y, range);
+        }
+        self.inst(0xF0 | x, single)
+    }
+
+    fn timer_statement(&mut self, op: u8) -> Result<()> {
+        self.expect(":=")?;
+        let x = self.register()?;
+        self.inst(0xF0 | x, op)
+    }
+
+    fn directive(&mut self, token: &str) -> Result<()> {
+        match token {
+            ":" => {
+                let name = self.identifier()?;
+                if self.here == PROGRAM_START + 2 && name == "main" {
+                    self.has_main = false;
+                    self.rom.clear();
+                    self.here = PROGRAM_START;
+                }
+                self.define_label(name, self.here)
+            }
+            ":alias" => {
+                let name = self.identifier()?;
+                let reg = self.register()?;
+                self.aliases.insert(name, reg);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.identifier()?;
+                let value = self.value()?;
+                self.constants.insert(name, value as f64);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.identifier()?;
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":macro" => {
+                let name = self.identifier()?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.next()?;
+                    if arg == "{" {
+                        break;
+                    }
+                    args.push(arg);
+                }
+                let mut depth = 1;
+                let mut body = Vec::new();
+                loop {
+                    let token = match self.tokens.pop_front() {
+                        Some(token) => token,
+                        None => return self.error("Unterminated macro."),
+                    };
+                    match token.text.as_str() {
+                        "{" => depth += 1,
+                        "}" => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    body.push(token);
+                }
+                self.macros.insert(
+                    name,
+                    Macro {
+                        args,
+                        body,
+                        calls: 0,
+                    },
+                );
+                Ok(())
+            }
+            ":unpack" => {
+                let long = self.peek() == Some("long");
+                let fixup = if long {
+                    self.next()?;
+                    Fixup::UnpackLong
+                } else {
+                    Fixup::Unpack(self.tiny_value()?)
+                };
+                let at = self.here;
+                let target = self.address(at, fixup)?;
+                let hi = self.aliases["unpack-hi"];
+                let lo = self.aliases["unpack-lo"];
+                self.inst(0x60 | hi, 0)?;
+                self.inst(0x60 | lo, 0)?;
+                self.patch(at, fixup, target);
+                Ok(())
+            }
+            ":next" => {
+                let name = self.identifier()?;
+                let addr = self.ahead(1)?;
+                self.define_label(name, addr)
+            }
+            ":org" => {
+                let addr = self.value()?;
+                if !(PROGRAM_START as i64..=0xFFFF).contains(&addr) {
+                    return self.error(format!("Invalid :org address {:#x}.", addr));
+                }
+                self.here = addr as u16;
+                Ok(())
+            }
+            ":byte" => {
+                let value = self.short_value()?;
+                self.byte(value)
+            }
+            ":call" => self.jump(0x20),
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+                Ok(())
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+                Ok(())
+            }
+            _ => self.error(format!("Unknown directive '{}'.", token)),
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<()> {
+        let line = self.line;
+        let (args, body, calls) = {
+            let m = self.macros.get_mut(name).unwrap();
+            let calls = m.calls;
+            m.calls += 1;
+            (m.args.clone(), m.body.clone(), calls)
+        };
+        let mut bindings = HashMap::new();
+        for arg in args {
+            bindings.insert(arg, self.next()?);
+        }
+        for token in body.into_iter().rev() {
+            let text = if token.text == "CALLS" {
+                calls.to_string()
+            } else {
+                bindings.get(&token.text).cloned().unwrap_or(token.text)
+            };
+            self.tokens.push_front(Token { text, line });
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<()> {
+        let token = self.next()?;
+        if token.starts_with(':') {
+            return self.directive(&token);
+        }
+        if self.macros.contains_key(&token) {
+            return self.expand_macro(&token);
+        }
+        if let Some(reg) = self.aliases.get(&token) {
+            return self.register_statement(*reg);
+        }
+        if let Some(reg) = parse_register(&token) {
+            return self.register_statement(reg);
+        }
+        match token.as_str() {
+            ";" | "return" => self.inst(0x00, 0xEE),
+            "clear" => self.inst(0x00, 0xE0),
+            "exit" => self.inst(0x00, 0xFD),
+            "lores" => self.inst(0x00, 0xFE),
+            "hires" => self.inst(0x00, 0xFF),
+            "scroll-right" => self.inst(0x00, 0xFB),
+            "scroll-left" => self.inst(0x00, 0xFC),
+            "scroll-down" => {
+                let n = self.tiny_value()?;
+                self.inst(0x00, 0xC0 | n)
+            }
+            "scroll-up" => {
+                let n = self.tiny_value()?;
+                self.inst(0x00, 0xD0 | n)
+            }
+            "audio" => self.inst(0xF0, 0x02),
+            "plane" => {
+                let n = self.tiny_value()?;
+                self.inst(0xF0 | n, 0x01)
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x33)
+            }
+            "save" => self.range_statement(0x55, 0x2),
+            "load" => self.range_statement(0x65, 0x3),
+            "saveflags" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x75)
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x85)
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.tiny_value()?;
+                self.fourop(0xD, x, y, n)
+            }
+            "jump" => self.jump(0x10),
+            "jump0" => self.jump(0xB0),
+            "native" => self.jump(0x00),
+            "i" => self.i_statement(),
+            "delay" => self.timer_statement(0x15),
+            "buzzer" => self.timer_statement(0x18),
+            "pitch" => self.timer_statement(0x3A),
+            "if" => {
+                let then = self
+                    .tokens
+                    .iter()
+                    .map(|t| t.text.as_str())
+                    .find(|t| *t == "then" || *t == "begin");
+                match then {
+                    Some("then") => {
+                        self.conditional(false)?;
+                        self.expect("then")
+                    }
+                    Some(_) => {
+                        self.conditional(true)?;
+                        self.expect("begin")?;
+                        self.branches.push(self.here);
+                        self.inst(0x10, 0x00)
+                    }
+                    None => self.error("Expected 'then' or 'begin'."),
+                }
+            }
+            "else" => {
+                let start = match self.branches.pop() {
+                    Some(start) => start,
+                    None => return self.error("This 'else' does not have a matching 'begin'."),
+                };
+                let target = self.ahead(2)?;
+                self.patch(start, Fixup::Addr12, target);
+                self.branches.push(self.here);
+                self.inst(0x10, 0x00)
+            }
+            "end" => match self.branches.pop() {
+                Some(start) => {
+                    self.patch(start, Fixup::Addr12, self.here);
+                    Ok(())
+                }
+                None => self.error("This 'end' does not have a matching 'begin'."),
+            },
+            "loop" => {
+                self.loops.push((self.here, Vec::new()));
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return self.error("This 'while' is not within a loop.");
+                }
+                self.conditional(true)?;
+                let at = self.here;
+                self.loops.last_mut().unwrap().1.push(at);
+                self.inst(0x10, 0x00)
+            }
+            "again" => {
+                let (start, exits) = match self.loops.pop() {
+                    Some(entry) => entry,
+                    None => return self.error("This 'again' does not have a matching 'loop'."),
+                };
+                self.inst(0x10 | (start >> 8) as u8, start as u8)?;
+                for exit in exits {
+                    self.patch(exit, Fixup::Addr12, self.here);
+                }
+                Ok(())
+            }
+            _ => {
+                if let Some(value) = self.constant(&token) {
+                    if self.labels.contains_key(&token) {
+                        let target = value as u16;
+                        return self.inst(0x20 | (target >> 8) as u8, target as u8);
+                    }
+                    let value = value.floor() as i64;
+                    if !(-128..=255).contains(&value) {
+                        return self
+                            .error(format!("Literal value {} does not fit in a byte.", value));
+                    }
+                    return self.byte(value as u8);
+                }
+                if is_identifier(&token) {
+                    self.tokens.push_front(Token {
+                        text: token,
+                        line: self.line,
+                    });
+                    return self.jump(0x20);
+                }
+                self.error(format!("Unrecognized token '{}'.", token))
+            }
+        }
+    }
+
+    fn go(mut self) -> Result<Program> {
+        self.inst(0x00, 0x00)?;
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if !self.branches.is_empty() {
+            return self.error("This program is missing an 'end'.");
+        }
+        if !self.loops.is_empty() {
+            return self.error("This program is missing an 'again'.");
+        }
+        if self.has_main {
+            match self.labels.get("main") {
+                Some(main) => {
+                    let main = *main;
+                    self.write(PROGRAM_START, 0x10 | ((main >> 8) & 0xF) as u8);
+                    self.write(PROGRAM_START + 1, main as u8);
+                }
+                None => return self.error("This program is missing a 'main' label."),
+            }
+        }
+        if let Some((name, fixups)) = self.protos.iter().min_by_key(|(_, f)| f[0].2) {
+            return Err(AsmError::new(
+                fixups[0].2,
+                format!("Undefined name '{}'.", name),
+            ));
+        }
+        Ok(Program {
+            rom: self.rom,
+            labels: self.labels.into_iter().collect(),
+            lines: self.lines,
+        })
+    }
+}
+
+/// Assembles Octo source code into a ROM image.
+pub fn assemble(source: &str) -> Result<Program> {
+    Compiler::new(source).go()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().rom
+    }
+
+    #[test]
+    fn test_main_first() {
+        assert_eq!(rom(": main v0 := 5 v1 += v0"), vec![0x60, 0x05, 0x81, 0x04]);
+    }
+
+    #[test]
+    fn test_main_jump() {
+        let program = assemble(": data 0xFF 0x81 : main i := data sprite v0 v1 2").unwrap();
+        assert_eq!(
+            program.rom,
+            vec![0x12, 0x04, 0xFF, 0x81, 0xA2, 0x02, 0xD0, 0x12]
+        );
+        assert_eq!(program.labels["data"], 0x202);
+        assert_eq!(program.labels["main"], 0x204);
+    }
+
+    #[test]
+    fn test_missing_main() {
+        let err = assemble("v0 := 1").unwrap_err();
+        assert_eq!(err.message, "This program is missing a 'main' label.");
+    }
+
+    #[test]
+    fn test_forward_call() {
+        assert_eq!(
+            rom(": main draw jump main : draw ;"),
+            vec![0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn test_if_then() {
+        assert_eq!(
+            rom(": main if v1 == 3 then v2 := 0 if v1 != v2 then clear if v3 key then ;"),
+            vec![0x41, 0x03, 0x62, 0x00, 0x51, 0x20, 0x00, 0xE0, 0xE3, 0xA1, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn test_if_begin_else() {
+        assert_eq!(
+            rom(": main if v0 == 1 begin v1 := 1 else v1 := 2 end"),
+            vec![0x30, 0x01, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(
+            rom(": main if v2 > 7 then v0 := 0"),
+            vec![0x6F, 0x07, 0x8F, 0x25, 0x3F, 0x01, 0x60, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_loop_while() {
+        assert_eq!(
+            rom(": main loop v0 += 1 while v0 != 10 again"),
+            vec![0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_const_alias_calc() {
+        assert_eq!(
+            rom(":const SPEED 3 :alias px v4 :calc TWICE { SPEED * 2 + 1 } : main px += SPEED px := TWICE"),
+            vec![0x74, 0x03, 0x64, 0x09]
+        );
+    }
+
+    #[test]
+    fn test_calc_right_to_left() {
+        assert_eq!(
+            rom(": main :byte { 2 * 3 + 1 } :byte { ( 2 * 3 ) + 1 }"),
+            vec![8, 7]
+        );
+    }
+
+    #[test]
+    fn test_calc_shift() {
+        assert_eq!(
+            rom(": main :byte { 1 << 3 } :byte { 64 >> 2 }"),
+            vec![8, 16]
+        );
+        for shift in ["1 << 70", "1 << -1", "1 >> 64"].iter() {
+            let source = format!(":calc x {{ {} }} : main", shift);
+            let err = assemble(&source).unwrap_err();
+            assert!(err.message.starts_with("Cannot shift"), "{}", shift);
+        }
+    }
+
+    #[test]
+    fn test_macro() {
+        assert_eq!(
+            rom(":macro set reg val { reg := val } :macro count reg { reg := CALLS } : main set v3 0x20 count va count vb"),
+            vec![0x63, 0x20, 0x6A, 0x00, 0x6B, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_unpack_next_org() {
+        assert_eq!(
+            rom(": main :unpack 0xA target :next slot v0 := 0 :org 0x20A : target 0xAB"),
+            vec![0x60, 0xA2, 0x61, 0x0A, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0xAB]
+        );
+        assert_eq!(
+            assemble(": main :next slot v0 := 0").unwrap().labels["slot"],
+            0x201
+        );
+        for source in [
+            ": main :org 0xFFFF :next slot",
+            ": main :org 0xFFFE i := long main",
+        ]
+        .iter()
+        {
+            let err = assemble(source).unwrap_err();
+            assert_eq!(err.message, "Outside legal address range.", "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_negative_immediates() {
+        assert_eq!(rom(": main v0 -= 1 v1 := -1"), vec![0x70, 0xFF, 0x61, 0xFF]);
+    }
+
+    #[test]
+    fn test_xo_statements() {
+        assert_eq!(
+            rom(": main i := long target save v1 - v3 plane 3 : target"),
+            vec![0xF0, 0x00, 0x02, 0x08, 0x51, 0x32, 0xF3, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_undefined_name() {
+        let err = assemble(": main\n  jump nowhere").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.message, "Undefined name 'nowhere'.");
+    }
+}
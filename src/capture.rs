@@ -0,0 +1,249 @@
+//! Screenshots and recordings of the screen, in the colours of a palette.
+//!
+//! Screenshots are PNG files. Recordings are animated GIF files, with each
+//! screen kept for as long as it was shown.
+
+use crate::palette::Palette;
+use image::buffer::ConvertBuffer;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, ImageFormat, Rgb, RgbImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Most distinct screens a recording keeps, a minute of changes every
+/// frame.
+pub const MAX_SCREENS: usize = 3600;
+
+/// Settings of the capture hotkeys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capture {
+    /// Size of a CHIP-8 pixel in the files.
+    pub scale: u32,
+    /// Where captures and exported reports go, instead of the current
+    /// directory.
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for Capture {
+    fn default() -> Capture {
+        Capture {
+            scale: 8,
+            dir: None,
+        }
+    }
+}
+
+impl Capture {
+    pub const MAX_SCALE: u32 = 32;
+
+    /// `scale` within 1 to `MAX_SCALE`.
+    pub fn scale(&self) -> u32 {
+        self.scale.clamp(1, Capture::MAX_SCALE)
+    }
+
+    /// A new file for a capture of `rom`: `NAME-N.EXTENSION` with the first
+    /// N not taken.
+    pub fn path(&self, rom: &Path, extension: &str) -> PathBuf {
+        self.paths(rom, &[extension]).remove(0)
+    }
+
+    /// New files that belong together, one for each of `extensions`, all
+    /// with the first N for which none is taken.
+    pub fn paths(&self, rom: &Path, extensions: &[&str]) -> Vec<PathBuf> {
+        let dir = self.dir.clone().unwrap_or_default();
+        let name = rom.file_stem().unwrap_or_default().to_string_lossy();
+        (1..)
+            .map(|n| {
+                extensions
+                    .iter()
+                    .map(|extension| dir.join(format!("{}-{}.{}", name, n, extension)))
+                    .collect::<Vec<_>>()
+            })
+            .find(|paths| paths.iter().all(|path| !path.exists()))
+            .unwrap()
+    }
+}
+
+/// The screen with every pixel enlarged to `scale` by `scale`.
+pub fn image(video_mem: &[[u8; 64]; 32], palette: &Palette, scale: u32) -> RgbImage {
+    let colors = palette.colors();
+    let scale = scale.max(1);
+    RgbImage::from_fn(64 * scale, 32 * scale, |x, y| {
+        // CHIP-8 pixels are lit in the first plane only.
+        let pixel = video_mem[(y / scale) as usize][(x / scale) as usize];
+        Rgb(colors[(pixel != 0) as usize].0)
+    })
+}
+
+/// Saves the screen as a PNG file.
+pub fn screenshot(
+    path: &Path,
+    video_mem: &[[u8; 64]; 32],
+    palette: &Palette,
+    scale: u32,
+) -> io::Result<()> {
+    image(video_mem, palette, scale)
+        .save_with_format(path, ImageFormat::Png)
+        .map_err(io::Error::other)
+}
+
+/// Whether `path` names a GIF file, which recordings are saved as.
+pub fn is_gif(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("gif"))
+}
+
+/// Screens in the order they were shown, each with how long it was shown.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    screens: Vec<([[u8; 64]; 32], Duration)>,
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording::default()
+    }
+
+    /// Adds a screen shown for `duration`. Returns false once the
+    /// recording is full.
+    pub fn push(&mut self, video_mem: &[[u8; 64]; 32], duration: Duration) -> bool {
+        let full = self.screens.len() >= MAX_SCREENS;
+        match self.screens.last_mut() {
+            Some((last, shown)) if last == video_mem => *shown += duration,
+            _ if full => return false,
+            _ => self.screens.push((*video_mem, duration)),
+        }
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.screens.is_empty()
+    }
+
+    /// Time the recording lasts.
+    pub fn duration(&self) -> Duration {
+        self.screens.iter().map(|(_, shown)| *shown).sum()
+    }
+
+    /// The screens with how long each is shown in whole `unit`s, rounding
+    /// the time they start at. Screens that round to nothing are left
+    /// out.
+    fn frames(&self, unit: Duration) -> Vec<(&[[u8; 64]; 32], u32)> {
+        let mut frames = Vec::new();
+        let mut elapsed = Duration::default();
+        let mut start = 0;
+        for (i, (screen, shown)) in self.screens.iter().enumerate() {
+            elapsed += *shown;
+            let mut end = (elapsed.as_secs_f64() / unit.as_secs_f64()).round() as u32;
+            if i + 1 == self.screens.len() {
+                end = end.max(start + 1);
+            }
+            if end > start {
+                frames.push((screen, end - start));
+                start = end;
+            }
+        }
+        frames
+    }
+
+    /// Saves the recording as a looping GIF animation.
+    pub fn save(&self, path: &Path, palette: &Palette, scale: u32) -> io::Result<()> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if self.is_empty() {
+            return Err(invalid("nothing was recorded".to_string()));
+        }
+        if !is_gif(path) {
+            return Err(invalid(format!("{}: recordings are .gif", path.display())));
+        }
+        let error = io::Error::other;
+        let mut encoder = GifEncoder::new(BufWriter::new(File::create(path)?));
+        encoder.set_repeat(Repeat::Infinite).map_err(error)?;
+        // Viewers slow down delays under 2 centiseconds, so frames are
+        // timed at 50 per second.
+        let frames = self.frames(Duration::from_millis(20)).into_iter();
+        encoder
+            .encode_frames(frames.map(|(screen, delay)| {
+                let image: RgbaImage = image(screen, palette, scale).convert();
+                Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(delay * 20, 1))
+            }))
+            .map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+    use std::fs;
+
+    #[test]
+    fn test_recording() {
+        let mut screen = [[0; 64]; 32];
+        let frame = Duration::from_secs(1) / 60;
+        let mut recording = Recording::new();
+        for i in 0..10 {
+            screen[0][i / 4] = 1;
+            assert!(recording.push(&screen, frame));
+        }
+        assert_eq!(recording.screens.len(), 3);
+        assert_eq!(recording.duration(), frame * 10);
+        let delays: Vec<u32> = recording
+            .frames(Duration::from_millis(1))
+            .iter()
+            .map(|f| f.1)
+            .collect();
+        assert_eq!(delays, [67, 66, 34]);
+        let delays: Vec<u32> = recording
+            .frames(Duration::from_millis(20))
+            .iter()
+            .map(|f| f.1)
+            .collect();
+        assert_eq!(delays, [3, 4, 1]);
+
+        let dir = std::env::temp_dir().join(format!("chip8-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let palette = Palette::preset("amber").unwrap();
+        let gif = dir.join("test.gif");
+        recording.save(&gif, &palette, 2).unwrap();
+        let decoder = GifDecoder::new(File::open(&gif).unwrap()).unwrap();
+        let frames = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), 3);
+        let last = frames[2].buffer();
+        assert_eq!(last.dimensions(), (128, 64));
+        assert_eq!(last.get_pixel(4, 0).0[..3], palette.foreground.0);
+        assert_eq!(last.get_pixel(6, 0).0[..3], palette.background.0);
+        assert!(recording.save(&dir.join("test.bmp"), &palette, 1).is_err());
+        assert!(Recording::new().save(&gif, &palette, 1).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_screenshot() {
+        let mut screen = [[0; 64]; 32];
+        screen[31][63] = 1;
+        let palette = Palette::default();
+        let image = image(&screen, &palette, 3);
+        assert_eq!(image.dimensions(), (192, 96));
+        assert_eq!(image.get_pixel(191, 95).0, palette.foreground.0);
+        assert_eq!(image.get_pixel(188, 95).0, palette.background.0);
+
+        let capture = Capture::default();
+        let path = capture.path(Path::new("roms/PONG2.ch8"), "png");
+        assert_eq!(path, Path::new("PONG2-1.png"));
+        let paths = capture.paths(Path::new("PONG2"), &["profile.txt", "profile.csv"]);
+        assert_eq!(
+            paths,
+            [
+                Path::new("PONG2-1.profile.txt"),
+                Path::new("PONG2-1.profile.csv")
+            ]
+        );
+        assert!(is_gif(Path::new("a.GIF")));
+        assert!(!is_gif(Path::new("a.png")));
+    }
+}
@@ -1,3 +1,9 @@
+use crate::coverage::Coverage;
+use crate::disasm::{Platform, Syntax};
+use crate::profile::Profiler;
+use crate::trace::Tracer;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
@@ -26,6 +32,93 @@ const LINE_LENGHT: usize = 8;
 const WIDTH: usize = 64;
 const HEIGHT: usize = 32;
 
+/// Behaviours that differ between CHIP-8 interpreters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place.
+    pub shift_reads_vy: bool,
+    /// FX55 and FX65 leave I pointing past the last register stored.
+    pub load_store_increments_i: bool,
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub const CHIP8: Quirks = Quirks {
+        shift_reads_vy: true,
+        load_store_increments_i: true,
+    };
+
+    /// SUPER-CHIP 1.1 on the HP 48.
+    pub const SCHIP: Quirks = Quirks {
+        shift_reads_vy: false,
+        load_store_increments_i: false,
+    };
+
+    pub const PROFILES: [(&'static str, Quirks); 2] =
+        [("chip8", Quirks::CHIP8), ("schip", Quirks::SCHIP)];
+
+    /// The name of the profile these quirks match, if any.
+    pub fn name(&self) -> Option<&'static str> {
+        Quirks::PROFILES
+            .iter()
+            .find(|(_, quirks)| quirks == self)
+            .map(|(name, _)| *name)
+    }
+
+    pub fn from_name(name: &str) -> Result<Quirks, String> {
+        Quirks::PROFILES
+            .iter()
+            .find(|(n, _)| *n == name.to_lowercase())
+            .map(|(_, quirks)| *quirks)
+            .ok_or_else(|| {
+                let names: Vec<_> = Quirks::PROFILES.iter().map(|(n, _)| *n).collect();
+                format!("unknown profile '{}', expected one of {}", name, names.join(", "))
+            })
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::SCHIP
+    }
+}
+
+/// An error that stopped the machine. Whatever the program does, `cycle`
+/// reports one of these instead of panicking.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The ROM does not fit between 0x200 and the end of memory.
+    ProgramTooLarge(usize),
+    /// The PC points at the last byte of memory or past it.
+    PcOutOfBounds(u16),
+    /// CALL with all 16 stack slots in use.
+    StackOverflow,
+    /// RET with an empty stack.
+    StackUnderflow,
+    /// An instruction read or wrote memory past 0xFFF.
+    MemoryOutOfBounds(usize),
+    NotImplemented(u16),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::ProgramTooLarge(size) => {
+                write!(f, "program of {} bytes does not fit in memory", size)
+            }
+            Fault::PcOutOfBounds(pc) => write!(f, "program counter {:#05x} is out of memory", pc),
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "return with an empty stack"),
+            Fault::MemoryOutOfBounds(addr) => {
+                write!(f, "memory access at {:#05x} is out of bounds", addr)
+            }
+            Fault::NotImplemented(opcode) => {
+                write!(f, "not implemented {:04X} {}", opcode, Syntax::PseudoC.format(*opcode))
+            }
+        }
+    }
+}
+
 // fn bcd(_digit: u32, _n: u8) -> u8 {
 //     println!("BCD");
 //     // machine.stop = true;
@@ -51,6 +144,14 @@ pub struct Machine {
     pub stop: bool,
     pub video_mem: [[u8; 64]; 32],
     program_size: usize,
+    cycles: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    fault: Option<Fault>,
+    quirks: Quirks,
+    platform: Platform,
+    rng: StdRng,
 }
 
 fn get_bit(opcode: u16, index: usize) -> u8 {
@@ -61,6 +162,31 @@ fn extract_bit(byte: u8, index: usize) -> u8 {
     1 & (byte >> (index))
 }
 
+fn fault(machine: &mut Machine, fault: Fault) {
+    machine.fault = Some(fault);
+    machine.stop = true;
+}
+
+fn access(machine: &mut Machine, addr: usize, len: usize) {
+    if let Some(coverage) = &mut machine.coverage {
+        coverage.access(addr, len);
+    }
+}
+
+/// Stores one byte on behalf of the running instruction.
+fn store(machine: &mut Machine, addr: usize, value: u8) {
+    machine.memory[addr] = value;
+    if let Some(tracer) = &mut machine.tracer {
+        tracer.write(addr as u16, value);
+    }
+}
+
+fn add_value(machine: &mut Machine) {
+    let x = get_bit(machine.opcode, 2) as usize;
+    let nn = (machine.opcode & 0x00FF) as u8;
+    machine.register[x] = machine.register[x].wrapping_add(nn);
+}
+
 fn add_reg(machine: &mut Machine, x: u8, y:u8) {
     let (val, overflow) = machine.register[x as usize].overflowing_add(machine.register[y as usize]);
     machine.register[x as usize] = val;
@@ -76,28 +202,44 @@ fn mem(machine: &mut Machine) {
 fn assign_reg(machine: &mut Machine) {
     let x = get_bit(machine.opcode, 2) as usize;
     let nn = (machine.opcode & 0x00FF) as u8;
-    println!("machine.register[{}] = {} ", x, nn);
     machine.register[x] = nn;
 }
 
 fn call(machine: &mut Machine) {
-    let addr = (machine.opcode & 0xFFF) as u16;
+    let addr = machine.opcode & 0xFFF;
+    if machine.sp as usize >= machine.stack.len() {
+        return fault(machine, Fault::StackOverflow);
+    }
     machine.stack[machine.sp as usize] = machine.pc;
     machine.sp += 1;
-    machine.pc = addr - 2;
+    machine.pc = addr.wrapping_sub(2);
 }
 
 fn goto(machine: &mut Machine) {
-    let addr = (machine.opcode & 0xFFF) as u16;
-    machine.pc = addr - 2;
+    let addr = machine.opcode & 0xFFF;
+    machine.pc = addr.wrapping_sub(2);
 }
 
 fn return_func(machine: &mut Machine) {
-    machine.pc = machine.stack[(machine.sp - 1) as usize] - 2;
+    if machine.sp == 0 {
+        return fault(machine, Fault::StackUnderflow);
+    }
+    machine.pc = machine.stack[(machine.sp - 1) as usize];
     machine.sp -= 1;
     // machine.pc = addr - 2;
 }
 
+fn shift_operand(machine: &Machine) -> u8 {
+    let source = if machine.quirks.shift_reads_vy { 1 } else { 2 };
+    machine.register[get_bit(machine.opcode, source) as usize]
+}
+
+fn random(machine: &mut Machine) {
+    let x = get_bit(machine.opcode, 2) as usize;
+    let nn = (machine.opcode & 0x00FF) as u8;
+    machine.register[x] = machine.rng.gen::<u8>() & nn;
+}
+
 fn if_eq_reg(machine: &mut Machine) {
     let x = get_bit(machine.opcode, 2) as usize;
     let y = get_bit(machine.opcode, 1) as usize;
@@ -132,39 +274,62 @@ fn if_eq(machine: &mut Machine) {
     }
 }
 
+fn if_key(machine: &mut Machine) {
+    let x = get_bit(machine.opcode, 2) as usize;
+    if machine.key[(machine.register[x] & 0xF) as usize] != 0 {
+        machine.pc += 2;
+    }
+}
+
+fn if_not_key(machine: &mut Machine) {
+    let x = get_bit(machine.opcode, 2) as usize;
+    if machine.key[(machine.register[x] & 0xF) as usize] == 0 {
+        machine.pc += 2;
+    }
+}
+
 fn xor(machine: &mut Machine) {
     let x = get_bit(machine.opcode, 2) as usize;
     let y = get_bit(machine.opcode, 1) as usize;
 
-    machine.register[x] = machine.register[x] ^ machine.register[y];
+    machine.register[x] ^= machine.register[y];
 }
 
 fn reg_dump(machine: &mut Machine) {
     let x = get_bit(machine.opcode, 2) as usize;
+    if machine.index as usize + x >= machine.memory.len() {
+        return fault(machine, Fault::MemoryOutOfBounds(machine.index as usize + x));
+    }
+    access(machine, machine.index as usize, x + 1);
 
     for offset in 0..(x + 1) {
-        machine.memory[machine.index as usize + offset] = machine.register[offset];
+        store(machine, machine.index as usize + offset, machine.register[offset]);
+    }
+    if machine.quirks.load_store_increments_i {
+        machine.index += x as u16 + 1;
     }
 }
 
 fn reg_fill(machine: &mut Machine) {
     let x = get_bit(machine.opcode, 2) as usize;
+    if machine.index as usize + x >= machine.memory.len() {
+        return fault(machine, Fault::MemoryOutOfBounds(machine.index as usize + x));
+    }
+    access(machine, machine.index as usize, x + 1);
 
     for offset in 0..(x + 1) {
         machine.register[offset] = machine.memory[machine.index as usize + offset];
     }
+    if machine.quirks.load_store_increments_i {
+        machine.index += x as u16 + 1;
+    }
 }
 
 fn add_index(machine: &mut Machine) {
     let x = get_bit(machine.opcode, 2) as usize;
-    machine.index += machine.register[x] as u16;
+    machine.index = machine.index.wrapping_add(machine.register[x] as u16);
 }
 
-fn reg_add(machine: &mut Machine) {
-    let (val, overflow) = machine.register[x as usize].overflowing_add(machine.register[y as usize]);
-    machine.register[x as usize] = val;
-    machine.register[0xF] = overflow as u8;
-}
 //Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels.
 //Each row of 8 pixels is read as bit-coded starting from memory location I;
 //I value doesn’t change after the execution of this instruction.
@@ -175,9 +340,12 @@ fn draw(machine: &mut Machine) {
     let y = machine.register[get_bit(machine.opcode, 1) as usize] as usize;
     let lines = get_bit(machine.opcode, 0) as usize;
     let index = machine.index as usize;
-    println!(" draw lines {} from ({},{})", lines, x, y);
+    if lines > 0 && index + lines > machine.memory.len() {
+        return fault(machine, Fault::MemoryOutOfBounds(index + lines - 1));
+    }
+    access(machine, index, lines);
     machine.register[0xF] = 0x0;
-    for offset in 0..lines * LINE_LENGHT as usize {
+    for offset in 0..lines * LINE_LENGHT {
         let x_col = x + (offset / LINE_LENGHT);
         let y_row = y + offset % LINE_LENGHT;
         // let vmem_offset = x_col + y_row;
@@ -197,45 +365,26 @@ fn draw(machine: &mut Machine) {
             0xFF
         };
 
-        println!(
-            "vmem ({},{})  mem ({},{}) {:#02x}  -> {:#02x}",
-            x_col / WIDTH,
-            y_row,
-            memory_addr,
-            offset % LINE_LENGHT,
-            old_pixel,
-            new_pixel
-            );
         machine.register[0xF] |= (new_pixel != old_pixel) as u8;
 
         machine.video_mem[x_col][y_row] ^= new_pixel;
     }
-    for x in machine.video_mem.iter() {
-        for y in x.iter() {
-            print!("{}", if *y == 0 { 0 } else { 1 });
-        }
-        print!("\n");
-    }
-}
-fn clear_display(machine: &mut Machine) {
-    machine.video_mem = [[0; 64]; 32]
 }
-
-fn bcd(_machine: &mut Machine) {
-    println!("Not implemented");
+//Stores the decimal digits of VX at I, I+1 and I+2.
+fn bcd(machine: &mut Machine) {
+    let value = machine.register[get_bit(machine.opcode, 2) as usize];
+    let index = machine.index as usize;
+    if index + 2 >= machine.memory.len() {
+        return fault(machine, Fault::MemoryOutOfBounds(index + 2));
+    }
+    access(machine, index, 3);
+    store(machine, index, value / 100);
+    store(machine, index + 1, value / 10 % 10);
+    store(machine, index + 2, value % 10);
 }
 
 fn non_implemented(machine: &mut Machine) {
-    println!(
-        "Not implemented {:#02x} {}",
-        machine.opcode,
-        (get_opcode(machine.opcode).unwrap().display)(machine.opcode)
-        );
-    machine.stop = true;
-}
-
-fn unknwon_opcode(_machine: u16) -> String {
-    "????".to_string()
+    fault(machine, Fault::NotImplemented(machine.opcode));
 }
 
 // type Opcode = fn(&mut Machine);
@@ -243,7 +392,6 @@ struct Opcode {
     mask: u16,
     value: u16,
     call: fn(&mut Machine),
-    display: fn(u16) -> String,
 }
 
 fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
@@ -258,19 +406,16 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
             call: |machine| {
                 machine.video_mem = [[0; 64]; 32];
             },
-            display: |_machine| "disp_clear()".to_string(),
         },
         Opcode {
             mask: 0xFF,
             value: 0xEE,
             call: return_func,
-            display: |_machine| "return".to_string(),
         },
         Opcode {
             mask: 0x000,
             value: 0x000,
             call: non_implemented,
-            display: |_machine| "call".to_string(),
         },
         ],
         );
@@ -280,7 +425,6 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 mask: 0x0,
                 value: 0x0,
                 call: goto,
-                display: |opcode| format!("goto {:#02x}", (opcode & 0xFFF) as u16),
             }],
             );
         opcodes.insert(
@@ -288,8 +432,7 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
             vec![Opcode {
                 mask: 0x0,
                 value: 0x0,
-                call: call,
-                display: |opcode| format!("call {:#02x}", (opcode & 0xFFF) as u16),
+                call,
             }],
             );
         opcodes.insert(
@@ -298,11 +441,6 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 mask: 0x0,
                 value: 0x0,
                 call: if_eq,
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let val = (opcode & 0xFF) as u8;
-                    format!("if register[{}] == {}", x, val)
-                },
             }],
             );
         opcodes.insert(
@@ -311,24 +449,14 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 mask: 0x0,
                 value: 0x0,
                 call: if_ne,
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let val = (opcode & 0xFF) as u8;
-                    format!("if register[{}] != {}", x, val)
-                },
             }],
             );
         opcodes.insert(
             0x5000u16,
             vec![Opcode {
-                mask: 0x0,
+                mask: 0xF,
                 value: 0x0,
                 call: if_ne_reg,
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("if register[{}] != register[{}]", x, y)
-                },
             }],
             );
         opcodes.insert(
@@ -337,11 +465,6 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 mask: 0x0,
                 value: 0x0,
                 call: assign_reg,
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let val = (opcode & 0xFF) as u8;
-                    format!("register[{}] = {}", x, val)
-                },
             }],
             );
         opcodes.insert(
@@ -349,16 +472,7 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
             vec![Opcode {
                 mask: 0x0,
                 value: 0x0,
-                call: |machine| {
-                    let x = get_bit(machine.opcode, 2) as usize;
-                    let nn = (machine.opcode & 0x00FF) as u8;
-                    machine.register[x] += nn;
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let val = (opcode & 0xFF) as u8;
-                    format!("register[{}] += {}", x, val)
-                },
+                call: add_value,
             }],
             );
 
@@ -373,11 +487,6 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                     let y = get_bit(machine.opcode, 1) as usize;
                     machine.register[x] = machine.register[y];
                 },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] = register[{}]", x, y)
-                },
             },
             Opcode {
                 mask: 0xF,
@@ -385,12 +494,7 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 call: |machine| {
                     let x = get_bit(machine.opcode, 2) as usize;
                     let y = get_bit(machine.opcode, 1) as usize;
-                    machine.register[x] = machine.register[x] | machine.register[y];
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] = register[{}] or register[{}]", x, x, y)
+                    machine.register[x] |= machine.register[y];
                 },
             },
             Opcode {
@@ -399,60 +503,35 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 call: |machine| {
                     let x = get_bit(machine.opcode, 2) as usize;
                     let y = get_bit(machine.opcode, 1) as usize;
-                    machine.register[x] = machine.register[x] & machine.register[y];
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] = register[{}] and register[{}]", x, x, y)
+                    machine.register[x] &= machine.register[y];
                 },
             },
             Opcode {
                 mask: 0xF,
                 value: 0x3,
                 call: xor,
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] = register[{}] xor register[{}]", x, x, y)
-                },
             },
             Opcode {
                 mask: 0xF,
                 value: 0x4,
                 call: |machine| {
-                    let x = get_bit(machine.opcode, 2) as usize;
-                    let y = get_bit(machine.opcode, 1) as usize;
-                    machine.register[x] += machine.register[y]
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] +=  register[{}]", x, y)
+                    let x = get_bit(machine.opcode, 2);
+                    let y = get_bit(machine.opcode, 1);
+                    add_reg(machine, x, y)
                 },
             },
             Opcode {
                 mask: 0xF,
                 value: 0x5,
                 call: non_implemented,
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] -=  register[{}]", x, y)
-                },
             },
             Opcode {
                 mask: 0xF,
                 value: 0x6,
                 call: |machine| {
-                    let x = get_bit(machine.opcode, 2) as usize;
-                    machine.register[0xf] = machine.register[x] & 0x1;
-                    machine.register[x] >= 1;
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let _y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] >>= 1", x)
+                    let value = shift_operand(machine);
+                    machine.register[get_bit(machine.opcode, 2) as usize] = value >> 1;
+                    machine.register[0xf] = value & 0x1;
                 },
             },
             Opcode {
@@ -461,26 +540,16 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 call: |machine| {
                     let x = get_bit(machine.opcode, 2) as usize;
                     let y = get_bit(machine.opcode, 1) as usize;
-                    machine.register[x] = machine.register[y] - machine.register[x];
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] = register[{}] - register[{}]", x, x, y)
+                    machine.register[x] = machine.register[y].wrapping_sub(machine.register[x]);
                 },
             },
             Opcode {
                 mask: 0xF,
                 value: 0xE,
                 call: |machine| {
-                    let x = get_bit(machine.opcode, 2) as usize;
-                    machine.register[0xf] = machine.register[x] & 0x1;
-                    machine.register[x] <= 1;
-                },
-                display: |opcode| {
-                    let x = get_bit(opcode, 2) as usize;
-                    let _y = get_bit(opcode, 1) as usize;
-                    format!("register[{}] <<= 1", x)
+                    let value = shift_operand(machine);
+                    machine.register[get_bit(machine.opcode, 2) as usize] = value << 1;
+                    machine.register[0xf] = value >> 7;
                 },
             },
             ],
@@ -492,11 +561,6 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                     mask: 0x0,
                     value: 0x0,
                     call: if_eq_reg,
-                    display: |opcode| {
-                        let x = get_bit(opcode, 2) as usize;
-                        let y = get_bit(opcode, 1) as usize;
-                        format!("if register[{}] != register[{}]", x, y)
-                    },
                 }],
                 );
             opcodes.insert(
@@ -505,10 +569,14 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                     mask: 0x0,
                     value: 0x0,
                     call: mem,
-                    display: |opcode| {
-                        let nn = (opcode & 0x0FFF) as u16;
-                        format!("I = {}", nn)
-                    },
+                }],
+                );
+            opcodes.insert(
+                0xC000u16,
+                vec![Opcode {
+                    mask: 0x0,
+                    value: 0x0,
+                    call: random,
                 }],
                 );
             opcodes.insert(
@@ -517,12 +585,6 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                     mask: 0x0,
                     value: 0x0,
                     call: draw,
-                    display: |opcode| {
-                        let x = get_bit(opcode, 2) as usize;
-                        let y = get_bit(opcode, 1) as usize;
-                        let n = get_bit(opcode, 0) as usize;
-                        format!("draw(register[{}], register[{}] , {})", x, y, n)
-                    },
                 }],
                 );
             opcodes.insert(
@@ -531,20 +593,12 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                 Opcode {
                     mask: 0xFF,
                     value: 0x9E,
-                    call: non_implemented,
-                    display: |opcode| {
-                        let x = get_bit(opcode, 2) as usize;
-                        format!("if(key() == register[{}]", x)
-                    },
+                    call: if_key,
                 },
                 Opcode {
                     mask: 0xFF,
                     value: 0xA1,
-                    call: non_implemented,
-                    display: |opcode| {
-                        let x = get_bit(opcode, 2) as usize;
-                        format!("if(key() != register[{}]", x)
-                    },
+                    call: if_not_key,
                 },
                 ],
                 );
@@ -555,85 +609,49 @@ fn create_opcodes() -> HashMap<u16, Vec<Opcode>> {
                         mask: 0xFF,
                         value: 0x07,
                         call: non_implemented,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("register[{}] = get_delay", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x0A,
                         call: non_implemented,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("register[{}] = get_key", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x15,
                         call: non_implemented,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("delay_timer(register[{}] )", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x18,
                         call: non_implemented,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("sound_timer(register[{}] )", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x1E,
                         call: add_index,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("I +=register[{}]", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x29,
                         call: |machine| {
                             let x = get_bit(machine.opcode, 2) as usize;
-                            machine.index = (20 * machine.register[x]).into();
-                        },
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("I = sprite_addr[register[{}]]", x)
+                            machine.index = 5 * (machine.register[x] & 0xF) as u16;
                         },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x33,
                         call: bcd,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("set_BCD(register[{}])", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x55,
                         call: reg_dump,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("reg_dump(register[{}],&I) ", x)
-                        },
                     },
                     Opcode {
                         mask: 0xFF,
                         value: 0x65,
                         call: reg_fill,
-                        display: |opcode| {
-                            let x = get_bit(opcode, 2) as usize;
-                            format!("reg_load(register[{}],&I) ", x)
-                        },
                     },
                     ],
                     );
@@ -646,9 +664,8 @@ lazy_static! {
 }
 
 fn get_opcode(opcode: u16) -> Result<&'static Opcode, String> {
-    let key = (opcode & 0xF000) as u16;
+    let key = opcode & 0xF000;
     if !OPCODES.contains_key(&key) {
-        println!("Unknown sub instruction {:#02x}", opcode);
         return Err(format!("Unknown sub instruction {:#02x}", opcode));
     }
     for op in OPCODES[&key].iter() {
@@ -656,7 +673,6 @@ fn get_opcode(opcode: u16) -> Result<&'static Opcode, String> {
             return Ok(op);
         }
     }
-    println!("Unknown sub instruction {:#02x}", opcode);
     Err(format!("Unknown sub instruction {:#02x}", opcode))
 }
 
@@ -676,11 +692,24 @@ impl Machine {
             stop: false,
             video_mem: [[0; 64]; 32],
             program_size: program.len(),
+            cycles: 0,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            fault: None,
+            quirks: Quirks::default(),
+            platform: Platform::Chip8,
+            rng: StdRng::from_entropy(),
         };
         for (i, x) in CHIP8_FONTSET.iter().enumerate() {
             machine.memory[i] = *x;
         }
-        for (i, x) in program.iter().enumerate() {
+        let room = machine.memory.len() - 0x200;
+        if program.len() > room {
+            fault(&mut machine, Fault::ProgramTooLarge(program.len()));
+            machine.program_size = room;
+        }
+        for (i, x) in program.iter().take(room).enumerate() {
             machine.memory[i + 0x200] = *x;
         }
 
@@ -690,52 +719,215 @@ impl Machine {
         machine
     }
 
-    pub fn get_source_code(&self) -> Vec<String> {
-        let mut sourceCode: Vec<String> = Vec::new();
+    pub fn get_source_code(&self, syntax: Syntax) -> Vec<String> {
+        let mut source_code: Vec<String> = Vec::new();
         for pc in (0..self.program_size).step_by(2) {
             let opcode = (self.memory[pc + 0x200] as u16) << 8 | self.memory[pc + 0x201] as u16;
-            match get_opcode(opcode) {
-                Ok(op) => {
-                    sourceCode.push((op.display)(opcode))
-                        // sourceCode = format!(
-                        //     "{}\n{:#02x}: {}",
-                        //     sourceCode,
-                        //     pc + 0x200,
-                        //     &(op.display)(opcode)
-                }
-                Err(msg) => sourceCode.push(msg),
-            }
+            source_code.push(syntax.format(opcode));
         }
-        sourceCode
+        source_code
     }
 
     pub fn cycle(&mut self) -> bool {
+        if self.fault.is_some() {
+            return true;
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.begin(self);
+            self.tracer = Some(tracer);
+        }
+        let stop = self.execute();
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.end() {
+                eprintln!("Tracing stopped: {}", e);
+                self.tracer = None;
+            }
+        }
+        stop
+    }
+
+    fn execute(&mut self) -> bool {
         let pc = self.pc as usize;
+        if pc + 1 >= self.memory.len() {
+            fault(self, Fault::PcOutOfBounds(self.pc));
+            return true;
+        }
         self.opcode = (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc, self.opcode);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.execute(self.pc);
+        }
 
         let c = ((self.opcode & 0xF000) >> 12) as u8;
         let x = ((self.opcode & 0x0F00) >> 8) as u8;
         let y = ((self.opcode & 0x00F0) >> 4) as u8;
-        let d = ((self.opcode & 0x000F) >> 0) as u8;
+        let d = (self.opcode & 0x000F) as u8;
 
         match(c,x,y,d) {
-            (0x0, _, _, _) => disp_clear(self),
+            (0x0, 0x0, 0xE, 0x0) => disp_clear(self),
             (0x0, 0x0, 0xE, 0xE) => return_func(self),
             (0x1, _, _, _) => goto(self),
             (0x2, _, _, _) => call(self),
             (0x3, _, _, _) => if_eq(self),
             (0x4, _, _, _) => if_ne(self),
-            (0x5, _,_, 0xE) => if_ne_reg(self),
             (0x6, _, _, _) => assign_reg(self),
-            (0x7, _, _, _) => add_reg(self),
+            (0x7, _, _, _) => add_value(self),
             (0x8,_,_,0x4) => add_reg(self, x,y),
-            _ => non_implemented(self),
+            _ => match get_opcode(self.opcode) {
+                Ok(op) => (op.call)(self),
+                Err(_) => non_implemented(self),
+            },
 
         }
 
-        self.pc += 2;
+        // A faulting instruction leaves the PC on itself.
+        if self.fault.is_some() {
+            return true;
+        }
+        self.pc = self.pc.wrapping_add(2);
+        self.cycles += 1;
         self.stop
     }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// The platform the program was written for.
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+    }
+
+    /// Makes CXNN produce the same numbers on every run.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Why the machine stopped, if it was because of an error.
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Whether `key` is held down.
+    pub fn key(&self, key: u8) -> bool {
+        self.key[(key & 0xF) as usize] != 0
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.key[(key & 0xF) as usize] = pressed as u8;
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Attaches a profiler, returning the one attached before.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    /// Attaches a coverage map, returning the one attached before.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        std::mem::replace(&mut self.coverage, coverage)
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn coverage_mut(&mut self) -> Option<&mut Coverage> {
+        self.coverage.as_mut()
+    }
+
+    /// Size of the loaded program in bytes.
+    pub fn program_size(&self) -> usize {
+        self.program_size
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.register
+    }
+
+    /// Addresses of the pending CALL instructions, outermost first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn memory(&self) -> &[u8; 4096] {
+        &self.memory
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8; 16] {
+        &mut self.register
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8; 4096] {
+        &mut self.memory
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc & 0xFFF;
+    }
+
+    pub fn set_index(&mut self, index: u16) {
+        self.index = index;
+    }
+
+    /// Sets the stack depth, keeping it within the 16 stack slots.
+    pub fn set_sp(&mut self, sp: u8) {
+        self.sp = sp.min(self.stack.len() as u8);
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
 }
 
 impl fmt::Debug for Machine {
@@ -770,7 +962,7 @@ stack:
 {}
 ",
 self.opcode,
-(get_opcode(self.opcode).unwrap().display)(self.opcode),
+Syntax::PseudoC.format(self.opcode),
 self.index,
 self.pc,
 self.delay_timer,
@@ -783,7 +975,6 @@ stack_string,
 }
 
 pub fn read_game(name: &str) -> std::io::Result<Vec<u8>> {
-    if name == "0" {}
     match name {
         "0" => Ok(vec![0xD0, 0x05]),
         _ => {
@@ -835,6 +1026,35 @@ mod test {
         assert_eq!(machine.register[0], 254);
     }
 
+    #[test]
+    fn test_dispatch_table() {
+        // The table must not panic either, whatever cycle matches first.
+        let mut machine = Machine::new(&[]);
+        machine.register[0] = 0xFF;
+        machine.register[1] = 0xFF;
+        for opcode in [0x7001, 0x8014].iter() {
+            machine.opcode = *opcode;
+            (get_opcode(*opcode).unwrap().call)(&mut machine);
+        }
+        assert_eq!(machine.register[0], 0xFF);
+
+        let mut machine = Machine::new(&[0x50, 0x1E]);
+        assert!(machine.cycle());
+        assert_eq!(machine.fault(), Some(Fault::NotImplemented(0x501E)));
+    }
+
+    #[test]
+    fn test_call_return() {
+        let prog: [u8; 6] = [0x22, 0x04, 0x12, 0x02, 0x00, 0xEE];
+        let mut machine = Machine::new(&prog);
+        machine.cycle();
+        assert_eq!(machine.pc(), 0x204);
+        assert_eq!(machine.stack(), &[0x200]);
+        machine.cycle();
+        assert_eq!(machine.pc(), 0x202);
+        assert!(machine.stack().is_empty());
+    }
+
     #[test]
     fn test_draw() {
         let index: usize = 10;
@@ -1001,6 +1221,32 @@ mod test {
         assert_eq!(machine.register[1], 23 ^ 56);
     }
 
+    #[test]
+    fn test_shift_left() {
+        let prog: [u8; 4] = [0x81, 0x0E, 0x81, 0x0E];
+        let mut machine = Machine::new(&prog);
+        machine.register[1] = 0x81;
+        machine.cycle();
+        assert_eq!((machine.register[1], machine.register[0xF]), (0x02, 1));
+        machine.cycle();
+        assert_eq!((machine.register[1], machine.register[0xF]), (0x04, 0));
+    }
+
+    #[test]
+    fn test_if_key() {
+        let prog: [u8; 8] = [0xE1, 0x9E, 0x00, 0x00, 0xE1, 0xA1, 0xE2, 0xA1];
+        let mut machine = Machine::new(&prog);
+        machine.register[1] = 0x5;
+        machine.register[2] = 0x6;
+        machine.key[0x5] = 1;
+        machine.cycle();
+        assert_eq!(machine.pc, 0x204);
+        machine.cycle();
+        assert_eq!(machine.pc, 0x206);
+        machine.cycle();
+        assert_eq!(machine.pc, 0x20A);
+    }
+
     #[test]
     fn test_reg_assing() {
         let prog: [u8; 2] = [0x81, 0x20];
@@ -1030,6 +1276,63 @@ mod test {
         assert_eq!(machine.register[1], 1);
     }
 
+    #[test]
+    fn test_bcd() {
+        let prog: [u8; 2] = [0xF3, 0x33];
+        let mut machine = Machine::new(&prog);
+        machine.register[3] = 254;
+        machine.index = 0x300;
+        machine.cycle();
+        assert_eq!(&machine.memory[0x300..0x303], &[2, 5, 4]);
+    }
+
+    #[test]
+    fn test_quirks() {
+        let prog: [u8; 6] = [0x81, 0x26, 0x81, 0x2E, 0xF1, 0x55];
+        let mut machine = Machine::new(&prog);
+        machine.register[1] = 0x81;
+        machine.register[2] = 0x04;
+        machine.index = 0x300;
+        machine.cycle();
+        assert_eq!((machine.register[1], machine.register[0xF]), (0x40, 1));
+
+        let mut machine = Machine::new(&prog);
+        machine.set_quirks(Quirks::from_name("CHIP8").unwrap());
+        machine.register[1] = 0x81;
+        machine.register[2] = 0x84;
+        machine.index = 0x300;
+        machine.cycle();
+        assert_eq!((machine.register[1], machine.register[0xF]), (0x42, 0));
+        machine.cycle();
+        assert_eq!((machine.register[1], machine.register[0xF]), (0x08, 1));
+        machine.cycle();
+        assert_eq!(machine.index, 0x302);
+        assert!(Quirks::from_name("xo").is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut machine = Machine::new(&[]);
+        machine.set_key(0x15, true);
+        assert!(machine.key(0x5) && !machine.key(0x1));
+        assert_eq!(machine.key[0x5], 1);
+        machine.set_key(0x5, false);
+        assert!(!machine.key(0x15));
+    }
+
+    #[test]
+    fn test_random() {
+        let prog: [u8; 2] = [0xC0, 0x0F];
+        let mut first = Machine::new(&prog);
+        let mut second = Machine::new(&prog);
+        first.set_seed(7);
+        second.set_seed(7);
+        first.cycle();
+        second.cycle();
+        assert_eq!(first.register[0], second.register[0]);
+        assert!(first.register[0] <= 0x0F);
+    }
+
     // #[test]
     // fn test_bcd() {
     //     let digits: Vec<_> = (0..8).map(|i| bcd(0x01234567u32, i as u8)).collect();
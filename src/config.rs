@@ -0,0 +1,232 @@
+//! User settings, kept in a TOML file between runs.
+//!
+//! ```toml
+//! quirks = "chip8"
+//! ips = 700
+//! scale = 12
+//! keys = "Left=4 Right=6"
+//! recent = ["assets/games/PONG2"]
+//!
+//! [palette]
+//! foreground = "#FFFFFF"
+//! background = "#000000"
+//!
+//! [roms.b232ef880bd6060fb45fa6effed7edf0ae95670e]
+//! ips = 500
+//! ```
+//!
+//! `quirks` and `ips` apply to ROMs the database does not know, and `keys`
+//! replaces the hex layout while keeping the games' own controls. The tables
+//! under `roms`, keyed by SHA-1, override the settings of a single ROM; their
+//! `keys` are the ROM's whole keymap.
+
+use crate::capture::Capture;
+use crate::chip::Quirks;
+use crate::effects::Effects;
+use crate::keymap::Keymap;
+use crate::library::Settings;
+use crate::palette::Palette;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of recently loaded ROMs remembered.
+pub const MAX_RECENT: usize = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Quirk profile for ROMs not in the database.
+    pub quirks: Option<String>,
+    /// Instructions per second for ROMs not in the database.
+    pub ips: Option<u32>,
+    /// Window size in multiples of the 64x32 screen.
+    pub scale: Option<u32>,
+    /// Key bindings used instead of the hex layout, as in `Left=4 Right=6`.
+    pub keys: Option<String>,
+    pub recent: Vec<PathBuf>,
+    /// The imgui window layout, in imgui's ini format.
+    pub layout: Option<String>,
+    pub palette: Palette,
+    pub effects: Effects,
+    pub capture: Capture,
+    pub roms: BTreeMap<String, RomConfig>,
+}
+
+/// Settings of a single ROM, overriding everything but the command line.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RomConfig {
+    pub quirks: Option<String>,
+    pub ips: Option<u32>,
+    pub keys: Option<String>,
+}
+
+/// Where the settings are kept: `chip8/settings.toml` in the platform's
+/// configuration directory.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").or_else(|| env::var_os("APPDATA")) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("chip8").join("settings.toml"))
+}
+
+impl Config {
+    /// Reads the settings at `path`. A missing file gives the defaults.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text).map_err(|e| {
+                let message = format!("{}: {}", path.display(), e);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Checks the quirk profiles and key bindings, so `apply` can't fail.
+    fn check(&self) -> Result<(), String> {
+        let roms = self.roms.values().map(|rom| (&rom.quirks, &rom.keys));
+        for (quirks, keys) in Some((&self.quirks, &self.keys)).into_iter().chain(roms) {
+            if let Some(name) = quirks {
+                Quirks::from_name(name)?;
+            }
+            if let Some(keys) = keys {
+                Keymap::parse(keys)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_text(&self) -> String {
+        toml::to_string(self).expect("settings are always representable")
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_text())
+    }
+
+    /// Moves `path` to the front of the recent ROMs.
+    pub fn add_recent(&mut self, path: &Path) {
+        self.recent.retain(|recent| recent != path);
+        self.recent.insert(0, path.to_path_buf());
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    /// Applies the defaults, for ROMs not in the database, and then the
+    /// overrides of the ROM with hash `sha1`.
+    pub fn apply(&self, settings: &mut Settings, sha1: &str) {
+        let quirks = |name: &Option<String>| name.as_ref().and_then(|n| Quirks::from_name(n).ok());
+        if !settings.known {
+            if let Some(quirks) = quirks(&self.quirks) {
+                settings.quirks = quirks;
+            }
+            if let Some(ips) = self.ips {
+                settings.ips = ips;
+            }
+        }
+        if let Some(mut keymap) = self.keys.as_deref().and_then(|k| Keymap::parse(k).ok()) {
+            for binding in settings.keymap.extras() {
+                if keymap.binding(&binding.host).is_none() {
+                    keymap.bind(&binding.host, binding.key);
+                    keymap.set_turbo(&binding.host, binding.turbo);
+                }
+            }
+            settings.keymap = keymap;
+        }
+        if let Some(rom) = self.roms.get(sha1) {
+            if let Some(quirks) = quirks(&rom.quirks) {
+                settings.quirks = quirks;
+            }
+            if let Some(ips) = rom.ips {
+                settings.ips = ips;
+            }
+            if let Some(keymap) = rom.keys.as_deref().and_then(|k| Keymap::parse(k).ok()) {
+                settings.keymap = keymap;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::effects::Persistence;
+    use crate::library::{self, Database};
+    use crate::palette::Color;
+
+    const TEXT: &str = r##"
+quirks = "chip8"
+ips = 900
+keys = "Space=5 Up=2"
+
+[palette]
+foreground = "#FFB000"
+
+[effects]
+persistence = "max-of-two"
+
+[roms.b232ef880bd6060fb45fa6effed7edf0ae95670e]
+ips = 500
+keys = "Up=C Down=D"
+"##;
+
+    #[test]
+    fn test_round_trip() {
+        let mut config = Config::parse(TEXT).unwrap();
+        assert_eq!(config.palette.foreground, Color([0xFF, 0xB0, 0x00]));
+        assert_eq!(config.palette.background, Palette::default().background);
+        assert_eq!(config.scale, None);
+        assert_eq!(config.effects.persistence, Persistence::MaxOfTwo);
+        config.effects.decay = 2.0;
+        assert_eq!(config.effects.decay(), Effects::MAX_DECAY);
+        config.layout = Some("[Window][Debug##Default]\nPos=60,60\n".to_string());
+        for i in 0..12 {
+            config.add_recent(Path::new(&format!("ROM{}", i % 11)));
+        }
+        assert_eq!(config.recent.len(), MAX_RECENT);
+        assert_eq!(config.recent[0], Path::new("ROM0"));
+        assert_eq!(Config::parse(&config.to_text()).unwrap(), config);
+        assert!(Config::parse("ips = \"fast\"").is_err());
+        assert!(Config::parse("[roms.abc]\nquirks = \"vip\"").is_err());
+        assert!(Config::parse("[palette]\nforeground = \"green\"").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = Config::parse(TEXT).unwrap();
+        let database = Database::bundled();
+        let pong = fs::read("assets/games/PONG2").unwrap();
+        let mut settings = Settings::detect(&pong, &database);
+        config.apply(&mut settings, &library::sha1(&pong));
+        assert_eq!((settings.quirks, settings.ips), (Quirks::SCHIP, 700));
+        assert_eq!(settings.keymap.key("Space"), Some(0x5));
+        assert_eq!(settings.keymap.key("Up"), Some(0x2));
+        assert_eq!(settings.keymap.key("Down"), Some(0x4));
+        assert_eq!(settings.keymap.key("X"), None);
+
+        let rom = [0x12, 0x00];
+        let mut settings = Settings::detect(&rom, &database);
+        config.apply(&mut settings, &library::sha1(&rom));
+        assert_eq!((settings.quirks, settings.ips), (Quirks::CHIP8, 900));
+
+        let pong = fs::read("assets/CHIP8/GAMES/PONG").unwrap();
+        let mut settings = Settings::detect(&pong, &database);
+        config.apply(&mut settings, &library::sha1(&pong));
+        assert_eq!(settings.ips, 500);
+        assert_eq!(settings.keymap.to_string(), "Up=C Down=D");
+    }
+}
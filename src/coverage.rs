@@ -0,0 +1,218 @@
+//! Memory coverage.
+//!
+//! A `Coverage` attached to a `Machine` remembers which bytes were executed
+//! as instructions and which were accessed as data by DXYN, FX33, FX55 and
+//! FX65. Everything else was never touched.
+
+use crate::asm::DebugMap;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+const CODE: u8 = 1;
+const DATA: u8 = 2;
+
+/// How a byte of memory has been used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Use {
+    Untouched,
+    Code,
+    Data,
+    /// Executed and accessed as data, e.g. self-modifying code.
+    Both,
+}
+
+/// Byte counts over a range of memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub code: usize,
+    pub data: usize,
+    pub untouched: usize,
+}
+
+impl Summary {
+    /// Share of the range that was executed or accessed, in percent.
+    pub fn percent(&self) -> f64 {
+        let total = self.code + self.data + self.untouched;
+        if total == 0 {
+            0.0
+        } else {
+            (self.code + self.data) as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+pub struct Coverage {
+    /// Executions of an instruction starting at each address.
+    starts: Vec<u32>,
+    flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            starts: vec![0; 4096],
+            flags: vec![0; 4096],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Coverage::new();
+    }
+
+    /// Records the instruction at `pc`, which is about to run.
+    pub fn execute(&mut self, pc: u16) {
+        let pc = pc as usize & 0xFFF;
+        self.starts[pc] = self.starts[pc].saturating_add(1);
+        self.flags[pc] |= CODE;
+        self.flags[(pc + 1) & 0xFFF] |= CODE;
+    }
+
+    /// Records a data access to `len` bytes from `addr`.
+    pub fn access(&mut self, addr: usize, len: usize) {
+        let end = (addr + len).min(self.flags.len());
+        for flags in self.flags[addr.min(end)..end].iter_mut() {
+            *flags |= DATA;
+        }
+    }
+
+    pub fn use_at(&self, addr: u16) -> Use {
+        match self.flags[addr as usize & 0xFFF] {
+            0 => Use::Untouched,
+            CODE => Use::Code,
+            DATA => Use::Data,
+            _ => Use::Both,
+        }
+    }
+
+    /// How often an instruction starting at `addr` was executed.
+    pub fn executions(&self, addr: u16) -> u32 {
+        self.starts[addr as usize & 0xFFF]
+    }
+
+    /// Counts the bytes in `range`. Bytes used both ways count as code.
+    pub fn summary(&self, range: Range<usize>) -> Summary {
+        let mut summary = Summary::default();
+        for flags in self.flags[range].iter() {
+            match *flags {
+                0 => summary.untouched += 1,
+                DATA => summary.data += 1,
+                _ => summary.code += 1,
+            }
+        }
+        summary
+    }
+
+    /// Writes an LCOV tracefile for the program in `range`. With a debug
+    /// map, records are per line of the map's source file; otherwise the
+    /// "line" numbers are addresses in `rom`. Data is left out, untouched
+    /// bytes are reported with zero hits.
+    pub fn write_lcov(
+        &self,
+        output: &mut dyn Write,
+        rom: &Path,
+        map: Option<&DebugMap>,
+        range: Range<usize>,
+    ) -> io::Result<()> {
+        let mut records: Vec<(usize, u32)> = Vec::new();
+        let source = match map {
+            Some(map) => {
+                let mut lines = map.lines.iter().peekable();
+                while let Some((addr, line)) = lines.next() {
+                    let start = *addr as usize;
+                    let end = lines.peek().map_or(range.end, |(next, _)| **next as usize);
+                    let bytes = start..end.max(start);
+                    if bytes.clone().all(|a| self.flags[a & 0xFFF] == DATA) {
+                        continue;
+                    }
+                    let hits = bytes.map(|a| self.starts[a & 0xFFF]).max().unwrap_or(0);
+                    records.push((*line, hits));
+                }
+                map.source.as_path()
+            }
+            None => {
+                for addr in range {
+                    if self.starts[addr] > 0 || self.flags[addr] == 0 {
+                        records.push((addr, self.starts[addr]));
+                    }
+                }
+                rom
+            }
+        };
+        writeln!(output, "TN:")?;
+        writeln!(output, "SF:{}", source.display())?;
+        for (line, hits) in records.iter() {
+            writeln!(output, "DA:{},{}", line, hits)?;
+        }
+        let hit = records.iter().filter(|(_, hits)| *hits > 0).count();
+        writeln!(output, "LH:{}", hit)?;
+        writeln!(output, "LF:{}", records.len())?;
+        writeln!(output, "end_of_record")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::{chipper, Program};
+    use crate::chip::Machine;
+
+    const SOURCE: &str = "\
+        LD   I, SPRITE
+        DRW  V0, V0, 2
+LOOP:   JP   LOOP
+        JP   LOOP
+SPRITE: DB   #FF, #81";
+
+    fn run() -> (Machine, Program) {
+        let program = chipper::assemble(SOURCE).unwrap();
+        let mut machine = Machine::new(&program.rom);
+        machine.set_coverage(Some(Coverage::new()));
+        for _ in 0..5 {
+            machine.cycle();
+        }
+        (machine, program)
+    }
+
+    #[test]
+    fn test_uses() {
+        let (machine, _) = run();
+        let coverage = machine.coverage().unwrap();
+        assert_eq!(coverage.use_at(0x200), Use::Code);
+        assert_eq!(coverage.use_at(0x205), Use::Code);
+        assert_eq!(coverage.use_at(0x206), Use::Untouched);
+        assert_eq!(coverage.use_at(0x209), Use::Data);
+        assert_eq!(coverage.executions(0x204), 3);
+        let summary = coverage.summary(0x200..0x20A);
+        assert_eq!((summary.code, summary.data, summary.untouched), (6, 2, 2));
+        assert_eq!(summary.percent(), 80.0);
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (machine, program) = run();
+        let coverage = machine.coverage().unwrap();
+        let mut output = Vec::new();
+        coverage
+            .write_lcov(&mut output, Path::new("TEST.CH8"), None, 0x200..0x20A)
+            .unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.starts_with("TN:\nSF:TEST.CH8\nDA:512,1\nDA:514,1\nDA:516,3\nDA:518,0\n"));
+        assert!(text.ends_with("LH:3\nLF:5\nend_of_record\n"));
+
+        let map = DebugMap::new(Path::new("TEST.SRC"), &program);
+        let mut output = Vec::new();
+        coverage
+            .write_lcov(&mut output, Path::new("TEST.CH8"), Some(&map), 0x200..0x20A)
+            .unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let records: Vec<&str> = text.lines().filter(|l| l.starts_with("DA:")).collect();
+        assert_eq!(records, vec!["DA:1,1", "DA:2,1", "DA:3,3", "DA:4,0"]);
+    }
+}
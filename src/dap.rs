@@ -0,0 +1,624 @@
+//! Debug Adapter Protocol server.
+//!
+//! Speaks DAP over any byte stream (stdio or a TCP connection) and drives a
+//! `Machine` through the `Debugger`. There is a single thread, with id 1.
+//! Registers and the call stack are exposed as variables and the whole
+//! address space as one memory reference.
+
+use crate::asm;
+use crate::chip::Machine;
+use crate::config::Config;
+use crate::debugger::{Debugger, Stop};
+use crate::library::{self, Database, Settings};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+/// Cycles executed between checks for incoming requests while running.
+const SLICE: usize = 1000;
+/// Upper bound for a single step over or step out.
+const STEP_LIMIT: usize = 10_000_000;
+/// Largest message body accepted, far above anything a client sends.
+const MAX_MESSAGE: usize = 4 << 20;
+
+/// Reads one `Content-Length` framed message. Returns `None` at the end of
+/// the stream.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    if length > MAX_MESSAGE {
+        let message = format!("message of {} bytes is too large", length);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+pub struct Adapter<W: Write> {
+    output: W,
+    seq: i64,
+    machine: Machine,
+    debugger: Debugger,
+    /// The user's settings, applied to launched ROMs as `run` does.
+    config: Config,
+    source: Option<PathBuf>,
+    /// Addresses of the last setBreakpoints and setInstructionBreakpoints
+    /// requests. Each request replaces only its own set.
+    line_breakpoints: Vec<u16>,
+    instruction_breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+    running: bool,
+    terminated: bool,
+}
+
+impl<W: Write> Adapter<W> {
+    pub fn new(output: W, config: Config) -> Adapter<W> {
+        Adapter {
+            output,
+            seq: 1,
+            machine: Machine::new(&[]),
+            debugger: Debugger::default(),
+            config,
+            source: None,
+            line_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            running: false,
+            terminated: false,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.output, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.running = false;
+        let body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.event("stopped", body)
+    }
+
+    fn report(&mut self, stop: Stop) -> io::Result<()> {
+        match stop {
+            Stop::Breakpoint(_) => self.stopped("breakpoint"),
+            Stop::Stepped => self.stopped("step"),
+            Stop::Halted => {
+                self.running = false;
+                let description = match self.machine.fault() {
+                    Some(fault) => fault.to_string(),
+                    None => "the machine stopped".to_string(),
+                };
+                let body = json!({
+                    "reason": "exception",
+                    "description": description,
+                    "threadId": THREAD_ID,
+                });
+                self.event("stopped", body)
+            }
+            Stop::Limit => Ok(()),
+        }
+    }
+
+    /// Runs the machine for one time slice if it is running.
+    pub fn tick(&mut self) -> io::Result<()> {
+        if self.running {
+            let stop = self.debugger.run(&mut self.machine, SLICE);
+            self.report(stop)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("missing 'program'")?;
+        let path = Path::new(program);
+        let (rom, map) = asm::load(path).map_err(|e| format!("{}: {}", program, e))?;
+        self.source = Some(
+            map.as_ref()
+                .map_or_else(|| path.to_path_buf(), |map| map.source.clone()),
+        );
+        self.machine = Machine::new(&rom);
+        let mut settings = Settings::detect(&rom, &Database::bundled());
+        self.config.apply(&mut settings, &library::sha1(&rom));
+        settings.apply(&mut self.machine);
+        self.debugger = Debugger::new(map);
+        self.line_breakpoints.clear();
+        self.instruction_breakpoints.clear();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(Value::Null)
+    }
+
+    fn source_json(&self) -> Value {
+        match &self.source {
+            Some(path) => json!({
+                "name": path.file_name().map(|n| n.to_string_lossy()),
+                "path": path.to_string_lossy(),
+            }),
+            None => Value::Null,
+        }
+    }
+
+    /// Sets the debugger's breakpoints to those of both kinds of request.
+    fn update_breakpoints(&mut self) {
+        for addr in self.debugger.breakpoints().clone() {
+            self.debugger.remove_breakpoint(addr);
+        }
+        let addrs = self
+            .line_breakpoints
+            .iter()
+            .chain(&self.instruction_breakpoints);
+        for addr in addrs {
+            self.debugger.add_breakpoint(&format!("{:#05x}", addr)).ok();
+        }
+    }
+
+    /// Whether `path` is the source of the launched program.
+    fn is_source(&self, path: &Path) -> bool {
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.source
+            .as_deref()
+            .is_some_and(|source| canonical(source) == canonical(path))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let breakpoints = args["breakpoints"].as_array().into_iter().flatten();
+        let other = match args["source"]["path"].as_str() {
+            Some(path) => !self.is_source(Path::new(path)),
+            None => false,
+        };
+        if other {
+            let message = "not in the launched program";
+            let verified: Vec<Value> = breakpoints
+                .map(|b| json!({ "verified": false, "line": b["line"], "message": message }))
+                .collect();
+            return json!({ "breakpoints": verified });
+        }
+        self.line_breakpoints.clear();
+        let mut verified = Vec::new();
+        for breakpoint in breakpoints {
+            let line = breakpoint["line"].as_u64().unwrap_or(0);
+            let result = self.debugger.resolve(&line.to_string());
+            if let Ok(addr) = result {
+                self.line_breakpoints.push(addr);
+            }
+            verified.push(match result {
+                Ok(addr) => json!({
+                    "verified": true,
+                    "line": line,
+                    "instructionReference": format!("{:#05x}", addr),
+                }),
+                Err(message) => json!({ "verified": false, "line": line, "message": message }),
+            });
+        }
+        self.update_breakpoints();
+        json!({ "breakpoints": verified })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        self.instruction_breakpoints.clear();
+        let mut verified = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let addr = parse_address(reference)
+                .and_then(|a| (a as i64).checked_add(offset))
+                .and_then(|a| u16::try_from(a).ok());
+            if let Some(addr) = addr {
+                self.instruction_breakpoints.push(addr);
+            }
+            verified.push(json!({ "verified": addr.is_some() }));
+        }
+        self.update_breakpoints();
+        json!({ "breakpoints": verified })
+    }
+
+    fn frame(&self, id: usize, addr: u16) -> Value {
+        let line = self.debugger.source_line(addr).map(|(line, _)| line);
+        json!({
+            "id": id,
+            "name": self.debugger.symbolize(addr),
+            "source": line.map(|_| self.source_json()),
+            "line": line.unwrap_or(0),
+            "column": 0,
+            "instructionPointerReference": format!("{:#05x}", addr),
+        })
+    }
+
+    fn stack_trace(&self) -> Value {
+        let mut frames = vec![self.frame(0, self.machine.pc())];
+        for (i, site) in self.machine.stack().iter().rev().enumerate() {
+            frames.push(self.frame(i + 1, *site));
+        }
+        json!({ "totalFrames": frames.len(), "stackFrames": frames })
+    }
+
+    fn variables(&self, args: &Value) -> Value {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let machine = &self.machine;
+        let mut variables = Vec::new();
+        match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => {
+                for (i, v) in machine.registers().iter().enumerate() {
+                    variables.push(variable(format!("V{:X}", i), format!("{:#04x}", v)));
+                }
+                variables.push(variable("I".into(), format!("{:#05x}", machine.index())));
+                variables.push(variable("PC".into(), format!("{:#05x}", machine.pc())));
+                variables.push(variable("SP".into(), machine.stack().len().to_string()));
+                variables.push(variable("DT".into(), machine.delay_timer().to_string()));
+                variables.push(variable("ST".into(), machine.sound_timer().to_string()));
+            }
+            Some(STACK_REF) => {
+                for (i, site) in machine.stack().iter().enumerate() {
+                    let value = format!("{:#05x} {}", site, self.debugger.symbolize(*site));
+                    variables.push(variable(format!("[{}]", i), value));
+                }
+            }
+            _ => {}
+        }
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or("");
+        let base = parse_address(reference).ok_or("invalid memory reference")?;
+        let memory = self.machine.memory();
+        let size = memory.len() as i64;
+        let offset = args["offset"].as_i64().unwrap_or(0);
+        let start = (base as i64).saturating_add(offset).clamp(0, size) as usize;
+        let count = args["count"].as_u64().unwrap_or(0).min(size as u64) as usize;
+        let end = (start + count).min(memory.len());
+        Ok(json!({
+            "address": format!("{:#05x}", start),
+            "data": base64(&memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn execute(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        Ok(match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsSteppingGranularity": true,
+            }),
+            "launch" => self.launch(args)?,
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => json!({ "breakpoints": [] }),
+            "configurationDone" => {
+                if !self.stop_on_entry {
+                    self.running = true;
+                }
+                Value::Null
+            }
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] }),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            ]}),
+            "variables" => self.variables(args),
+            "readMemory" => self.read_memory(args)?,
+            "continue" => {
+                self.running = true;
+                json!({ "allThreadsContinued": true })
+            }
+            "pause" | "next" | "stepIn" | "stepOut" => {
+                self.running = false;
+                Value::Null
+            }
+            "disconnect" | "terminate" => {
+                self.running = false;
+                self.terminated = true;
+                Value::Null
+            }
+            _ => return Err(format!("unsupported request '{}'", command)),
+        })
+    }
+
+    /// Handles one request, sending its response and any resulting events.
+    pub fn handle(&mut self, request: &Value) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let result = self.execute(command, args);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+
+        match command {
+            "initialize" => self.event("initialized", json!({})),
+            "configurationDone" if self.stop_on_entry => self.stopped("entry"),
+            "pause" => self.stopped("pause"),
+            "next" if args["granularity"] == "instruction" => {
+                self.debugger.step(&mut self.machine);
+                self.stopped("step")
+            }
+            "next" => {
+                let stop = self.debugger.step_over(&mut self.machine, STEP_LIMIT);
+                self.report(stop)
+            }
+            "stepIn" => {
+                self.debugger.step(&mut self.machine);
+                self.stopped("step")
+            }
+            "stepOut" => {
+                let stop = self.debugger.step_out(&mut self.machine, STEP_LIMIT);
+                self.report(stop)
+            }
+            "disconnect" | "terminate" => self.event("terminated", json!({})),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Serves one debugging session, reading requests from `input` on a
+/// separate thread so that a running program can still be paused.
+pub fn serve<R, W>(input: R, output: W, config: Config) -> io::Result<()>
+where
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut adapter = Adapter::new(output, config);
+    while !adapter.is_terminated() {
+        let message = if adapter.is_running() {
+            adapter.tick()?;
+            match receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        if let Some(message) = message {
+            adapter.handle(&message)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chip::Quirks;
+    use crate::config::RomConfig;
+    use std::collections::BTreeSet;
+    use std::io::Cursor;
+
+    fn script(requests: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output, Config::default()).unwrap();
+        let mut output = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|m| m["type"] == "response" && m["command"] == command)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+    }
+
+    #[test]
+    fn test_read_message() {
+        let mut input = Cursor::new("Content-Length: 2\r\n\r\n{}");
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        let mut input = Cursor::new("Content-Length: 99999999999999\r\n\r\n{}");
+        let err = read_message(&mut input).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_session() {
+        let source = "assets/CHIP8/GAMES/SOURCES/PONG.SRC";
+        let messages = script(&[
+            json!({ "command": "initialize", "arguments": { "adapterID": "chip8" } }),
+            json!({ "command": "launch", "arguments": { "program": source } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": source },
+                "breakpoints": [{ "line": 215 }, { "line": 1 }],
+            }}),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "readMemory", "arguments": { "memoryReference": "0x200", "count": 3 } }),
+            json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], false);
+        let stopped: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .collect();
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        assert_eq!(stopped[1]["body"]["reason"], "step");
+
+        let traces: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["command"] == "stackTrace")
+            .map(|m| &m["body"]["stackFrames"])
+            .collect();
+        assert_eq!(traces[0][0]["line"], 215);
+        assert_eq!(traces[0][0]["name"], "DRAW_SCORE");
+        assert_eq!(traces[0][1]["line"], 68);
+        assert_eq!(traces[0][1]["source"]["name"], "PONG.SRC");
+        assert_eq!(traces[1][0]["line"], 70);
+        assert_eq!(traces[1].as_array().unwrap().len(), 1);
+
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[16]["name"], "I");
+        assert_eq!(variables[19]["value"], "0");
+        let memory = &response(&messages, "readMemory")["body"];
+        assert_eq!(memory["data"], base64(&[0x6A, 0x02, 0x6B]));
+        assert_eq!(messages.last().unwrap()["event"], "terminated");
+    }
+
+    #[test]
+    fn test_launch_settings() {
+        let pong = json!({ "program": "assets/CHIP8/GAMES/PONG" });
+        let mut adapter = Adapter::new(Vec::new(), Config::default());
+        adapter.execute("launch", &pong).unwrap();
+        assert_eq!(adapter.machine.quirks(), Quirks::SCHIP);
+
+        let mut config = Config::default();
+        let rom = RomConfig {
+            quirks: Some("chip8".to_string()),
+            ..RomConfig::default()
+        };
+        let sha1 = library::sha1(&std::fs::read("assets/CHIP8/GAMES/PONG").unwrap());
+        config.roms.insert(sha1, rom);
+        let mut adapter = Adapter::new(Vec::new(), config);
+        adapter.execute("launch", &pong).unwrap();
+        assert_eq!(adapter.machine.quirks(), Quirks::CHIP8);
+    }
+
+    #[test]
+    fn test_breakpoint_requests() {
+        let source = "assets/CHIP8/GAMES/SOURCES/PONG.SRC";
+        let mut adapter = Adapter::new(Vec::new(), Config::default());
+        adapter
+            .execute("launch", &json!({ "program": source }))
+            .unwrap();
+        let instructions = json!({ "breakpoints": [{ "instructionReference": "0x202" }] });
+        adapter
+            .execute("setInstructionBreakpoints", &instructions)
+            .unwrap();
+        let lines = json!({ "source": { "path": source }, "breakpoints": [{ "line": 215 }] });
+        adapter.execute("setBreakpoints", &lines).unwrap();
+        let other = json!({ "source": { "path": "OTHER.SRC" }, "breakpoints": [{ "line": 1 }] });
+        let response = adapter.execute("setBreakpoints", &other).unwrap();
+        assert_eq!(response["breakpoints"][0]["verified"], false);
+        let line = adapter.debugger.resolve("215").unwrap();
+        let expected: BTreeSet<u16> = [0x202, line].iter().copied().collect();
+        assert_eq!(adapter.debugger.breakpoints(), &expected);
+
+        adapter
+            .execute("setBreakpoints", &json!({ "breakpoints": [] }))
+            .unwrap();
+        assert!(adapter.debugger.breakpoints().contains(&0x202));
+        assert_eq!(adapter.debugger.breakpoints().len(), 1);
+
+        let huge = json!({ "memoryReference": "0x200", "offset": i64::MAX, "count": u64::MAX });
+        let memory = adapter.execute("readMemory", &huge).unwrap();
+        assert_eq!(memory["address"], "0x1000");
+        assert_eq!(memory["unreadableBytes"], 4096);
+        let negative = json!({ "memoryReference": "0x200", "offset": i64::MIN, "count": 2 });
+        let memory = adapter.execute("readMemory", &negative).unwrap();
+        assert_eq!(memory["address"], "0x000");
+    }
+}
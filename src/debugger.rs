@@ -0,0 +1,281 @@
+//! Source level debugging on top of `Machine::cycle`.
+//!
+//! A `Debugger` owns the breakpoints and, when the ROM was built by one of
+//! our assemblers, the `DebugMap` and source text used to show where the
+//! program counter is in the original source.
+
+use crate::asm::DebugMap;
+use crate::chip::Machine;
+use crate::disasm::{decode, Instruction};
+use std::collections::BTreeSet;
+use std::fs;
+
+/// Why `Debugger::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// A step finished.
+    Stepped,
+    Halted,
+    /// The cycle budget ran out first.
+    Limit,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    map: Option<DebugMap>,
+    source: Vec<String>,
+    breakpoints: BTreeSet<u16>,
+    pub paused: bool,
+}
+
+impl Debugger {
+    /// Creates a debugger, reading the source file named by `map`.
+    pub fn new(map: Option<DebugMap>) -> Debugger {
+        let source = map
+            .as_ref()
+            .and_then(|map| fs::read_to_string(&map.source).ok())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        Debugger::with_source(map, source)
+    }
+
+    pub fn with_source(map: Option<DebugMap>, source: Vec<String>) -> Debugger {
+        Debugger {
+            map,
+            source,
+            breakpoints: BTreeSet::new(),
+            paused: false,
+        }
+    }
+
+    pub fn map(&self) -> Option<&DebugMap> {
+        self.map.as_ref()
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    /// Resolves a breakpoint location: `0x2f0` or `#2F0` is an address, a
+    /// plain number is a source line and anything else is a label.
+    pub fn resolve(&self, spec: &str) -> Result<u16, String> {
+        let spec = spec.trim();
+        let hex = spec.strip_prefix("0x").or_else(|| spec.strip_prefix('#'));
+        if let Some(hex) = hex {
+            return u16::from_str_radix(hex, 16).map_err(|_| format!("invalid address '{}'", spec));
+        }
+        let map = match &self.map {
+            Some(map) => map,
+            None => return Err(format!("no debug map to resolve '{}'", spec)),
+        };
+        if let Ok(line) = spec.parse::<usize>() {
+            return map
+                .address_of_line(line)
+                .ok_or_else(|| format!("no code on line {}", line));
+        }
+        map.address_of_label(spec)
+            .ok_or_else(|| format!("unknown label '{}'", spec))
+    }
+
+    pub fn add_breakpoint(&mut self, spec: &str) -> Result<u16, String> {
+        let addr = self.resolve(spec)?;
+        self.breakpoints.insert(addr);
+        Ok(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self, machine: &mut Machine) -> bool {
+        machine.cycle()
+    }
+
+    /// Runs at most `limit` cycles, stopping before any instruction that has
+    /// a breakpoint. The instruction at the current PC is always executed,
+    /// so resuming from a breakpoint makes progress.
+    pub fn run(&mut self, machine: &mut Machine, limit: usize) -> Stop {
+        self.run_until(machine, limit, |_| false)
+    }
+
+    /// Executes the current instruction, running a whole subroutine if it
+    /// is a CALL.
+    pub fn step_over(&mut self, machine: &mut Machine, limit: usize) -> Stop {
+        let pc = machine.pc() as usize;
+        let memory = machine.memory();
+        let opcode = match (memory.get(pc), memory.get(pc + 1)) {
+            (Some(high), Some(low)) => (*high as u16) << 8 | *low as u16,
+            _ => 0,
+        };
+        if let Instruction::Call(_) = decode(opcode) {
+            let depth = machine.stack().len();
+            let next = machine.pc() + 2;
+            self.run_until(machine, limit, |m| {
+                m.stack().len() <= depth && m.pc() == next
+            })
+        } else {
+            self.run_until(machine, 1, |_| true)
+        }
+    }
+
+    /// Runs until the current subroutine returns.
+    pub fn step_out(&mut self, machine: &mut Machine, limit: usize) -> Stop {
+        let depth = machine.stack().len();
+        self.run_until(machine, limit, |m| m.stack().len() < depth)
+    }
+
+    fn run_until(
+        &mut self,
+        machine: &mut Machine,
+        limit: usize,
+        done: impl Fn(&Machine) -> bool,
+    ) -> Stop {
+        for _ in 0..limit {
+            if machine.cycle() {
+                self.paused = true;
+                return Stop::Halted;
+            }
+            if done(machine) {
+                self.paused = true;
+                return Stop::Stepped;
+            }
+            if self.breakpoints.contains(&machine.pc()) {
+                self.paused = true;
+                return Stop::Breakpoint(machine.pc());
+            }
+        }
+        Stop::Limit
+    }
+
+    /// Source line number and text of the statement at `addr`.
+    pub fn source_line(&self, addr: u16) -> Option<(usize, &str)> {
+        let line = self.map.as_ref()?.line_at(addr)?;
+        let text = self.source.get(line.checked_sub(1)?)?;
+        Some((line, text.as_str()))
+    }
+
+    /// Source lines around `addr`, as (line number, text, is current).
+    pub fn source_context(&self, addr: u16, radius: usize) -> Vec<(usize, &str, bool)> {
+        let current = match self.source_line(addr) {
+            Some((line, _)) => line,
+            None => return Vec::new(),
+        };
+        let first = current.saturating_sub(radius).max(1);
+        let last = (current + radius).min(self.source.len());
+        (first..=last)
+            .map(|line| (line, self.source[line - 1].as_str(), line == current))
+            .collect()
+    }
+
+    /// Names `addr` after the closest label at or before it.
+    pub fn symbolize(&self, addr: u16) -> String {
+        let closest = self.map.as_ref().and_then(|map| {
+            map.labels
+                .iter()
+                .filter(|(_, a)| **a <= addr)
+                .max_by_key(|(_, a)| **a)
+        });
+        match closest {
+            Some((name, a)) if *a == addr => name.clone(),
+            Some((name, a)) => format!("{}+{}", name, addr - a),
+            None => format!("{:#05x}", addr),
+        }
+    }
+
+    /// Describes each pending call, innermost first.
+    pub fn call_stack(&self, machine: &Machine) -> Vec<String> {
+        let memory = machine.memory();
+        machine
+            .stack()
+            .iter()
+            .rev()
+            .map(|site| {
+                let site = *site as usize & 0xFFF;
+                let opcode = (memory[site] as u16) << 8 | memory[(site + 1) & 0xFFF] as u16;
+                let caller = self.symbolize(site as u16);
+                match decode(opcode) {
+                    Instruction::Call(target) => {
+                        format!("{} called from {}", self.symbolize(target), caller)
+                    }
+                    _ => format!("return to {}", caller),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::chipper;
+    use std::path::Path;
+
+    const SOURCE: &str = "\
+START:  LD   V0, 0
+LOOP:   CALL BUMP
+        JP   LOOP
+
+BUMP:   ADD  V0, 1
+        RET";
+
+    fn setup() -> (Debugger, Machine) {
+        let program = chipper::assemble(SOURCE).unwrap();
+        let map = DebugMap::new(Path::new("TEST.SRC"), &program);
+        let source = SOURCE.lines().map(str::to_string).collect();
+        let debugger = Debugger::with_source(Some(map), source);
+        (debugger, Machine::new(&program.rom))
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (debugger, _) = setup();
+        assert_eq!(debugger.resolve("bump"), Ok(0x206));
+        assert_eq!(debugger.resolve("5"), Ok(0x206));
+        assert_eq!(debugger.resolve("0x204"), Ok(0x204));
+        assert!(debugger.resolve("4").is_err());
+        assert!(debugger.resolve("NOWHERE").is_err());
+    }
+
+    #[test]
+    fn test_run_to_breakpoint() {
+        let (mut debugger, mut machine) = setup();
+        debugger.add_breakpoint("BUMP").unwrap();
+        assert_eq!(debugger.run(&mut machine, 100), Stop::Breakpoint(0x206));
+        assert_eq!(
+            debugger.source_line(machine.pc()),
+            Some((5, "BUMP:   ADD  V0, 1"))
+        );
+        assert_eq!(debugger.call_stack(&machine), vec!["BUMP called from LOOP"]);
+        assert_eq!(debugger.run(&mut machine, 100), Stop::Breakpoint(0x206));
+        assert_eq!(machine.registers()[0], 1);
+        assert_eq!(debugger.symbolize(0x208), "BUMP+2");
+        let context = debugger.source_context(0x208, 1);
+        assert_eq!(context[1], (6, "        RET", true));
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let (mut debugger, mut machine) = setup();
+        debugger.step(&mut machine);
+        assert_eq!(debugger.step_over(&mut machine, 100), Stop::Stepped);
+        assert_eq!((machine.pc(), machine.registers()[0]), (0x204, 1));
+        debugger.step(&mut machine);
+        debugger.step(&mut machine);
+        assert_eq!(machine.pc(), 0x206);
+        assert_eq!(debugger.step_out(&mut machine, 100), Stop::Stepped);
+        assert_eq!((machine.pc(), machine.registers()[0]), (0x204, 2));
+    }
+
+    #[test]
+    fn test_step_over_jumps() {
+        let mut debugger = Debugger::new(None);
+        // JP 0x204; CLS; SE V0, 0; CLS
+        let mut machine = Machine::new(&[0x12, 0x04, 0x00, 0xE0, 0x30, 0x00, 0x00, 0xE0]);
+        assert_eq!(debugger.step_over(&mut machine, 1_000_000), Stop::Stepped);
+        assert_eq!(machine.pc(), 0x204);
+        assert_eq!(debugger.step_over(&mut machine, 1_000_000), Stop::Stepped);
+        assert_eq!(machine.pc(), 0x208);
+    }
+}
@@ -0,0 +1,784 @@
+//! Decoding of opcodes and disassembly listings.
+
+use crate::asm::{MAX_SIZE, PROGRAM_START};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// A decoded instruction. Register operands are indices into V0-VF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Sys(u16),
+    Clear,
+    Return,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Lores,
+    Hires,
+    Jump(u16),
+    Call(u16),
+    SkipEqImm(u8, u8),
+    SkipNeImm(u8, u8),
+    SkipEqReg(u8, u8),
+    SaveRange(u8, u8),
+    LoadRange(u8, u8),
+    LoadImm(u8, u8),
+    AddImm(u8, u8),
+    Move(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    Add(u8, u8),
+    Sub(u8, u8),
+    ShiftRight(u8, u8),
+    SubN(u8, u8),
+    ShiftLeft(u8, u8),
+    SkipNeReg(u8, u8),
+    LoadI(u16),
+    JumpV0(u16),
+    Random(u8, u8),
+    Draw(u8, u8, u8),
+    SkipKey(u8),
+    SkipNotKey(u8),
+    /// XO-CHIP `F000 NNNN`; the address is the word following the opcode.
+    LoadILong,
+    Plane(u8),
+    Audio,
+    GetDelay(u8),
+    WaitKey(u8),
+    SetDelay(u8),
+    SetSound(u8),
+    AddI(u8),
+    Font(u8),
+    BigFont(u8),
+    Bcd(u8),
+    Pitch(u8),
+    Save(u8),
+    Load(u8),
+    SaveFlags(u8),
+    LoadFlags(u8),
+    Unknown(u16),
+}
+
+pub fn decode(opcode: u16) -> Instruction {
+    use Instruction::*;
+    let x = ((opcode >> 8) & 0xF) as u8;
+    let y = ((opcode >> 4) & 0xF) as u8;
+    let n = (opcode & 0xF) as u8;
+    let nn = (opcode & 0xFF) as u8;
+    let nnn = opcode & 0xFFF;
+    match opcode >> 12 {
+        0x0 => match nnn {
+            0x0E0 => Clear,
+            0x0EE => Return,
+            0x0FB => ScrollRight,
+            0x0FC => ScrollLeft,
+            0x0FD => Exit,
+            0x0FE => Lores,
+            0x0FF => Hires,
+            _ if nnn & 0xFF0 == 0x0C0 => ScrollDown(n),
+            _ if nnn & 0xFF0 == 0x0D0 => ScrollUp(n),
+            _ => Sys(nnn),
+        },
+        0x1 => Jump(nnn),
+        0x2 => Call(nnn),
+        0x3 => SkipEqImm(x, nn),
+        0x4 => SkipNeImm(x, nn),
+        0x5 => match n {
+            0x0 => SkipEqReg(x, y),
+            0x2 => SaveRange(x, y),
+            0x3 => LoadRange(x, y),
+            _ => Unknown(opcode),
+        },
+        0x6 => LoadImm(x, nn),
+        0x7 => AddImm(x, nn),
+        0x8 => match n {
+            0x0 => Move(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => Add(x, y),
+            0x5 => Sub(x, y),
+            0x6 => ShiftRight(x, y),
+            0x7 => SubN(x, y),
+            0xE => ShiftLeft(x, y),
+            _ => Unknown(opcode),
+        },
+        0x9 if n == 0 => SkipNeReg(x, y),
+        0xA => LoadI(nnn),
+        0xB => JumpV0(nnn),
+        0xC => Random(x, nn),
+        0xD => Draw(x, y, n),
+        0xE if nn == 0x9E => SkipKey(x),
+        0xE if nn == 0xA1 => SkipNotKey(x),
+        0xF => match nn {
+            0x00 if x == 0 => LoadILong,
+            0x01 => Plane(x),
+            0x02 if x == 0 => Audio,
+            0x07 => GetDelay(x),
+            0x0A => WaitKey(x),
+            0x15 => SetDelay(x),
+            0x18 => SetSound(x),
+            0x1E => AddI(x),
+            0x29 => Font(x),
+            0x30 => BigFont(x),
+            0x33 => Bcd(x),
+            0x3A => Pitch(x),
+            0x55 => Save(x),
+            0x65 => Load(x),
+            0x75 => SaveFlags(x),
+            0x85 => LoadFlags(x),
+            _ => Unknown(opcode),
+        },
+        _ => Unknown(opcode),
+    }
+}
+
+impl Instruction {
+    /// Size in bytes, including the operand word of `LoadILong`.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong => 4,
+            _ => 2,
+        }
+    }
+
+    pub fn is_skip(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            SkipEqImm(..)
+                | SkipNeImm(..)
+                | SkipEqReg(..)
+                | SkipNeReg(..)
+                | SkipKey(_)
+                | SkipNotKey(_)
+        )
+    }
+
+    /// Address operand referring to code or data in memory, if any.
+    pub fn target(&self) -> Option<u16> {
+        use Instruction::*;
+        match *self {
+            Jump(addr) | Call(addr) | LoadI(addr) | JumpV0(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+fn v(reg: u8) -> String {
+    format!("v{:x}", reg)
+}
+
+/// Formats an instruction as an Octo statement. `name` is asked for a label
+/// whenever the instruction refers to an address.
+fn octo(instruction: Instruction, name: &dyn Fn(u16) -> String) -> String {
+    use Instruction::*;
+    match instruction {
+        Sys(addr) => format!("native {}", name(addr)),
+        Clear => "clear".to_string(),
+        Return => "return".to_string(),
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        Lores => "lores".to_string(),
+        Hires => "hires".to_string(),
+        Jump(addr) => format!("jump {}", name(addr)),
+        Call(addr) => format!(":call {}", name(addr)),
+        SkipEqImm(x, nn) => format!("if {} != {:#04x} then", v(x), nn),
+        SkipNeImm(x, nn) => format!("if {} == {:#04x} then", v(x), nn),
+        SkipEqReg(x, y) => format!("if {} != {} then", v(x), v(y)),
+        SaveRange(x, y) => format!("save {} - {}", v(x), v(y)),
+        LoadRange(x, y) => format!("load {} - {}", v(x), v(y)),
+        LoadImm(x, nn) => format!("{} := {:#04x}", v(x), nn),
+        AddImm(x, nn) => format!("{} += {:#04x}", v(x), nn),
+        Move(x, y) => format!("{} := {}", v(x), v(y)),
+        Or(x, y) => format!("{} |= {}", v(x), v(y)),
+        And(x, y) => format!("{} &= {}", v(x), v(y)),
+        Xor(x, y) => format!("{} ^= {}", v(x), v(y)),
+        Add(x, y) => format!("{} += {}", v(x), v(y)),
+        Sub(x, y) => format!("{} -= {}", v(x), v(y)),
+        ShiftRight(x, y) => format!("{} >>= {}", v(x), v(y)),
+        SubN(x, y) => format!("{} =- {}", v(x), v(y)),
+        ShiftLeft(x, y) => format!("{} <<= {}", v(x), v(y)),
+        SkipNeReg(x, y) => format!("if {} == {} then", v(x), v(y)),
+        LoadI(addr) => format!("i := {}", name(addr)),
+        JumpV0(addr) => format!("jump0 {}", name(addr)),
+        Random(x, nn) => format!("{} := random {:#04x}", v(x), nn),
+        Draw(x, y, n) => format!("sprite {} {} {}", v(x), v(y), n),
+        SkipKey(x) => format!("if {} -key then", v(x)),
+        SkipNotKey(x) => format!("if {} key then", v(x)),
+        LoadILong => "i := long".to_string(),
+        Plane(n) => format!("plane {}", n),
+        Audio => "audio".to_string(),
+        GetDelay(x) => format!("{} := delay", v(x)),
+        WaitKey(x) => format!("{} := key", v(x)),
+        SetDelay(x) => format!("delay := {}", v(x)),
+        SetSound(x) => format!("buzzer := {}", v(x)),
+        AddI(x) => format!("i += {}", v(x)),
+        Font(x) => format!("i := hex {}", v(x)),
+        BigFont(x) => format!("i := bighex {}", v(x)),
+        Bcd(x) => format!("bcd {}", v(x)),
+        Pitch(x) => format!("pitch := {}", v(x)),
+        Save(x) => format!("save {}", v(x)),
+        Load(x) => format!("load {}", v(x)),
+        SaveFlags(x) => format!("saveflags {}", v(x)),
+        LoadFlags(x) => format!("loadflags {}", v(x)),
+        Unknown(opcode) => format!("{:#04x} {:#04x}", opcode >> 8, opcode & 0xFF),
+    }
+}
+
+/// Formats an instruction in the pseudo-C notation of the debugger panel.
+fn pseudo_c(instruction: Instruction, name: &dyn Fn(u16) -> String) -> String {
+    use Instruction::*;
+    let r = |reg: u8| format!("register[{}]", reg);
+    match instruction {
+        Sys(_) => "call".to_string(),
+        Clear => "disp_clear()".to_string(),
+        Return => "return".to_string(),
+        ScrollDown(n) => format!("scroll_down({})", n),
+        ScrollUp(n) => format!("scroll_up({})", n),
+        ScrollRight => "scroll_right()".to_string(),
+        ScrollLeft => "scroll_left()".to_string(),
+        Exit => "exit()".to_string(),
+        Lores => "lores()".to_string(),
+        Hires => "hires()".to_string(),
+        Jump(addr) => format!("goto {}", name(addr)),
+        Call(addr) => format!("call {}", name(addr)),
+        SkipEqImm(x, nn) => format!("if {} == {}", r(x), nn),
+        SkipNeImm(x, nn) => format!("if {} != {}", r(x), nn),
+        SkipEqReg(x, y) => format!("if {} == {}", r(x), r(y)),
+        SaveRange(x, y) => format!("reg_dump({}..{}, &I)", r(x), r(y)),
+        LoadRange(x, y) => format!("reg_load({}..{}, &I)", r(x), r(y)),
+        LoadImm(x, nn) => format!("{} = {}", r(x), nn),
+        AddImm(x, nn) => format!("{} += {}", r(x), nn),
+        Move(x, y) => format!("{} = {}", r(x), r(y)),
+        Or(x, y) => format!("{} = {} or {}", r(x), r(x), r(y)),
+        And(x, y) => format!("{} = {} and {}", r(x), r(x), r(y)),
+        Xor(x, y) => format!("{} = {} xor {}", r(x), r(x), r(y)),
+        Add(x, y) => format!("{} += {}", r(x), r(y)),
+        Sub(x, y) => format!("{} -= {}", r(x), r(y)),
+        ShiftRight(x, _) => format!("{} >>= 1", r(x)),
+        SubN(x, y) => format!("{} = {} - {}", r(x), r(y), r(x)),
+        ShiftLeft(x, _) => format!("{} <<= 1", r(x)),
+        SkipNeReg(x, y) => format!("if {} != {}", r(x), r(y)),
+        LoadI(addr) => format!("I = {}", name(addr)),
+        JumpV0(addr) => format!("goto {} + {}", name(addr), r(0)),
+        Random(x, nn) => format!("{} = rand() & {}", r(x), nn),
+        Draw(x, y, n) => format!("draw({}, {}, {})", r(x), r(y), n),
+        SkipKey(x) => format!("if (key() == {})", r(x)),
+        SkipNotKey(x) => format!("if (key() != {})", r(x)),
+        LoadILong => "I = long".to_string(),
+        Plane(n) => format!("plane({})", n),
+        Audio => "audio()".to_string(),
+        GetDelay(x) => format!("{} = get_delay", r(x)),
+        WaitKey(x) => format!("{} = get_key", r(x)),
+        SetDelay(x) => format!("delay_timer({})", r(x)),
+        SetSound(x) => format!("sound_timer({})", r(x)),
+        AddI(x) => format!("I += {}", r(x)),
+        Font(x) => format!("I = sprite_addr[{}]", r(x)),
+        BigFont(x) => format!("I = big_sprite_addr[{}]", r(x)),
+        Bcd(x) => format!("set_BCD({})", r(x)),
+        Pitch(x) => format!("pitch({})", r(x)),
+        Save(x) => format!("reg_dump({}, &I)", r(x)),
+        Load(x) => format!("reg_load({}, &I)", r(x)),
+        SaveFlags(x) => format!("flags_dump({})", r(x)),
+        LoadFlags(x) => format!("flags_load({})", r(x)),
+        Unknown(_) => "????".to_string(),
+    }
+}
+
+/// Formats an instruction with the mnemonics of Cowgod's Chip-8 technical
+/// reference. `chipper` restricts the output to what Chipper V2.11 accepts and
+/// uses its `#` prefix for hexadecimal numbers.
+fn mnemonic(instruction: Instruction, name: &dyn Fn(u16) -> String, chipper: bool) -> String {
+    use Instruction::*;
+    let byte = |nn: u8| {
+        if chipper {
+            format!("#{:02X}", nn)
+        } else {
+            format!("{:#04X}", nn)
+        }
+    };
+    let word = |opcode: u16| {
+        if chipper {
+            format!("DW    #{:04X}", opcode)
+        } else {
+            format!("DW {:#06X}", opcode)
+        }
+    };
+    let r = |reg: u8| format!("V{:X}", reg);
+    let op = |m: &str, args: String| {
+        if chipper {
+            format!("{:<5} {}", m, args).trim_end().to_string()
+        } else {
+            format!("{} {}", m, args).trim_end().to_string()
+        }
+    };
+    match instruction {
+        Sys(addr) => op("SYS", name(addr)),
+        Clear => op("CLS", String::new()),
+        Return => op("RET", String::new()),
+        ScrollDown(n) => op("SCD", n.to_string()),
+        ScrollRight => op("SCR", String::new()),
+        ScrollLeft => op("SCL", String::new()),
+        Exit => op("EXIT", String::new()),
+        Lores => op("LOW", String::new()),
+        Hires => op("HIGH", String::new()),
+        Jump(addr) => op("JP", name(addr)),
+        Call(addr) => op("CALL", name(addr)),
+        SkipEqImm(x, nn) => op("SE", format!("{}, {}", r(x), byte(nn))),
+        SkipNeImm(x, nn) => op("SNE", format!("{}, {}", r(x), byte(nn))),
+        SkipEqReg(x, y) => op("SE", format!("{}, {}", r(x), r(y))),
+        LoadImm(x, nn) => op("LD", format!("{}, {}", r(x), byte(nn))),
+        AddImm(x, nn) => op("ADD", format!("{}, {}", r(x), byte(nn))),
+        Move(x, y) => op("LD", format!("{}, {}", r(x), r(y))),
+        Or(x, y) => op("OR", format!("{}, {}", r(x), r(y))),
+        And(x, y) => op("AND", format!("{}, {}", r(x), r(y))),
+        Xor(x, y) => op("XOR", format!("{}, {}", r(x), r(y))),
+        Add(x, y) => op("ADD", format!("{}, {}", r(x), r(y))),
+        Sub(x, y) => op("SUB", format!("{}, {}", r(x), r(y))),
+        ShiftRight(x, y) => op("SHR", format!("{}, {}", r(x), r(y))),
+        SubN(x, y) => op("SUBN", format!("{}, {}", r(x), r(y))),
+        ShiftLeft(x, y) => op("SHL", format!("{}, {}", r(x), r(y))),
+        SkipNeReg(x, y) => op("SNE", format!("{}, {}", r(x), r(y))),
+        LoadI(addr) => op("LD", format!("I, {}", name(addr))),
+        JumpV0(addr) => op("JP", format!("V0, {}", name(addr))),
+        Random(x, nn) => op("RND", format!("{}, {}", r(x), byte(nn))),
+        Draw(x, y, n) => op("DRW", format!("{}, {}, {}", r(x), r(y), n)),
+        SkipKey(x) => op("SKP", r(x)),
+        SkipNotKey(x) => op("SKNP", r(x)),
+        GetDelay(x) => op("LD", format!("{}, DT", r(x))),
+        WaitKey(x) => op("LD", format!("{}, K", r(x))),
+        SetDelay(x) => op("LD", format!("DT, {}", r(x))),
+        SetSound(x) => op("LD", format!("ST, {}", r(x))),
+        AddI(x) => op("ADD", format!("I, {}", r(x))),
+        Font(x) => op("LD", format!("F, {}", r(x))),
+        BigFont(x) => op("LD", format!("HF, {}", r(x))),
+        Bcd(x) => op("LD", format!("B, {}", r(x))),
+        Save(x) => op("LD", format!("[I], {}", r(x))),
+        Load(x) => op("LD", format!("{}, [I]", r(x))),
+        SaveFlags(x) => op("LD", format!("R, {}", r(x))),
+        LoadFlags(x) => op("LD", format!("{}, R", r(x))),
+        // XO-CHIP additions have no Chipper equivalent.
+        ScrollUp(n) if !chipper => op("SCU", n.to_string()),
+        SaveRange(x, y) if !chipper => op("LD", format!("[I], {}-{}", r(x), r(y))),
+        LoadRange(x, y) if !chipper => op("LD", format!("{}-{}, [I]", r(x), r(y))),
+        LoadILong if !chipper => op("LD", "I, LONG".to_string()),
+        Plane(n) if !chipper => op("PLANE", n.to_string()),
+        Audio if !chipper => op("AUDIO", String::new()),
+        Pitch(x) if !chipper => op("LD", format!("PITCH, {}", r(x))),
+        ScrollUp(n) => word(0x00D0 | n as u16),
+        SaveRange(x, y) => word(0x5002 | (x as u16) << 8 | (y as u16) << 4),
+        LoadRange(x, y) => word(0x5003 | (x as u16) << 8 | (y as u16) << 4),
+        LoadILong => word(0xF000),
+        Plane(n) => word(0xF001 | (n as u16) << 8),
+        Audio => word(0xF002),
+        Pitch(x) => word(0xF03A | (x as u16) << 8),
+        Unknown(opcode) => word(opcode),
+    }
+}
+
+/// Notation used to print instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// The pseudo-C notation of the debugger panel.
+    PseudoC,
+    /// Mnemonics of Cowgod's Chip-8 technical reference.
+    Cowgod,
+    /// Chipper V2.11, the assembler the bundled `.SRC` files are written in.
+    Chipper,
+    Octo,
+}
+
+impl Syntax {
+    pub const ALL: [Syntax; 4] = [
+        Syntax::PseudoC,
+        Syntax::Cowgod,
+        Syntax::Chipper,
+        Syntax::Octo,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Syntax::PseudoC => "pseudo-c",
+            Syntax::Cowgod => "cowgod",
+            Syntax::Chipper => "chipper",
+            Syntax::Octo => "octo",
+        }
+    }
+
+    /// How an address operand without a label is printed.
+    pub fn address(self, addr: u16) -> String {
+        match self {
+            Syntax::PseudoC => format!("{:#02x}", addr),
+            Syntax::Cowgod => format!("{:#05X}", addr),
+            Syntax::Chipper => format!("#{:03X}", addr),
+            Syntax::Octo => format!("{:#05x}", addr),
+        }
+    }
+
+    pub fn format(self, opcode: u16) -> String {
+        self.format_instruction(decode(opcode), &|addr| self.address(addr))
+    }
+
+    /// Formats an instruction, asking `name` for the text of address operands.
+    pub fn format_instruction(
+        self,
+        instruction: Instruction,
+        name: &dyn Fn(u16) -> String,
+    ) -> String {
+        match self {
+            Syntax::PseudoC => pseudo_c(instruction, name),
+            Syntax::Cowgod => mnemonic(instruction, name, false),
+            Syntax::Chipper => mnemonic(instruction, name, true),
+            Syntax::Octo => octo(instruction, name),
+        }
+    }
+}
+
+impl fmt::Display for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Syntax, String> {
+        Syntax::ALL
+            .iter()
+            .find(|syntax| syntax.name() == s.to_lowercase())
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<_> = Syntax::ALL.iter().map(|s| s.name()).collect();
+                format!(
+                    "unknown syntax '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The part of a program that fits in memory. Anything past it can never be
+/// loaded, and its addresses would not fit in 12 bits.
+fn loadable(program: &[u8]) -> &[u8] {
+    &program[..program.len().min(MAX_SIZE)]
+}
+
+/// Finds the bytes of a program reachable as code by following control flow
+/// from the entry point. Everything else is treated as data.
+fn find_code(program: &[u8]) -> BTreeSet<u16> {
+    let program = loadable(program);
+    let end = PROGRAM_START as usize + program.len();
+    let fetch = |addr: u16| -> u16 {
+        let offset = (addr - PROGRAM_START) as usize;
+        (program[offset] as u16) << 8 | program[offset + 1] as u16
+    };
+    let mut code = BTreeSet::new();
+    let mut pending = vec![PROGRAM_START];
+    while let Some(addr) = pending.pop() {
+        if addr < PROGRAM_START || addr as usize + 1 >= end || code.contains(&addr) {
+            continue;
+        }
+        let instruction = decode(fetch(addr));
+        if addr as usize + instruction.size() as usize > end {
+            continue;
+        }
+        code.insert(addr);
+        let next = addr + instruction.size();
+        match instruction {
+            Instruction::Jump(target) => pending.push(target),
+            Instruction::Call(target) => {
+                pending.push(target);
+                pending.push(next);
+            }
+            Instruction::Return | Instruction::Exit | Instruction::JumpV0(_) => {}
+            Instruction::Unknown(_) => {}
+            _ if instruction.is_skip() => {
+                pending.push(next);
+                pending.push(next + 2);
+            }
+            _ => pending.push(next),
+        }
+    }
+    code
+}
+
+/// The family of interpreters a program was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::SuperChip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+}
+
+impl Instruction {
+    /// The first platform that has this instruction.
+    pub fn platform(&self) -> Platform {
+        use Instruction::*;
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Lores | Hires | BigFont(_)
+            | SaveFlags(_) | LoadFlags(_) => Platform::SuperChip,
+            ScrollUp(_) | SaveRange(..) | LoadRange(..) | LoadILong | Plane(_) | Audio
+            | Pitch(_) => Platform::XoChip,
+            _ => Platform::Chip8,
+        }
+    }
+}
+
+/// Guesses the platform from the instructions reachable from the entry
+/// point, e.g. a SUPER-CHIP game switching to hires with 00FF.
+pub fn detect_platform(program: &[u8]) -> Platform {
+    let program = loadable(program);
+    let fetch = |addr: u16| {
+        let offset = (addr - PROGRAM_START) as usize;
+        (program[offset] as u16) << 8 | program[offset + 1] as u16
+    };
+    find_code(program)
+        .iter()
+        .map(|addr| decode(fetch(*addr)).platform())
+        .max_by_key(|platform| *platform as u8)
+        .unwrap_or(Platform::Chip8)
+}
+
+/// Number of instructions reachable from the entry point.
+pub fn code_size(program: &[u8]) -> usize {
+    find_code(program).len()
+}
+
+impl Syntax {
+    fn label(self, addr: u16) -> String {
+        match self {
+            Syntax::Octo => format!("label-{:03x}", addr),
+            Syntax::Chipper => format!("L{:03X}", addr),
+            Syntax::Cowgod => format!("label_{:03X}", addr),
+            Syntax::PseudoC => format!("label_{:03x}", addr),
+        }
+    }
+
+    fn label_definition(self, label: &str) -> String {
+        match self {
+            Syntax::Octo => format!(": {}", label),
+            _ => format!("{}:", label),
+        }
+    }
+
+    fn data(self, bytes: &[u8]) -> String {
+        let hex: Vec<_> = bytes
+            .iter()
+            .map(|b| match self {
+                Syntax::Chipper => format!("#{:02X}", b),
+                Syntax::Cowgod => format!("{:#04X}", b),
+                _ => format!("{:#04x}", b),
+            })
+            .collect();
+        match self {
+            Syntax::Octo => hex.join(" "),
+            Syntax::Chipper => format!("{:<5} {}", "DB", hex.join(", ")),
+            Syntax::Cowgod => format!("DB {}", hex.join(", ")),
+            Syntax::PseudoC => format!("data {}", hex.join(", ")),
+        }
+    }
+}
+
+/// Disassembles a ROM into a labelled listing. Code is found by following
+/// control flow from `0x200`; bytes that are never reached are emitted as
+/// data, so Octo and Chipper listings assemble back into the same bytes.
+/// Bytes that do not fit in memory are left out.
+pub fn listing(program: &[u8], syntax: Syntax) -> String {
+    let program = loadable(program);
+    let code = find_code(program);
+    let end = PROGRAM_START + program.len() as u16;
+    let fetch = |addr: u16| -> u16 {
+        let offset = (addr - PROGRAM_START) as usize;
+        (program[offset] as u16) << 8 | program[offset + 1] as u16
+    };
+
+    // Instruction starts actually emitted, in address order. Overlapping
+    // instructions can't be expressed, so the later one becomes data.
+    let mut starts = BTreeSet::new();
+    let mut addr = PROGRAM_START;
+    while addr < end {
+        if code.contains(&addr) {
+            starts.insert(addr);
+            addr += decode(fetch(addr)).size();
+        } else {
+            addr += 1;
+        }
+    }
+
+    let mut labels: HashMap<u16, String> = HashMap::new();
+    if syntax == Syntax::Octo {
+        labels.insert(PROGRAM_START, "main".to_string());
+    }
+    for start in starts.iter() {
+        let instruction = decode(fetch(*start));
+        let target = match instruction {
+            Instruction::LoadILong => Some(fetch(*start + 2)),
+            _ => instruction.target(),
+        };
+        if let Some(target) = target {
+            let in_program = target >= PROGRAM_START && target < end;
+            let aligned = starts.contains(&target) || !code.contains(&target);
+            let inside_instruction = starts
+                .range(..target)
+                .next_back()
+                .is_some_and(|s| *s + decode(fetch(*s)).size() > target);
+            if in_program && aligned && !inside_instruction {
+                labels.entry(target).or_insert_with(|| syntax.label(target));
+            }
+        }
+    }
+    let name = |addr: u16| -> String {
+        match labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => syntax.address(addr),
+        }
+    };
+
+    let mut out = String::new();
+    let mut data = Vec::new();
+    let flush = |out: &mut String, data: &mut Vec<u8>| {
+        if !data.is_empty() {
+            out.push_str(&format!("\t{}\n", syntax.data(data)));
+            data.clear();
+        }
+    };
+    let mut addr = PROGRAM_START;
+    while addr < end {
+        if let Some(label) = labels.get(&addr) {
+            flush(&mut out, &mut data);
+            out.push_str(&format!("{}\n", syntax.label_definition(label)));
+        }
+        if starts.contains(&addr) {
+            flush(&mut out, &mut data);
+            let instruction = decode(fetch(addr));
+            let text = match (instruction, syntax) {
+                (Instruction::LoadILong, Syntax::Octo) => {
+                    format!("i := long {}", name(fetch(addr + 2)))
+                }
+                (Instruction::LoadILong, _) => format!(
+                    "{}\n\t{}",
+                    syntax.format_instruction(instruction, &name),
+                    syntax.data(&program[(addr - PROGRAM_START + 2) as usize..][..2])
+                ),
+                _ => syntax.format_instruction(instruction, &name),
+            };
+            out.push_str(&format!("\t{}\n", text));
+            addr += instruction.size();
+        } else {
+            data.push(program[(addr - PROGRAM_START) as usize]);
+            if data.len() == 8 {
+                flush(&mut out, &mut data);
+            }
+            addr += 1;
+        }
+    }
+    flush(&mut out, &mut data);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::octo::assemble;
+    use std::fs;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x00E0), Instruction::Clear);
+        assert_eq!(decode(0x8AB4), Instruction::Add(0xA, 0xB));
+        assert_eq!(decode(0xD125), Instruction::Draw(1, 2, 5));
+        assert_eq!(decode(0xF000), Instruction::LoadILong);
+        assert_eq!(decode(0x8AB9), Instruction::Unknown(0x8AB9));
+    }
+
+    #[test]
+    fn test_octo_statement() {
+        assert_eq!(Syntax::Octo.format(0x3A05), "if va != 0x05 then");
+        assert_eq!(Syntax::Octo.format(0x8127), "v1 =- v2");
+        assert_eq!(Syntax::Octo.format(0x1234), "jump 0x234");
+    }
+
+    #[test]
+    fn test_octo_listing() {
+        let program = [0xA2, 0x06, 0xD0, 0x11, 0x12, 0x00, 0x80];
+        assert_eq!(
+            listing(&program, Syntax::Octo),
+            ": main\n\ti := label-206\n\tsprite v0 v1 1\n\tjump main\n: label-206\n\t0x80\n"
+        );
+        assert_eq!(
+            listing(&program, Syntax::Chipper),
+            "L200:\n\tLD    I, L206\n\tDRW   V0, V1, 1\n\tJP    L200\nL206:\n\tDB    #80\n"
+        );
+    }
+
+    #[test]
+    fn test_syntaxes() {
+        let formats: Vec<_> = Syntax::ALL.iter().map(|s| s.format(0x8123)).collect();
+        assert_eq!(
+            formats,
+            vec![
+                "register[1] = register[1] xor register[2]",
+                "XOR V1, V2",
+                "XOR   V1, V2",
+                "v1 ^= v2",
+            ]
+        );
+        assert_eq!(Syntax::Cowgod.format(0xA2F0), "LD I, 0x2F0");
+        assert_eq!(Syntax::Chipper.format(0xF000), "DW    #F000");
+        assert_eq!("OCTO".parse::<Syntax>(), Ok(Syntax::Octo));
+        assert!("intel".parse::<Syntax>().is_err());
+    }
+
+    #[test]
+    fn test_detect_platform() {
+        for (dir, platform) in [("GAMES", Platform::Chip8), ("SGAMES", Platform::SuperChip)].iter()
+        {
+            for name in ["BLINKY", "MAZE"].iter() {
+                let rom = fs::read(format!("assets/CHIP8/{}/{}", dir, name)).unwrap();
+                assert_eq!(detect_platform(&rom), *platform, "{}/{}", dir, name);
+            }
+        }
+        assert_eq!(detect_platform(&[0xF0, 0x00, 0x12, 0x34]), Platform::XoChip);
+    }
+
+    #[test]
+    fn test_octo_round_trip() {
+        for name in ["PONG", "BRIX", "INVADERS", "TETRIS", "BLINKY"].iter() {
+            let rom = fs::read(format!("assets/CHIP8/GAMES/{}", name)).unwrap();
+            let source = listing(&rom, Syntax::Octo);
+            let program = assemble(&source).unwrap();
+            assert_eq!(program.rom, rom, "{} does not round trip", name);
+        }
+    }
+
+    #[test]
+    fn test_oversized() {
+        // Larger than any u16 offset from 0x200, let alone memory.
+        let mut rom = vec![0; 70000];
+        rom[..4].copy_from_slice(&[0x60, 0x01, 0x12, 0x00]);
+        assert_eq!(code_size(&rom), 2);
+        assert_eq!(detect_platform(&rom), Platform::Chip8);
+        let source = listing(&rom, Syntax::Octo);
+        assert_eq!(assemble(&source).unwrap().rom, &rom[..MAX_SIZE]);
+    }
+}
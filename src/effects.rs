@@ -0,0 +1,215 @@
+//! Settings of the filters the screen is drawn through, and of where it is
+//! drawn.
+
+use serde::{Deserialize, Serialize};
+
+/// How lit pixels linger after they are erased, against the flicker of
+/// sprites that are erased and redrawn every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Persistence {
+    Off,
+    /// Pixels fade out like phosphor, by `decay` each frame.
+    Phosphor,
+    /// A pixel is lit if it was in this frame or the previous one.
+    MaxOfTwo,
+}
+
+impl Persistence {
+    pub const ALL: [Persistence; 3] = [
+        Persistence::Off,
+        Persistence::Phosphor,
+        Persistence::MaxOfTwo,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Persistence::Off => "Off",
+            Persistence::Phosphor => "Phosphor",
+            Persistence::MaxOfTwo => "Max of 2 frames",
+        }
+    }
+}
+
+/// How the 64x32 screen is enlarged to the window.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scaler {
+    /// Sharp square pixels.
+    Nearest,
+    /// Bilinear filtering.
+    Smooth,
+    /// Scale2x, also known as EPX: rounds off diagonal edges.
+    Scale2x,
+}
+
+impl Scaler {
+    pub const ALL: [Scaler; 3] = [Scaler::Nearest, Scaler::Smooth, Scaler::Scale2x];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scaler::Nearest => "Nearest",
+            Scaler::Smooth => "Smooth",
+            Scaler::Scale2x => "Scale2x (EPX)",
+        }
+    }
+}
+
+/// How the screen fills the area it is drawn in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Viewport {
+    /// All of the area, whatever its shape.
+    Stretch,
+    /// As much of the area as the 2:1 screen can fill, letterboxed.
+    Aspect,
+    /// The largest whole multiple of the screen that fits, letterboxed.
+    Integer,
+}
+
+impl Viewport {
+    pub const ALL: [Viewport; 3] = [Viewport::Stretch, Viewport::Aspect, Viewport::Integer];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Viewport::Stretch => "Stretch",
+            Viewport::Aspect => "Keep aspect",
+            Viewport::Integer => "Integer scale",
+        }
+    }
+}
+
+/// Pixels of the screen. The SUPER-CHIP and XO-CHIP 128x64 mode is not
+/// emulated, so every platform draws at 64x32.
+pub const RESOLUTION: [u32; 2] = [64, 32];
+
+/// Persistence, scaling and the CRT effects. The CRT effects have a
+/// strength from 0, for off, to 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Effects {
+    pub persistence: Persistence,
+    /// Brightness a pixel keeps each frame after it is erased, with
+    /// phosphor persistence.
+    pub decay: f32,
+    pub scaler: Scaler,
+    /// Darkens the gaps between pixel rows.
+    pub scanlines: f32,
+    /// Bends the screen like the glass of a tube.
+    pub curvature: f32,
+    /// Makes lit pixels glow onto their neighbours.
+    pub bloom: f32,
+    /// Outlines every pixel, like an LCD.
+    pub grid: f32,
+    pub viewport: Viewport,
+    /// Whether the screen is drawn in a window of its own rather than
+    /// behind the others.
+    pub docked: bool,
+}
+
+impl Default for Effects {
+    fn default() -> Effects {
+        Effects {
+            persistence: Persistence::Off,
+            decay: 0.75,
+            scaler: Scaler::Nearest,
+            scanlines: 0.0,
+            curvature: 0.0,
+            bloom: 0.0,
+            grid: 0.0,
+            viewport: Viewport::Aspect,
+            docked: false,
+        }
+    }
+}
+
+impl Effects {
+    /// Most brightness a pixel can keep: at 1 it would never fade.
+    pub const MAX_DECAY: f32 = 0.95;
+
+    /// `decay` within the range the filter supports.
+    pub fn decay(&self) -> f32 {
+        self.decay.clamp(0.0, Effects::MAX_DECAY)
+    }
+
+    /// Strengths of scanlines, curvature, bloom and grid, within 0 to 1.
+    pub fn crt(&self) -> [f32; 4] {
+        let strength = |value: f32| value.clamp(0.0, 1.0);
+        [
+            strength(self.scanlines),
+            strength(self.curvature),
+            strength(self.bloom),
+            strength(self.grid),
+        ]
+    }
+
+    /// Scanlines, a slight curve and some glow, like a television.
+    pub fn set_crt(&mut self) {
+        self.scanlines = 0.5;
+        self.curvature = 0.3;
+        self.bloom = 0.3;
+        self.grid = 0.0;
+    }
+
+    pub fn clear_crt(&mut self) {
+        self.scanlines = 0.0;
+        self.curvature = 0.0;
+        self.bloom = 0.0;
+        self.grid = 0.0;
+    }
+
+    /// Where a screen of `resolution` goes in an `area`, as left, bottom,
+    /// width and height.
+    pub fn viewport(&self, area: [u32; 2], resolution: [u32; 2]) -> [u32; 4] {
+        let [width, height] = area;
+        let fit = (width as f32 / resolution[0] as f32).min(height as f32 / resolution[1] as f32);
+        let scale = match self.viewport {
+            Viewport::Stretch => return [0, 0, width, height],
+            Viewport::Integer if fit >= 1.0 => fit.floor(),
+            Viewport::Aspect | Viewport::Integer => fit,
+        };
+        let size = [
+            (resolution[0] as f32 * scale) as u32,
+            (resolution[1] as f32 * scale) as u32,
+        ];
+        [
+            (width - size[0]) / 2,
+            (height - size[1]) / 2,
+            size[0],
+            size[1],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crt() {
+        let mut effects: Effects = toml::from_str("scaler = \"scale2x\"\nscanlines = 1.5").unwrap();
+        assert_eq!(effects.scaler, Scaler::Scale2x);
+        assert_eq!(effects.crt(), [1.0, 0.0, 0.0, 0.0]);
+        effects.set_crt();
+        assert!(effects.crt().iter().any(|strength| *strength > 0.0));
+        effects.clear_crt();
+        assert_eq!(effects.crt(), [0.0; 4]);
+        assert_eq!(effects.persistence, Persistence::Off);
+        assert!(toml::from_str::<Effects>("scaler = \"hq2x\"").is_err());
+    }
+
+    #[test]
+    fn test_viewport() {
+        let mut effects = Effects::default();
+        let chip8 = RESOLUTION;
+        assert_eq!(effects.viewport([1024, 768], chip8), [0, 128, 1024, 512]);
+        assert_eq!(effects.viewport([300, 400], chip8), [0, 125, 300, 150]);
+
+        effects.viewport = Viewport::Integer;
+        assert_eq!(effects.viewport([1000, 768], chip8), [20, 144, 960, 480]);
+        assert_eq!(effects.viewport([32, 32], chip8), [0, 8, 32, 16]);
+
+        effects.viewport = Viewport::Stretch;
+        assert_eq!(effects.viewport([1000, 768], chip8), [0, 0, 1000, 768]);
+    }
+}
@@ -0,0 +1,114 @@
+//! Input decoding shared by the fuzz targets in `fuzz/` and the randomized
+//! test below.
+//!
+//! An input is laid out as:
+//!
+//! ```text
+//! V0..VF (16) | I (2) | PC (2) | SP (1) | key interval (1) | key count (1) | keys | ROM
+//! ```
+//!
+//! Each key byte is applied after every `key interval` cycles; its low
+//! nibble selects the key and bit 4 presses or releases it. Missing fields
+//! read as zero.
+
+use crate::chip::{Fault, Machine};
+
+/// Upper bound on executed instructions, as most programs loop forever.
+pub const MAX_CYCLES: usize = 10_000;
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> &'a [u8] {
+        let (head, tail) = self.data.split_at(count.min(self.data.len()));
+        self.data = tail;
+        head
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.take(1).first().copied().unwrap_or(0)
+    }
+
+    fn word(&mut self) -> u16 {
+        (self.byte() as u16) << 8 | self.byte() as u16
+    }
+}
+
+/// Builds a machine from `data` and runs it until it stops or
+/// `MAX_CYCLES` have passed. Returns the fault that stopped it, if any.
+pub fn run(data: &[u8]) -> Option<Fault> {
+    let mut input = Reader { data };
+    let mut registers = [0; 16];
+    for (register, byte) in registers.iter_mut().zip(input.take(16)) {
+        *register = *byte;
+    }
+    let index = input.word();
+    let pc = input.word();
+    let sp = input.byte();
+    let interval = input.byte().max(1) as usize;
+    let count = input.byte() as usize;
+    let keys = input.take(count);
+
+    let mut machine = Machine::new(input.data);
+    *machine.registers_mut() = registers;
+    machine.set_index(index);
+    machine.set_pc(pc);
+    machine.set_sp(sp);
+
+    let mut keys = keys.iter();
+    for cycle in 0..MAX_CYCLES {
+        if cycle % interval == 0 {
+            if let Some(key) = keys.next() {
+                machine.set_key(key & 0xF, key & 0x10 != 0);
+            }
+        }
+        if machine.cycle() {
+            break;
+        }
+    }
+    machine.fault()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_random_inputs() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..500 {
+            let size = rng.gen_range(0, 4000);
+            let data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+            run(&data);
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        let header = |pc: u16| {
+            let mut data = vec![0; 16];
+            data.extend_from_slice(&[0x0F, 0xFF, (pc >> 8) as u8, pc as u8, 0, 1, 0]);
+            data
+        };
+        let with_rom = |rom: &[u8]| [header(0x200), rom.to_vec()].concat();
+        assert_eq!(run(&with_rom(&[0x22, 0x00])), Some(Fault::StackOverflow));
+        assert_eq!(run(&with_rom(&[0x00, 0xEE])), Some(Fault::StackUnderflow));
+        assert_eq!(
+            run(&with_rom(&[0xF1, 0x55])),
+            Some(Fault::MemoryOutOfBounds(0x1000))
+        );
+        assert_eq!(
+            run(&with_rom(&[0xD0, 0x02])),
+            Some(Fault::MemoryOutOfBounds(0x1000))
+        );
+        assert_eq!(run(&header(0xFFF)), Some(Fault::PcOutOfBounds(0xFFF)));
+        assert_eq!(
+            run(&with_rom(&vec![0; 4000])),
+            Some(Fault::ProgramTooLarge(4000))
+        );
+    }
+}
@@ -0,0 +1,442 @@
+//! GDB remote serial protocol stub.
+//!
+//! Registers are numbered V0–VF (0–15), I (16), PC (17), SP (18), DT (19)
+//! and ST (20). I and PC are 16 bits wide and sent little endian, the rest
+//! are single bytes; the layout is also described by the `target.xml`
+//! served through `qXfer:features:read`.
+
+use crate::chip::Machine;
+use crate::debugger::{Debugger, Stop};
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+const REGISTER_COUNT: usize = 21;
+/// Cycles executed between checks for an interrupt while continuing.
+const SLICE: usize = 1000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// What the client sent, as split up by the reader thread.
+#[derive(Debug, PartialEq)]
+enum Input {
+    Packet(String),
+    Interrupt,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Reads packets until the stream closes. Acknowledgements are dropped
+/// and checksums are not verified, as the transport is reliable.
+fn read_input(input: impl Read, sender: mpsc::Sender<Input>) {
+    let mut bytes = BufReader::new(input).bytes();
+    while let Some(Ok(byte)) = bytes.next() {
+        let message = match byte {
+            0x03 => Input::Interrupt,
+            b'$' => {
+                let mut data = Vec::new();
+                while let Some(Ok(byte)) = bytes.next() {
+                    if byte == b'#' {
+                        break;
+                    }
+                    data.push(byte);
+                }
+                bytes.next();
+                bytes.next();
+                Input::Packet(String::from_utf8_lossy(&data).into_owned())
+            }
+            _ => continue,
+        };
+        if sender.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+pub struct Stub<W: Write> {
+    output: W,
+    machine: Machine,
+    debugger: Debugger,
+    running: bool,
+}
+
+impl<W: Write> Stub<W> {
+    pub fn new(output: W, machine: Machine, debugger: Debugger) -> Stub<W> {
+        Stub {
+            output,
+            machine,
+            debugger,
+            running: false,
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.output, "${}#{:02x}", data, checksum(data))?;
+        self.output.flush()
+    }
+
+    fn stop_reply(stop: Stop) -> Option<&'static str> {
+        match stop {
+            Stop::Breakpoint(_) | Stop::Stepped => Some("S05"),
+            Stop::Halted => Some("S04"),
+            Stop::Limit => None,
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<Vec<u8>> {
+        let machine = &self.machine;
+        Some(match n {
+            0..=15 => vec![machine.registers()[n]],
+            16 => machine.index().to_le_bytes().to_vec(),
+            17 => machine.pc().to_le_bytes().to_vec(),
+            18 => vec![machine.stack().len() as u8],
+            19 => vec![machine.delay_timer()],
+            20 => vec![machine.sound_timer()],
+            _ => return None,
+        })
+    }
+
+    fn set_register(&mut self, n: usize, value: &[u8]) -> Option<()> {
+        let machine = &mut self.machine;
+        let word = || Some(u16::from_le_bytes([*value.first()?, *value.get(1)?]));
+        match n {
+            0..=15 => machine.registers_mut()[n] = *value.first()?,
+            16 => machine.set_index(word()?),
+            17 => machine.set_pc(word()?),
+            18 => machine.set_sp(*value.first()?),
+            19 => machine.set_delay_timer(*value.first()?),
+            20 => machine.set_sound_timer(*value.first()?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn registers(&self) -> String {
+        let bytes: Vec<u8> = (0..REGISTER_COUNT)
+            .flat_map(|n| self.register(n).unwrap_or_default())
+            .collect();
+        hex(&bytes)
+    }
+
+    fn set_registers(&mut self, text: &str) -> Option<()> {
+        let bytes = unhex(text)?;
+        let mut offset = 0;
+        for n in 0..REGISTER_COUNT {
+            let size = self.register(n)?.len();
+            self.set_register(n, bytes.get(offset..offset + size)?)?;
+            offset += size;
+        }
+        Some(())
+    }
+
+    /// Parses `addr,length` into a range of memory.
+    fn memory_range(spec: &str) -> Option<std::ops::Range<usize>> {
+        let (addr, length) = spec.split_once(',')?;
+        let start = usize::from_str_radix(addr, 16).ok()?;
+        let end = start.checked_add(usize::from_str_radix(length, 16).ok()?)?;
+        if end > 4096 {
+            return None;
+        }
+        Some(start..end)
+    }
+
+    fn breakpoint(&mut self, data: &str, insert: bool) -> Option<()> {
+        let mut fields = data[1..].split(',');
+        let kind = fields.next()?;
+        if kind != "0" && kind != "1" {
+            return None;
+        }
+        let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+        if insert {
+            self.debugger
+                .add_breakpoint(&format!("{:#05x}", addr))
+                .ok()?;
+        } else {
+            self.debugger.remove_breakpoint(addr);
+        }
+        Some(())
+    }
+
+    /// Computes the reply to a packet. `None` means that no reply is sent
+    /// yet, because the machine was resumed.
+    fn reply(&mut self, data: &str) -> Option<String> {
+        let error = || "E01".to_string();
+        let ok = |done: Option<()>| done.map_or_else(error, |_| "OK".to_string());
+        let command = data.chars().next().unwrap_or(' ');
+        let args = data.get(1..).unwrap_or("");
+        Some(match command {
+            '?' => "S05".to_string(),
+            'g' => self.registers(),
+            'G' => ok(self.set_registers(args)),
+            'p' => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|n| self.register(n))
+                .map_or_else(error, |value| hex(&value)),
+            'P' => {
+                let done = args.split_once('=').and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    self.set_register(n, &unhex(value)?)
+                });
+                ok(done)
+            }
+            'm' => match Stub::<W>::memory_range(args) {
+                Some(range) => hex(&self.machine.memory()[range]),
+                None => error(),
+            },
+            'M' => {
+                let done = args.split_once(':').and_then(|(spec, value)| {
+                    let range = Stub::<W>::memory_range(spec)?;
+                    let bytes = unhex(value)?;
+                    if bytes.len() != range.len() {
+                        return None;
+                    }
+                    self.machine.memory_mut()[range].copy_from_slice(&bytes);
+                    Some(())
+                });
+                ok(done)
+            }
+            'Z' | 'z' => ok(self.breakpoint(data, command == 'Z')),
+            's' => {
+                let stop = if self.debugger.step(&mut self.machine) {
+                    Stop::Halted
+                } else {
+                    Stop::Stepped
+                };
+                Stub::<W>::stop_reply(stop)?.to_string()
+            }
+            'c' => {
+                self.running = true;
+                return None;
+            }
+            'H' => "OK".to_string(),
+            'D' => "OK".to_string(),
+            _ if data.starts_with("qSupported") => {
+                "PacketSize=1000;qXfer:features:read+".to_string()
+            }
+            _ if data.starts_with("qXfer:features:read:target.xml:") => {
+                let spec = &data["qXfer:features:read:target.xml:".len()..];
+                let range = spec.split_once(',').and_then(|(offset, length)| {
+                    let offset = usize::from_str_radix(offset, 16).ok()?;
+                    let length = usize::from_str_radix(length, 16).ok()?;
+                    Some(
+                        offset.min(TARGET_XML.len())
+                            ..offset.saturating_add(length).min(TARGET_XML.len()),
+                    )
+                });
+                match range {
+                    Some(range) if range.end == TARGET_XML.len() => {
+                        format!("l{}", &TARGET_XML[range])
+                    }
+                    Some(range) => format!("m{}", &TARGET_XML[range]),
+                    None => error(),
+                }
+            }
+            _ if data == "qAttached" => "1".to_string(),
+            _ if data == "qC" => "QC1".to_string(),
+            _ if data == "qfThreadInfo" => "m1".to_string(),
+            _ if data == "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        })
+    }
+
+    fn handle(&mut self, input: Input) -> io::Result<()> {
+        match input {
+            Input::Interrupt => {
+                if self.running {
+                    self.running = false;
+                    self.send("S02")?;
+                }
+            }
+            Input::Packet(data) => {
+                self.output.write_all(b"+")?;
+                if let Some(reply) = self.reply(&data) {
+                    self.send(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the machine for one time slice while continuing.
+    fn tick(&mut self) -> io::Result<()> {
+        let stop = self.debugger.run(&mut self.machine, SLICE);
+        if let Some(reply) = Stub::<W>::stop_reply(stop) {
+            self.running = false;
+            self.send(reply)?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves one GDB connection until the client detaches, kills the target
+/// or disconnects.
+pub fn serve<R, W>(input: R, output: W, machine: Machine, debugger: Debugger) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || read_input(input, sender));
+
+    let mut stub = Stub::new(output, machine, debugger);
+    loop {
+        let input = if stub.running {
+            stub.tick()?;
+            match receiver.try_recv() {
+                Ok(input) => input,
+                Err(mpsc::TryRecvError::Empty) => continue,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(input) => input,
+                Err(_) => break,
+            }
+        };
+        let last = matches!(&input, Input::Packet(p) if p == "k" || p == "D");
+        stub.handle(input)?;
+        if last {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufRead;
+    use std::net::{TcpListener, TcpStream};
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) {
+            write!(self.writer, "${}#{:02x}", data, checksum(data)).unwrap();
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.response()
+        }
+
+        fn response(&mut self) -> String {
+            let mut packet = Vec::new();
+            self.reader.read_until(b'#', &mut packet).unwrap();
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum).unwrap();
+            let data = String::from_utf8(packet[1..packet.len() - 1].to_vec()).unwrap();
+            assert_eq!(
+                std::str::from_utf8(&sum).unwrap(),
+                format!("{:02x}", checksum(&data))
+            );
+            data
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        // 0x200: V0 := 1; 0x202: V0 += 1; 0x204: jump 0x202
+        let prog = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            let machine = Machine::new(&prog);
+            serve(
+                stream.try_clone().unwrap(),
+                stream,
+                machine,
+                Debugger::default(),
+            )
+            .unwrap();
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut client = Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+
+        assert!(client
+            .request("qSupported:xmlRegisters=i386")
+            .contains("qXfer"));
+        assert!(client
+            .request("qXfer:features:read:target.xml:0,20")
+            .starts_with("m<?xml"));
+        assert_eq!(
+            client.request("qXfer:features:read:target.xml:ffffffffffffffff,ffffffffffffffff"),
+            "l"
+        );
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(client.request("p11"), "0002");
+        assert_eq!(client.request("m200,4"), "60017001");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "01");
+        assert_eq!(client.request("Z0,204,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(&client.request("g")[..4], "0200");
+        assert_eq!(client.request("p11"), "0402");
+        assert_eq!(client.request("z0,204,2"), "OK");
+        assert_eq!(client.request("P0=ff"), "OK");
+        assert_eq!(client.request("M300,2:abcd"), "OK");
+        assert_eq!(client.request("m300,2"), "abcd");
+        assert_eq!(client.request("mfff,2"), "E01");
+
+        client.send("c");
+        client.writer.write_all(&[0x03]).unwrap();
+        assert_eq!(client.response(), "S02");
+        assert_eq!(client.request("D"), "OK");
+        server.join().unwrap();
+    }
+}
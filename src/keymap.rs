@@ -0,0 +1,193 @@
+//! Host keyboard bindings for the 16 CHIP-8 keys.
+//!
+//! Host keys are named like winit's `VirtualKeyCode` variants, e.g. `Key1`,
+//! `Q` or `Left`, so the bindings can be stored as text. A CHIP-8 key can
+//! have several host keys, and a host key can autofire, pressing and
+//! releasing its key while held: `Space=5*`.
+
+use std::fmt;
+
+/// The COSMAC VIP keypad laid over the left of a QWERTY keyboard, by
+/// CHIP-8 key.
+pub const HEX_LAYOUT: [&str; 16] = [
+    "X", "Key1", "Key2", "Key3", "Q", "W", "E", "A", "S", "D", "Z", "C", "Key4", "R", "F", "V",
+];
+
+/// COSMAC VIP keypad rows, as the keys are laid out on the device.
+pub const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub host: String,
+    pub key: u8,
+    pub turbo: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<Binding>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::new()
+    }
+}
+
+impl Keymap {
+    /// The hex layout.
+    pub fn new() -> Keymap {
+        let mut keymap = Keymap::empty();
+        for (key, host) in HEX_LAYOUT.iter().enumerate() {
+            keymap.bind(host, key as u8);
+        }
+        keymap
+    }
+
+    pub fn empty() -> Keymap {
+        Keymap {
+            bindings: Vec::new(),
+        }
+    }
+
+    /// A keymap with only the bindings in `text`.
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        let mut keymap = Keymap::empty();
+        keymap.bind_all(text)?;
+        Ok(keymap)
+    }
+
+    /// Makes `host` press `key`, in addition to the keys already bound to
+    /// it. A host key drives one CHIP-8 key, so an earlier binding of `host`
+    /// is replaced.
+    pub fn bind(&mut self, host: &str, key: u8) {
+        self.unbind(host);
+        self.bindings.push(Binding {
+            host: host.to_string(),
+            key: key & 0xF,
+            turbo: false,
+        });
+    }
+
+    pub fn unbind(&mut self, host: &str) {
+        self.bindings.retain(|binding| binding.host != host);
+    }
+
+    /// Turns autofire on or off for `host`, if it is bound.
+    pub fn set_turbo(&mut self, host: &str, turbo: bool) {
+        for binding in self.bindings.iter_mut().filter(|b| b.host == host) {
+            binding.turbo = turbo;
+        }
+    }
+
+    /// Adds bindings written as `Left=4 Right=6 Space=5*`, where `*` turns
+    /// on autofire.
+    pub fn bind_all(&mut self, text: &str) -> Result<(), String> {
+        for binding in text.split_whitespace() {
+            let (host, key) = binding
+                .split_once('=')
+                .ok_or_else(|| format!("expected HOST=KEY, got '{}'", binding))?;
+            let (key, turbo) = match key.strip_suffix('*') {
+                Some(key) => (key, true),
+                None => (key, false),
+            };
+            let key = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|key| *key < 16)
+                .ok_or_else(|| format!("invalid key '{}'", key))?;
+            self.bind(host, key);
+            self.set_turbo(host, turbo);
+        }
+        Ok(())
+    }
+
+    /// The CHIP-8 key `host` presses.
+    pub fn key(&self, host: &str) -> Option<u8> {
+        self.binding(host).map(|binding| binding.key)
+    }
+
+    pub fn binding(&self, host: &str) -> Option<&Binding> {
+        self.bindings.iter().find(|binding| binding.host == host)
+    }
+
+    /// Host keys bound to `key`.
+    pub fn hosts(&self, key: u8) -> Vec<&str> {
+        self.bindings
+            .iter()
+            .filter(|binding| binding.key == key)
+            .map(|binding| binding.host.as_str())
+            .collect()
+    }
+
+    pub fn bindings(&self) -> &[Binding] {
+        &self.bindings
+    }
+
+    /// Bindings that are not part of the hex layout, such as a game's
+    /// arrow keys.
+    pub fn extras(&self) -> Vec<&Binding> {
+        let layout = Keymap::new();
+        self.bindings
+            .iter()
+            .filter(|binding| !layout.bindings.contains(binding))
+            .collect()
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bindings: Vec<String> = self
+            .bindings
+            .iter()
+            .map(|binding| {
+                let turbo = if binding.turbo { "*" } else { "" };
+                format!("{}={:X}{}", binding.host, binding.key, turbo)
+            })
+            .collect();
+        write!(f, "{}", bindings.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bindings() {
+        let mut keymap = Keymap::new();
+        assert_eq!(keymap.key("Key1"), Some(0x1));
+        assert_eq!(keymap.key("V"), Some(0xF));
+        assert_eq!(keymap.key("Left"), None);
+
+        keymap.bind_all("Left=4 Right=6 Q=5").unwrap();
+        assert_eq!(keymap.key("Left"), Some(0x4));
+        assert_eq!(keymap.key("Q"), Some(0x5));
+        assert_eq!(keymap.hosts(0x5), vec!["W", "Q"]);
+        assert_eq!(keymap.hosts(0x4), vec!["Left"]);
+        assert!(keymap.bind_all("Left").is_err());
+        assert!(keymap.bind_all("Left=G").is_err());
+        assert!(keymap.to_string().ends_with("Left=4 Right=6 Q=5"));
+        let extras: Vec<_> = keymap.extras().iter().map(|b| b.host.as_str()).collect();
+        assert_eq!(extras, vec!["Left", "Right", "Q"]);
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut keymap = Keymap::parse("Space=5* W=5").unwrap();
+        assert_eq!(keymap.hosts(0x5), vec!["Space", "W"]);
+        assert!(keymap.binding("Space").unwrap().turbo);
+        assert!(!keymap.binding("W").unwrap().turbo);
+        assert_eq!(keymap.to_string(), "Space=5* W=5");
+        assert_eq!(Keymap::parse(&keymap.to_string()), Ok(keymap.clone()));
+
+        keymap.set_turbo("Space", false);
+        keymap.unbind("W");
+        assert_eq!(keymap.to_string(), "Space=5");
+        assert!(Keymap::parse("Space=*5").is_err());
+    }
+}
@@ -0,0 +1,22 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod archive;
+pub mod asm;
+pub mod capture;
+pub mod chip;
+pub mod config;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod effects;
+pub mod fuzz;
+pub mod gdb;
+pub mod keymap;
+pub mod library;
+pub mod pacing;
+pub mod palette;
+pub mod profile;
+pub mod trace;
+pub mod watch;
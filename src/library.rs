@@ -0,0 +1,538 @@
+//! ROM library.
+//!
+//! ROMs found in the library directories are identified by the SHA-1 of
+//! their contents and matched against a bundled database of known games.
+//! Descriptions come from the `GAMES.TXT` and `SGAMES.TXT` documents of the
+//! CHIP-8 emulator pack in `assets/CHIP8/DOCS`.
+
+use crate::archive;
+use crate::asm::MAX_SIZE;
+use crate::chip::{Machine, Quirks};
+use crate::disasm::{self, Platform};
+use crate::keymap::Keymap;
+use crate::pacing::FRAME_RATE;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DATABASE: &str = include_str!("../assets/roms.json");
+const GAMES_TXT: &[u8] = include_bytes!("../assets/CHIP8/DOCS/GAMES.TXT");
+const SGAMES_TXT: &[u8] = include_bytes!("../assets/CHIP8/DOCS/SGAMES.TXT");
+
+/// Directories of the ROMs that come with the emulator.
+pub const BUNDLED_DIRS: [&str; 2] = ["assets/games", "assets/CHIP8/SGAMES"];
+
+/// Frames a ROM runs for its thumbnail, enough for most games to draw their
+/// title screen or first level.
+pub const THUMBNAIL_FRAMES: u64 = 120;
+
+/// Extensions ROMs are commonly distributed with.
+const EXTENSIONS: [&str; 5] = ["ch8", "c8", "sc8", "xo8", "chip8"];
+
+/// What the database knows about a ROM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    /// File name in the emulator pack.
+    pub name: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub platform: Platform,
+    pub quirks: Option<Quirks>,
+    pub ips: Option<u32>,
+    /// Which keys do what.
+    pub keys: Option<String>,
+    /// The hex layout with the game's controls added, e.g. the arrow keys.
+    pub keymap: Option<Keymap>,
+    pub description: Option<String>,
+}
+
+fn platform(name: &str) -> Result<Platform, String> {
+    match name {
+        "chip8" => Ok(Platform::Chip8),
+        "schip" => Ok(Platform::SuperChip),
+        "xochip" => Ok(Platform::XoChip),
+        _ => Err(format!("unknown platform '{}'", name)),
+    }
+}
+
+pub fn sha1(data: &[u8]) -> String {
+    sha1::Sha1::from(data).digest().to_string()
+}
+
+/// Splits a game list such as `GAMES.TXT` into descriptions by file name.
+/// An entry starts with `NAME    : text` in the first column and continues
+/// on indented lines. `PONG (2)` describes both PONG and PONG2.
+pub fn parse_descriptions(text: &str) -> BTreeMap<String, String> {
+    let mut descriptions = BTreeMap::new();
+    let mut current: Option<(Vec<String>, String)> = None;
+    let mut finish = |current: &mut Option<(Vec<String>, String)>| {
+        if let Some((names, text)) = current.take() {
+            for name in names {
+                descriptions.insert(name, text.clone());
+            }
+        }
+    };
+    for line in text.lines() {
+        let starts_entry = line.starts_with(|c: char| c.is_ascii_alphanumeric());
+        match line.split_once(':') {
+            Some((head, rest)) if starts_entry => {
+                finish(&mut current);
+                let head = head.trim();
+                let names = match head.split_once(" (") {
+                    Some((name, suffix)) => {
+                        let suffix = suffix.trim_end_matches(')');
+                        vec![name.to_string(), format!("{}{}", name, suffix)]
+                    }
+                    None => vec![head.to_string()],
+                };
+                current = Some((names, rest.trim().to_string()));
+            }
+            _ if line.trim().is_empty() => finish(&mut current),
+            _ => {
+                if let Some((_, text)) = &mut current {
+                    text.push(' ');
+                    text.push_str(line.trim());
+                }
+            }
+        }
+    }
+    finish(&mut current);
+    descriptions
+}
+
+/// Known ROMs by SHA-1.
+#[derive(Debug, Default)]
+pub struct Database {
+    entries: HashMap<String, Metadata>,
+}
+
+impl Database {
+    /// The database compiled into the program, with the emulator pack's
+    /// descriptions attached.
+    pub fn bundled() -> Database {
+        let mut database = Database::parse(DATABASE).expect("invalid bundled ROM database");
+        let games = String::from_utf8_lossy(GAMES_TXT);
+        database.add_descriptions(Platform::Chip8, &parse_descriptions(&games));
+        let sgames = String::from_utf8_lossy(SGAMES_TXT);
+        database.add_descriptions(Platform::SuperChip, &parse_descriptions(&sgames));
+        database
+    }
+
+    /// Reads a JSON object mapping SHA-1 hashes to entries with `name`,
+    /// `title` and `platform`, and optionally `author`, `quirks`, `ips`,
+    /// `keys` and `controls`, which binds host keys as in `Left=4 Right=6`.
+    pub fn parse(json: &str) -> Result<Database, String> {
+        let root: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let object = root.as_object().ok_or("expected an object")?;
+        let mut entries = HashMap::new();
+        for (hash, entry) in object.iter() {
+            let text = |key: &str| entry[key].as_str().map(str::to_string);
+            let required = |key: &str| text(key).ok_or(format!("{}: missing {}", hash, key));
+            let quirks = match text("quirks") {
+                Some(name) => Some(Quirks::from_name(&name)?),
+                None => None,
+            };
+            let keymap = match text("controls") {
+                Some(controls) => {
+                    let mut keymap = Keymap::new();
+                    keymap
+                        .bind_all(&controls)
+                        .map_err(|e| format!("{}: {}", hash, e))?;
+                    Some(keymap)
+                }
+                None => None,
+            };
+            let metadata = Metadata {
+                name: required("name")?,
+                title: required("title")?,
+                author: text("author"),
+                platform: platform(&required("platform")?)?,
+                quirks,
+                ips: entry["ips"].as_u64().map(|ips| ips as u32),
+                keys: text("keys"),
+                keymap,
+                description: None,
+            };
+            entries.insert(hash.to_lowercase(), metadata);
+        }
+        Ok(Database { entries })
+    }
+
+    /// Attaches descriptions to the entries of `platform` by file name.
+    pub fn add_descriptions(
+        &mut self,
+        platform: Platform,
+        descriptions: &BTreeMap<String, String>,
+    ) {
+        for metadata in self.entries.values_mut() {
+            if metadata.platform == platform {
+                if let Some(text) = descriptions.get(&metadata.name) {
+                    metadata.description = Some(text.clone());
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, sha1: &str) -> Option<&Metadata> {
+        self.entries.get(sha1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// How a ROM should be run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub ips: u32,
+    pub keymap: Keymap,
+    /// Whether the ROM is in the database, as opposed to guessed from its
+    /// instructions.
+    pub known: bool,
+}
+
+impl Settings {
+    /// Looks the ROM up in `database`. Unknown ROMs get the defaults of the
+    /// platform their instructions suggest, e.g. SUPER-CHIP for one that
+    /// switches to hires with 00FF.
+    pub fn detect(rom: &[u8], database: &Database) -> Settings {
+        match database.get(&sha1(rom)) {
+            Some(metadata) => Settings {
+                platform: metadata.platform,
+                quirks: metadata.quirks.unwrap_or_else(|| quirks(metadata.platform)),
+                ips: metadata.ips.unwrap_or_else(|| ips(metadata.platform)),
+                keymap: metadata.keymap.clone().unwrap_or_default(),
+                known: true,
+            },
+            None => {
+                let platform = disasm::detect_platform(rom);
+                Settings {
+                    platform,
+                    quirks: quirks(platform),
+                    ips: ips(platform),
+                    keymap: Keymap::new(),
+                    known: false,
+                }
+            }
+        }
+    }
+
+    /// Configures `machine` to run the ROM. Speed and keys are up to the
+    /// caller.
+    pub fn apply(&self, machine: &mut Machine) {
+        machine.set_quirks(self.quirks);
+        machine.set_platform(self.platform);
+    }
+}
+
+/// The screen after running `rom` without a window for
+/// `THUMBNAIL_FRAMES`, or until it stops.
+pub fn thumbnail(rom: &[u8], settings: &Settings) -> [[u8; 64]; 32] {
+    let mut machine = Machine::new(rom);
+    settings.apply(&mut machine);
+    machine.set_seed(0);
+    let cycles = THUMBNAIL_FRAMES * settings.ips as u64 / FRAME_RATE as u64;
+    for _ in 0..cycles {
+        if machine.cycle() {
+            break;
+        }
+    }
+    machine.video_mem
+}
+
+/// Quirks of the usual interpreter for `platform`. XO-CHIP follows the
+/// original CHIP-8.
+pub fn quirks(platform: Platform) -> Quirks {
+    match platform {
+        Platform::SuperChip => Quirks::SCHIP,
+        Platform::Chip8 | Platform::XoChip => Quirks::CHIP8,
+    }
+}
+
+/// Instructions per second games for `platform` expect.
+pub fn ips(platform: Platform) -> u32 {
+    match platform {
+        Platform::Chip8 => 700,
+        Platform::SuperChip | Platform::XoChip => 1000,
+    }
+}
+
+/// A ROM file found in the library.
+#[derive(Debug, Clone)]
+pub struct Rom {
+    pub path: PathBuf,
+    pub sha1: String,
+    pub size: usize,
+    pub metadata: Option<Metadata>,
+}
+
+impl Rom {
+    /// Reads the ROM at `path`, which may point into an archive.
+    pub fn load(path: &Path, database: &Database) -> io::Result<Rom> {
+        Ok(Rom::new(path, &archive::read(path, MAX_SIZE)?, database))
+    }
+
+    fn new(path: &Path, data: &[u8], database: &Database) -> Rom {
+        let sha1 = sha1(data);
+        Rom {
+            path: path.to_path_buf(),
+            metadata: database.get(&sha1).cloned(),
+            sha1,
+            size: data.len(),
+        }
+    }
+
+    /// The ROMs among the entries of a ZIP archive.
+    pub fn archived(archive: &Path, database: &Database) -> io::Result<Vec<Rom>> {
+        let roms = archive::entries(archive)?
+            .iter()
+            .map(|(name, data)| Rom::new(&archive::join(archive, name), data, database))
+            .filter(Rom::is_rom)
+            .collect();
+        Ok(roms)
+    }
+
+    /// The database title, or the file name for unknown ROMs.
+    pub fn title(&self) -> String {
+        match &self.metadata {
+            Some(metadata) => metadata.title.clone(),
+            None => self.file_name(),
+        }
+    }
+
+    /// Name of the file, or of the entry for ROMs in archives.
+    fn file_name(&self) -> String {
+        let path = match archive::split(&self.path) {
+            Some((_, name)) => PathBuf::from(name),
+            None => self.path.clone(),
+        };
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Known ROMs, ROM extensions and extensionless files small enough to
+    /// be programs count; documents, sources and executables do not.
+    fn is_rom(&self) -> bool {
+        if self.metadata.is_some() {
+            return true;
+        }
+        if self.size == 0 || self.size > MAX_SIZE {
+            return false;
+        }
+        match Path::new(&self.file_name()).extension() {
+            Some(ext) => EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()),
+            None => true,
+        }
+    }
+}
+
+pub struct Library {
+    pub dirs: Vec<PathBuf>,
+    pub roms: Vec<Rom>,
+    database: Database,
+}
+
+impl Library {
+    pub fn new(dirs: Vec<PathBuf>) -> Library {
+        Library::with_database(dirs, Database::bundled())
+    }
+
+    pub fn with_database(dirs: Vec<PathBuf>, database: Database) -> Library {
+        Library {
+            dirs,
+            roms: Vec::new(),
+            database,
+        }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    /// Searches the directories and their subdirectories for ROMs, sorted
+    /// by title. ZIP archives count as directories, and may be given in
+    /// place of one. Whatever cannot be read is skipped.
+    pub fn scan(&mut self) {
+        let mut roms = Vec::new();
+        let mut pending = self.dirs.clone();
+        let mut visited = HashSet::new();
+        while let Some(dir) = pending.pop() {
+            if archive::is_archive(&dir) {
+                roms.extend(Rom::archived(&dir, &self.database).unwrap_or_default());
+                continue;
+            }
+            // Symlinks may lead back into a directory already searched.
+            let canonical = match dir.canonicalize() {
+                Ok(canonical) => canonical,
+                Err(_) => continue,
+            };
+            if !visited.insert(canonical) {
+                continue;
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() || archive::is_archive(&path) {
+                    pending.push(path);
+                    continue;
+                }
+                // Files too large to be programs are not read at all.
+                let size = fs::metadata(&path).map_or(u64::MAX, |m| m.len());
+                if size > MAX_SIZE as u64 {
+                    continue;
+                }
+                if let Ok(rom) = Rom::load(&path, &self.database) {
+                    if rom.is_rom() {
+                        roms.push(rom);
+                    }
+                }
+            }
+        }
+        roms.sort_by(|a, b| a.title().cmp(&b.title()).then(a.path.cmp(&b.path)));
+        self.roms = roms;
+    }
+
+    pub fn find(&self, sha1: &str) -> Option<&Rom> {
+        self.roms.iter().find(|rom| rom.sha1 == sha1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PONG: &str = "b232ef880bd6060fb45fa6effed7edf0ae95670e";
+
+    #[test]
+    fn test_descriptions() {
+        let text = "\
+            * games *\n\
+\n\
+PONG (2): Here is the well known pong game.\n          Two versions.\n\
+\n\
+BRIX    : Use 4 and 6.\n";
+        let descriptions = parse_descriptions(text);
+        assert_eq!(descriptions.len(), 3);
+        assert_eq!(
+            descriptions["PONG2"],
+            "Here is the well known pong game. Two versions."
+        );
+        assert_eq!(descriptions["BRIX"], "Use 4 and 6.");
+    }
+
+    #[test]
+    fn test_bundled_database() {
+        let database = Database::bundled();
+        let pong = database.get(PONG).unwrap();
+        assert_eq!(pong.title, "Pong");
+        assert_eq!(pong.platform, Platform::Chip8);
+        assert!(pong
+            .description
+            .as_ref()
+            .unwrap()
+            .starts_with("Here is the well known pong"));
+        let rom = fs::read("assets/CHIP8/SGAMES/ALIEN").unwrap();
+        let alien = database.get(&sha1(&rom)).unwrap();
+        assert_eq!(
+            (alien.platform, alien.quirks),
+            (Platform::SuperChip, Some(Quirks::SCHIP))
+        );
+        assert!(alien
+            .description
+            .as_ref()
+            .unwrap()
+            .contains("Space Invaders clone"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut library = Library::new(vec![PathBuf::from("assets/CHIP8")]);
+        library.scan();
+        assert_eq!(library.roms.len(), 43);
+        assert!(library.roms.iter().all(|rom| rom.metadata.is_some()));
+        assert_eq!(
+            library.find(PONG).unwrap().path,
+            Path::new("assets/CHIP8/GAMES/PONG")
+        );
+        assert!(Database::parse("{\"00\": {\"name\": \"X\"}}").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_links() {
+        let dir = std::env::temp_dir().join(format!("chip8-library-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("assets/CHIP8/GAMES/PONG", dir.join("PONG")).unwrap();
+        fs::write(dir.join("LARGE"), vec![0; MAX_SIZE + 1]).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+
+        let mut library = Library::new(vec![dir.clone()]);
+        library.scan();
+        fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<_> = library.roms.iter().map(Rom::file_name).collect();
+        assert_eq!(names, ["PONG"]);
+    }
+
+    #[test]
+    fn test_scan_archive() {
+        let mut library = Library::new(vec![PathBuf::from("assets/chp8_220.zip")]);
+        library.scan();
+        assert_eq!(library.roms.len(), 43);
+        let pong = library.find(PONG).unwrap();
+        assert_eq!(pong.path, Path::new("assets/chp8_220.zip:CHIP8/GAMES/PONG"));
+        assert_eq!(
+            Rom::load(&pong.path, library.database()).unwrap().sha1,
+            PONG
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let database = Database::bundled();
+        let invaders = fs::read("assets/CHIP8/GAMES/INVADERS").unwrap();
+        let settings = Settings::detect(&invaders, &database);
+        assert!(settings.known);
+        assert_eq!((settings.quirks, settings.ips), (Quirks::SCHIP, 700));
+        assert_eq!(settings.keymap.key("Space"), Some(0x5));
+
+        let mut unknown = fs::read("assets/CHIP8/SGAMES/ALIEN").unwrap();
+        unknown.push(0);
+        let settings = Settings::detect(&unknown, &database);
+        assert!(!settings.known);
+        assert_eq!(settings.platform, Platform::SuperChip);
+        assert_eq!((settings.quirks, settings.ips), (Quirks::SCHIP, 1000));
+
+        let settings = Settings::detect(&[0xF0, 0x00, 0x12, 0x34], &database);
+        assert_eq!(settings.platform, Platform::XoChip);
+        assert_eq!(settings.quirks, Quirks::CHIP8);
+        let settings = Settings::detect(&[0x12, 0x00], &database);
+        assert_eq!((settings.platform, settings.ips), (Platform::Chip8, 700));
+        assert_eq!(settings.keymap, Keymap::new());
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let database = Database::bundled();
+        let rom = fs::read("assets/games/BRIX").unwrap();
+        let screen = thumbnail(&rom, &Settings::detect(&rom, &database));
+        let lit = screen.iter().flatten().filter(|pixel| **pixel != 0).count();
+        assert!(lit > 50, "{} pixels lit", lit);
+        assert_eq!(
+            thumbnail(&[0x12, 0x00], &Settings::detect(&[0x12, 0x00], &database))[0][0],
+            0
+        );
+    }
+}
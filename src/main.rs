@@ -1,55 +1,192 @@
-use chip::{read_game, Machine};
+use chip8::archive;
+use chip8::asm;
+use chip8::capture::{self, Capture, Recording};
+use chip8::chip::{read_game, Fault, Machine, Quirks};
+use chip8::config::{self, Config, RomConfig};
+use chip8::coverage::{Coverage, Use};
+use chip8::dap;
+use chip8::effects::{self, Effects, Persistence, Scaler, Viewport};
+use chip8::gdb;
+use chip8::keymap::{Keymap, KEYPAD};
+use chip8::library::{self, Database, Library, Settings};
+use chip8::pacing::{self, Pacer};
+use chip8::palette::{Color, Palette, PRESETS};
+use chip8::profile::Profiler;
+use chip8::trace::{self, Tracer};
+use chip8::debugger::Debugger;
+use chip8::disasm::{self, Syntax};
+use chip8::watch::{self, Watch};
 use std::borrow::Cow;
-use std::error::Error;
-use std::io::Cursor;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, LineWriter};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-mod chip;
-#[macro_use]
-extern crate lazy_static;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate glium;
 
 use glium::{
     backend::Facade,
-    glutin, index,
+    glutin::event::VirtualKeyCode,
     texture::{ClientFormat, RawImage2d},
+    uniforms::{MagnifySamplerFilter, SamplerBehavior},
     Display, Surface, Texture2d,
 };
-use image::{jpeg::JpegDecoder, ImageDecoder};
 use imgui::*;
-use rand::prelude::*;
+use imgui_glium_renderer::Renderer;
 
+mod screen;
 mod support;
 
+use screen::Screen;
+
 struct CustomTexturesApp {
-    my_texture_id: Option<TextureId>,
     machine: Machine,
+    rom: PathBuf,
+    /// SHA-1 of the running ROM.
+    sha1: String,
+    syntax: Syntax,
+    debugger: Debugger,
+    breakpoint: ImString,
+    breakpoint_error: Option<String>,
+    profile_message: Option<String>,
+    coverage_message: Option<String>,
+    pacer: Pacer,
+    last_frame: Instant,
+    /// Settings of the running ROM before the user's and the command
+    /// line's.
+    detected: Settings,
+    keymap: Keymap,
+    /// Host keys of the keymap, resolved to key codes, with autofire.
+    keys: Vec<(VirtualKeyCode, u8, bool)>,
+    /// Frames shown, for autofire.
+    frames: u64,
+    /// CHIP-8 key being remapped.
+    remap_key: u8,
+    /// Whether remapping edits the running ROM's profile rather than the
+    /// layout of all ROMs.
+    remap_rom: bool,
+    /// Waiting for a host key to bind to `remap_key`.
+    capturing: bool,
+    /// Keys held down with the mouse on the keypad window.
+    mouse_keys: [bool; 16],
+    options: MachineOptions,
+    library: Library,
+    search: ImString,
+    selected: Option<String>,
+    /// Thumbnail textures by SHA-1.
+    thumbnails: HashMap<String, TextureId>,
+    /// The palette the thumbnails are drawn in.
+    thumbnail_palette: Palette,
+    /// What the screen is drawn into when it is docked in a window.
+    docked_screen: Option<(TextureId, Rc<Texture2d>)>,
+    browser_message: Option<String>,
+    /// Set while the screen is being recorded.
+    recording: Option<Recording>,
+    capture_message: Option<String>,
+    /// Set in watch mode.
+    watch: Option<Watch>,
+    last_poll: Instant,
+    /// Where the settings are saved on exit.
+    config_path: Option<PathBuf>,
+}
+
+/// Frames an autofiring key stays pressed, and then released.
+const TURBO_FRAMES: u64 = 3;
+
+/// How often watched files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Host keys that can be bound to CHIP-8 keys.
+const HOST_KEYS: [VirtualKeyCode; 52] = {
+    use VirtualKeyCode::*;
+    [
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, A, B, C, D, E, F, G, H, I, J,
+        K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Left, Up, Right, Down, Space, Return,
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ]
+};
+
+/// Key codes of the bindings in `keymap`. Unknown host keys are left out.
+fn resolve_keys(keymap: &Keymap) -> Vec<(VirtualKeyCode, u8, bool)> {
+    keymap
+        .bindings()
+        .iter()
+        .filter_map(|binding| {
+            let code = HOST_KEYS
+                .iter()
+                .find(|code| format!("{:?}", code) == binding.host)?;
+            Some((*code, binding.key, binding.turbo))
+        })
+        .collect()
+}
+
+/// Colour of a byte in the coverage overlay.
+fn coverage_color(coverage: Option<&Coverage>, addr: u16) -> [f32; 4] {
+    match coverage.map(|c| c.use_at(addr)) {
+        Some(Use::Code) => [0.4, 1.0, 0.4, 1.0],
+        Some(Use::Data) => [1.0, 0.85, 0.3, 1.0],
+        Some(Use::Both) => [0.4, 0.8, 1.0, 1.0],
+        Some(Use::Untouched) => [0.5, 0.5, 0.5, 1.0],
+        None => [1.0, 1.0, 1.0, 1.0],
+    }
 }
 fn generate_texture<F>(machine: &Machine, gl_ctx: &F) -> Texture2d
+where
+    F: Facade,
+{
+    screen_texture(&machine.video_mem, gl_ctx)
+}
+
+/// Planes a pixel is lit in, bit 0 for the first. The machine draws on one.
+fn planes(pixel: u8) -> u8 {
+    (pixel != 0) as u8
+}
+
+/// Uploads a screen as the planes of each pixel, bottom row first. The
+/// shader colours them with the palette.
+fn screen_texture<F>(video_mem: &[[u8; 64]; 32], gl_ctx: &F) -> Texture2d
 where
     F: Facade,
 {
     const WIDTH: usize = 64;
     const HEIGHT: usize = 32;
-    // Generate dummy texture
     let mut data = Vec::with_capacity(WIDTH * HEIGHT);
 
-    for i in 0..HEIGHT {
-        for j in 0..WIDTH {
-            let pixel = machine.video_mem[HEIGHT - 1 - i][j];
-            // let pixel:u8 = 0xFF;
-            // let pixel:u8 = 0x00;
-            data.push(pixel);
-            data.push(pixel);
-            data.push(pixel);
-        }
+    for row in video_mem.iter().rev() {
+        data.extend(row.iter().map(|pixel| planes(*pixel)));
     }
 
     let raw = RawImage2d {
         data: Cow::Owned(data),
         width: WIDTH as u32,
         height: HEIGHT as u32,
+        format: ClientFormat::U8,
+    };
+    Texture2d::new(gl_ctx, raw).unwrap()
+}
+
+/// Uploads a screen in the colours of `palette`, for imgui to draw.
+fn palette_texture<F>(video_mem: &[[u8; 64]; 32], palette: &Palette, gl_ctx: &F) -> Texture2d
+where
+    F: Facade,
+{
+    let colors = palette.colors();
+    let mut data = Vec::with_capacity(64 * 32 * 3);
+    for row in video_mem.iter().rev() {
+        for pixel in row.iter() {
+            data.extend_from_slice(&colors[planes(*pixel) as usize].0);
+        }
+    }
+    let raw = RawImage2d {
+        data: Cow::Owned(data),
+        width: 64,
+        height: 32,
         format: ClientFormat::U8U8U8,
     };
     Texture2d::new(gl_ctx, raw).unwrap()
@@ -63,183 +200,1592 @@ impl CustomTexturesApp {
                 // if let Some(my_texture_id) = self.my_texture_id {
                 //     Image::new(my_texture_id, [100.0, 100.0]).build(ui);
                 // }
-                for (index, line) in self.machine.get_source_code().iter().enumerate() {
-                    ui.text(line);
+                let mut current = Syntax::ALL
+                    .iter()
+                    .position(|s| *s == self.syntax)
+                    .unwrap_or(0);
+                if ComboBox::new(im_str!("Syntax")).build_simple(
+                    ui,
+                    &mut current,
+                    &Syntax::ALL,
+                    &|s| Cow::Owned(ImString::new(s.name())),
+                ) {
+                    self.syntax = Syntax::ALL[current];
+                }
+                let coverage = self.machine.coverage();
+                let memory = self.machine.memory();
+                for (i, line) in self.machine.get_source_code(self.syntax).iter().enumerate() {
+                    let addr = 0x200 + 2 * i as u16;
+                    let color = coverage_color(coverage, addr);
+                    // Words only ever read as data are not shown as code.
+                    if coverage.map(|c| c.use_at(addr)) == Some(Use::Data) {
+                        let (a, b) = (memory[addr as usize], memory[addr as usize + 1]);
+                        ui.text_colored(color, format!("DB #{:02X}, #{:02X}", a, b));
+                    } else {
+                        ui.text_colored(color, line);
+                    }
                 }
             });
     }
-}
 
-fn main() -> std::io::Result<()> {
-    let buffer = read_game("INVADERS")?;
+    fn show_debugger(&mut self, ui: &Ui) {
+        let machine = &mut self.machine;
+        let debugger = &mut self.debugger;
+        let breakpoint = &mut self.breakpoint;
+        let breakpoint_error = &mut self.breakpoint_error;
+        Window::new(im_str!("Debugger"))
+            .size([400.0, 500.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let label = if debugger.paused {
+                    im_str!("Run")
+                } else {
+                    im_str!("Pause")
+                };
+                if ui.button(label, [0.0, 0.0]) {
+                    debugger.paused = !debugger.paused;
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Step"), [0.0, 0.0]) {
+                    debugger.paused = true;
+                    debugger.step(machine);
+                }
 
-    let mut my_app = CustomTexturesApp {
-        my_texture_id: None,
-        machine: Machine::new(buffer.as_slice()),
-    };
+                if let Some(fault) = machine.fault() {
+                    ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Stopped: {}", fault));
+                }
+                ui.text(format!("Platform: {}", machine.platform().name()));
+                let pc = machine.pc();
+                ui.text(format!("PC: {:#05x} {}", pc, debugger.symbolize(pc)));
+                ui.text(format!("I:  {:#05x}", machine.index()));
+                for (row, registers) in machine.registers().chunks(8).enumerate() {
+                    let values: Vec<String> =
+                        registers.iter().map(|v| format!("{:02X}", v)).collect();
+                    ui.text(format!(
+                        "V{:X}-V{:X}: {}",
+                        row * 8,
+                        row * 8 + 7,
+                        values.join(" ")
+                    ));
+                }
 
-    let mut system = support::init(file!());
-
-    let vertex_buffer = {
-        #[derive(Copy, Clone)]
-        struct Vertex {
-            position: [f32; 2],
-            tex_coords: [f32; 2],
-        }
-
-        implement_vertex!(Vertex, position, tex_coords);
-
-        glium::VertexBuffer::new(
-            &system.display,
-            &[
-                Vertex {
-                    position: [-1.0, -1.0],
-                    tex_coords: [0.0, 0.0],
-                },
-                Vertex {
-                    position: [-1.0, 1.0],
-                    tex_coords: [0.0, 1.0],
-                },
-                Vertex {
-                    position: [1.0, 1.0],
-                    tex_coords: [1.0, 1.0],
-                },
-                Vertex {
-                    position: [1.0, -1.0],
-                    tex_coords: [1.0, 0.0],
-                },
-            ],
-        )
-        .unwrap()
-    };
+                ui.separator();
+                let context = debugger.source_context(pc, 5);
+                if context.is_empty() {
+                    if let Some(opcode) = fetch(machine, pc) {
+                        ui.text(Syntax::Chipper.format(opcode));
+                    }
+                }
+                for (line, text, current) in context {
+                    let marker = if current { ">" } else { " " };
+                    ui.text(format!("{}{:5} {}", marker, line, text));
+                }
 
-    // building the index buffer
-    let index_buffer = glium::IndexBuffer::new(
-        &system.display,
-        index::PrimitiveType::TriangleStrip,
-        &[1 as u16, 2, 0, 3],
-    )
-    .unwrap();
+                ui.separator();
+                ui.text("Call stack:");
+                for call in debugger.call_stack(machine) {
+                    ui.text(format!("  {}", call));
+                }
 
-    // compiling shaders and linking them together
-    let program = program!(&system.display,
-        140 => {
-            vertex: "
-                #version 140
+                ui.separator();
+                ui.input_text(im_str!("Breakpoint"), breakpoint).build();
+                ui.same_line(0.0);
+                if ui.button(im_str!("Add"), [0.0, 0.0]) {
+                    *breakpoint_error = debugger.add_breakpoint(breakpoint.to_str()).err();
+                    breakpoint.clear();
+                }
+                if let Some(error) = breakpoint_error {
+                    ui.text(error.as_str());
+                }
+                let mut removed = None;
+                for addr in debugger.breakpoints().iter() {
+                    let name = ImString::new(format!("x##{}", addr));
+                    if ui.small_button(&name) {
+                        removed = Some(*addr);
+                    }
+                    ui.same_line(0.0);
+                    ui.text(format!("{:#05x} {}", addr, debugger.symbolize(*addr)));
+                }
+                if let Some(addr) = removed {
+                    debugger.remove_breakpoint(addr);
+                }
+            });
+    }
 
-                uniform mat4 matrix;
+    /// Replaces the running program with the ROM at `path`, rebuilt from
+    /// its source if that changed. Breakpoints are dropped, as they belong
+    /// to the previous program.
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        let (rom, map) = asm::load_latest(path)?;
+        let sha1 = library::sha1(&rom);
+        let detected = Settings::detect(&rom, &Database::bundled());
+        let mut settings = detected.clone();
+        self.options.configure(&mut settings, &sha1);
+        let mut machine = self.options.machine(&rom);
+        machine.set_coverage(Some(Coverage::new()));
+        if self.machine.profiler().is_some() {
+            machine.set_profiler(Some(Profiler::new()));
+        }
+        self.machine = machine;
+        self.debugger = Debugger::new(map);
+        self.rom = path.to_path_buf();
+        self.sha1 = sha1;
+        self.options.config.add_recent(path);
+        self.pacer.ips = settings.ips;
+        self.pacer.reset();
+        self.detected = detected;
+        self.set_keymap(settings.keymap);
+        if self.watch.is_some() {
+            self.watch = Some(Watch::new(watch::sources(path, self.debugger.map())));
+        }
+        Ok(())
+    }
 
-                in vec2 position;
-                in vec2 tex_coords;
+    fn set_keymap(&mut self, keymap: Keymap) {
+        self.keys = resolve_keys(&keymap);
+        self.keymap = keymap;
+    }
 
-                out vec2 v_tex_coords;
+    fn show_display(&mut self, ui: &Ui) {
+        let palette = &mut self.options.config.palette;
+        let effects = &mut self.options.config.effects;
+        let capture = &mut self.options.config.capture;
+        let recording = self.recording.is_some();
+        let message = &self.capture_message;
+        let mut screenshot = false;
+        let mut record = false;
+        Window::new(im_str!("Display"))
+            .size([300.0, 560.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let preview = ImString::new(palette.preset_name().unwrap_or("Custom"));
+                ComboBox::new(im_str!("Palette"))
+                    .preview_value(&preview)
+                    .build(ui, || {
+                        for (name, preset) in PRESETS.iter() {
+                            let selected = *palette == *preset;
+                            if Selectable::new(&ImString::new(*name))
+                                .selected(selected)
+                                .build(ui)
+                            {
+                                *palette = preset.clone();
+                            }
+                        }
+                    });
+                let labels = [
+                    im_str!("Background"),
+                    im_str!("Foreground"),
+                    im_str!("Plane 2"),
+                    im_str!("Both planes"),
+                ];
+                for (label, color) in labels.iter().zip(palette.colors_mut().iter_mut()) {
+                    let mut rgb = color.to_f32();
+                    if ColorEdit::new(label, &mut rgb).build(ui) {
+                        **color = Color::from_f32(rgb);
+                    }
+                }
 
-                void main() {
-                    gl_Position = matrix * vec4(position, 0.0, 1.0);
-                    v_tex_coords = tex_coords;
+                ui.separator();
+                ui.text("Persistence:");
+                for persistence in Persistence::ALL.iter() {
+                    let label = ImString::new(persistence.name());
+                    ui.radio_button(&label, &mut effects.persistence, *persistence);
+                }
+                if effects.persistence == Persistence::Phosphor {
+                    Slider::new(im_str!("Decay"))
+                        .range(0.0..=Effects::MAX_DECAY)
+                        .build(ui, &mut effects.decay);
                 }
-            ",
 
-            fragment: "
-                #version 140
-                uniform sampler2D tex;
-                in vec2 v_tex_coords;
-                out vec4 f_color;
+                ui.separator();
+                ui.text("Scaling:");
+                for scaler in Scaler::ALL.iter() {
+                    let label = ImString::new(scaler.name());
+                    ui.radio_button(&label, &mut effects.scaler, *scaler);
+                }
 
-                void main() {
-                    f_color = texture(tex, v_tex_coords);
+                ui.separator();
+                ui.text("Viewport:");
+                for viewport in Viewport::ALL.iter() {
+                    let label = ImString::new(viewport.name());
+                    ui.radio_button(&label, &mut effects.viewport, *viewport);
                 }
-            "
-        },
+                ui.checkbox(im_str!("Dock in a window"), &mut effects.docked);
+
+                ui.separator();
+                ui.text("CRT:");
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Television")) {
+                    effects.set_crt();
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Off")) {
+                    effects.clear_crt();
+                }
+                let sliders = [
+                    (im_str!("Scanlines"), &mut effects.scanlines),
+                    (im_str!("Curvature"), &mut effects.curvature),
+                    (im_str!("Bloom"), &mut effects.bloom),
+                    (im_str!("Pixel grid"), &mut effects.grid),
+                ];
+                for (label, value) in sliders {
+                    Slider::new(label).range(0.0..=1.0).build(ui, value);
+                }
+
+                ui.separator();
+                ui.text("Capture:");
+                Slider::new(im_str!("Capture scale"))
+                    .range(1..=Capture::MAX_SCALE)
+                    .build(ui, &mut capture.scale);
+                if ui.button(im_str!("Screenshot (F12)"), [0.0, 0.0]) {
+                    screenshot = true;
+                }
+                ui.same_line(0.0);
+                let label = if recording {
+                    im_str!("Stop (Shift+F12)")
+                } else {
+                    im_str!("Record (Shift+F12)")
+                };
+                if ui.button(label, [0.0, 0.0]) {
+                    record = true;
+                }
+                if let Some(message) = message {
+                    ui.text_wrapped(&ImString::new(message));
+                }
+            });
+        if screenshot {
+            self.screenshot();
+        }
+        if record && recording {
+            self.stop_recording();
+        } else if record {
+            self.start_recording();
+        }
+    }
+
+    /// The screen in a window of its own, when it is docked.
+    fn show_screen(
+        &mut self,
+        ui: &Ui,
+        display: &Display,
+        renderer: &mut Renderer,
+        screen: &mut Screen,
+        planes: Texture2d,
+    ) {
+        let config = &self.options.config;
+        let docked = &mut self.docked_screen;
+        Window::new(im_str!("Screen"))
+            .size([520.0, 300.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let [width, height] = ui.content_region_avail();
+                let size = [width.max(1.0) as u32, height.max(1.0) as u32];
+                let (id, texture) = match docked {
+                    Some((id, texture)) if texture.dimensions() == (size[0], size[1]) => {
+                        (*id, Rc::clone(texture))
+                    }
+                    _ => {
+                        let texture = Rc::new(Texture2d::empty(display, size[0], size[1]).unwrap());
+                        let entry = imgui_glium_renderer::Texture {
+                            texture: Rc::clone(&texture),
+                            sampler: SamplerBehavior {
+                                magnify_filter: MagnifySamplerFilter::Nearest,
+                                ..Default::default()
+                            },
+                        };
+                        let id = match docked.take() {
+                            Some((id, _)) => {
+                                renderer.textures().replace(id, entry);
+                                id
+                            }
+                            None => renderer.textures().insert(entry),
+                        };
+                        *docked = Some((id, Rc::clone(&texture)));
+                        (id, texture)
+                    }
+                };
+                let viewport = config.effects.viewport(size, effects::RESOLUTION);
+                screen.draw(
+                    &mut texture.as_surface(),
+                    viewport,
+                    planes,
+                    &config.palette,
+                    &config.effects,
+                );
+                Image::new(id, [width, height])
+                    .uv0([0.0, 1.0])
+                    .uv1([1.0, 0.0])
+                    .build(ui);
+            });
+    }
 
-        110 => {
-            vertex: "
-                #version 110
+    /// The keymap edited in the Keys window: the running ROM's, or the
+    /// layout of all ROMs.
+    fn edited_keymap(&self) -> Keymap {
+        if self.remap_rom {
+            return self.keymap.clone();
+        }
+        let keys = self.options.config.keys.as_deref();
+        keys.and_then(|keys| Keymap::parse(keys).ok())
+            .unwrap_or_default()
+    }
 
-                uniform mat4 matrix;
+    /// Stores `keymap`, or forgets the edited one when `None`, and applies
+    /// the result to the running ROM.
+    fn save_keymap(&mut self, keymap: Option<Keymap>) {
+        let keys = keymap.map(|keymap| keymap.to_string());
+        let config = &mut self.options.config;
+        if self.remap_rom {
+            config.roms.entry(self.sha1.clone()).or_default().keys = keys;
+            config.roms.retain(|_, rom| *rom != RomConfig::default());
+        } else {
+            config.keys = keys;
+        }
+        let mut settings = self.detected.clone();
+        self.options.configure(&mut settings, &self.sha1);
+        self.set_keymap(settings.keymap);
+    }
 
-                attribute vec2 position;
-                attribute vec2 tex_coords;
+    fn show_keys(&mut self, ui: &Ui) {
+        let mut keymap = self.edited_keymap();
+        let mut changed = false;
+        let mut reset = false;
+        Window::new(im_str!("Keys"))
+            .size([300.0, 400.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.radio_button(im_str!("All ROMs"), &mut self.remap_rom, false);
+                ui.same_line(0.0);
+                ui.radio_button(im_str!("This ROM"), &mut self.remap_rom, true);
+                for row in KEYPAD.iter() {
+                    for (column, key) in row.iter().enumerate() {
+                        if column > 0 {
+                            ui.same_line(0.0);
+                        }
+                        let hosts = keymap.hosts(*key).join(" ");
+                        let label = ImString::new(format!("{:X}\n{}##remap{}", key, hosts, key));
+                        let color = if *key == self.remap_key {
+                            Some(ui.push_style_color(StyleColor::Button, [0.3, 0.5, 0.8, 1.0]))
+                        } else {
+                            None
+                        };
+                        if ui.button(&label, [64.0, 40.0]) {
+                            self.remap_key = *key;
+                            self.capturing = false;
+                        }
+                        if let Some(color) = color {
+                            color.pop(ui);
+                        }
+                    }
+                }
 
-                varying vec2 v_tex_coords;
+                ui.separator();
+                let key = self.remap_key;
+                ui.text(format!("Key {:X}:", key));
+                let hosts: Vec<String> = keymap.hosts(key).iter().map(|h| h.to_string()).collect();
+                for host in hosts.iter() {
+                    if ui.small_button(&ImString::new(format!("x##{}", host))) {
+                        keymap.unbind(host);
+                        changed = true;
+                        continue;
+                    }
+                    ui.same_line(0.0);
+                    ui.text(host);
+                    ui.same_line(120.0);
+                    let mut turbo = keymap.binding(host).is_some_and(|b| b.turbo);
+                    if ui.checkbox(&ImString::new(format!("Autofire##{}", host)), &mut turbo) {
+                        keymap.set_turbo(host, turbo);
+                        changed = true;
+                    }
+                }
+                if self.capturing {
+                    ui.text("Press a key to bind, Escape to cancel");
+                    if ui.is_key_pressed(VirtualKeyCode::Escape as u32) {
+                        self.capturing = false;
+                    } else if let Some(code) = HOST_KEYS
+                        .iter()
+                        .find(|code| ui.is_key_pressed(**code as u32))
+                    {
+                        keymap.bind(&format!("{:?}", code), key);
+                        changed = true;
+                        self.capturing = false;
+                    }
+                } else if ui.button(im_str!("Add host key"), [0.0, 0.0]) {
+                    self.capturing = true;
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Reset"), [0.0, 0.0]) {
+                    reset = true;
+                }
+            });
+        if reset {
+            self.save_keymap(None);
+        } else if changed {
+            self.save_keymap(Some(keymap));
+        }
+    }
 
-                void main() {
-                    gl_Position = matrix * vec4(position, 0.0, 1.0);
-                    v_tex_coords = tex_coords;
+    /// Saves the settings, with the window layout, if there is somewhere to
+    /// save them.
+    fn save_config(&mut self, layout: String) {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => return,
+        };
+        self.options.config.layout = Some(layout);
+        if let Err(e) = self.options.config.save(path) {
+            eprintln!("Saving settings to {} failed: {}", path.display(), e);
+        }
+    }
+
+    /// Loads the running ROM again from disk, keeping the breakpoints.
+    fn reload(&mut self) {
+        let breakpoints = self.debugger.breakpoints().clone();
+        let paused = self.debugger.paused;
+        let rom = self.rom.clone();
+        self.browser_message = Some(match self.load(&rom) {
+            Ok(()) => {
+                for addr in breakpoints {
+                    let _ = self.debugger.add_breakpoint(&format!("{:#x}", addr));
                 }
-            ",
+                self.debugger.paused = paused;
+                format!("Reloaded {}", rom.display())
+            }
+            Err(e) => format!("Reloading {} failed: {}", rom.display(), e),
+        });
+    }
 
-            fragment: "
-                #version 110
-                uniform sampler2D tex;
-                varying vec2 v_tex_coords;
+    /// Reloads the ROM when one of its files changed, in watch mode.
+    fn poll_watch(&mut self) {
+        if self.last_poll.elapsed() < WATCH_INTERVAL {
+            return;
+        }
+        self.last_poll = Instant::now();
+        if self.watch.as_mut().is_some_and(Watch::changed) {
+            self.reload();
+        }
+    }
 
-                void main() {
-                    gl_FragColor = texture2D(tex, v_tex_coords);
+    /// Loads a ROM dropped on the window. Archives are added to the
+    /// library instead.
+    fn drop_files(&mut self, files: &[PathBuf]) {
+        for path in files {
+            if archive::is_archive(path) {
+                if !self.library.dirs.contains(path) {
+                    self.library.dirs.push(path.clone());
                 }
-            ",
-        },
+                self.library.scan();
+                self.browser_message = Some(format!("Added {} to the library", path.display()));
+                continue;
+            }
+            self.browser_message = match self.load(path) {
+                Ok(()) => None,
+                Err(e) => Some(format!("Loading {} failed: {}", path.display(), e)),
+            };
+        }
+    }
 
-        100 => {
-            vertex: "
-                #version 100
+    fn hotkeys(&mut self, ui: &Ui) {
+        if ui.is_key_pressed(VirtualKeyCode::F5 as u32) {
+            self.reload();
+        }
+        if ui.is_key_pressed(VirtualKeyCode::F12 as u32) {
+            if !ui.io().key_shift {
+                self.screenshot();
+            } else if self.recording.is_some() {
+                self.stop_recording();
+            } else {
+                self.start_recording();
+            }
+        }
+    }
 
-                uniform lowp mat4 matrix;
+    /// Runs `rom` without a window and keeps its screen as a texture.
+    fn thumbnail(
+        &mut self,
+        rom: &library::Rom,
+        display: &Display,
+        renderer: &mut Renderer,
+    ) -> io::Result<TextureId> {
+        let palette = &self.options.config.palette;
+        if self.thumbnail_palette != *palette {
+            for (_, id) in self.thumbnails.drain() {
+                renderer.textures().remove(id);
+            }
+            self.thumbnail_palette = palette.clone();
+        }
+        if let Some(id) = self.thumbnails.get(&rom.sha1) {
+            return Ok(*id);
+        }
+        let data = archive::read(&rom.path, asm::MAX_SIZE)?;
+        let settings = Settings::detect(&data, self.library.database());
+        let screen = library::thumbnail(&data, &settings);
+        let texture = imgui_glium_renderer::Texture {
+            texture: Rc::new(palette_texture(
+                &screen,
+                &self.thumbnail_palette,
+                display.get_context(),
+            )),
+            sampler: SamplerBehavior {
+                magnify_filter: MagnifySamplerFilter::Nearest,
+                ..Default::default()
+            },
+        };
+        let id = renderer.textures().insert(texture);
+        self.thumbnails.insert(rom.sha1.clone(), id);
+        Ok(id)
+    }
 
-                attribute lowp vec2 position;
-                attribute lowp vec2 tex_coords;
+    fn show_browser(&mut self, ui: &Ui, display: &Display, renderer: &mut Renderer) {
+        let mut load = None;
+        let mut selected = None;
+        let mut reload = false;
+        Window::new(im_str!("ROMs"))
+            .size([520.0, 480.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.text(format!("Running {}", self.rom.display()));
+                if ui.button(im_str!("Reload (F5)"), [0.0, 0.0]) {
+                    reload = true;
+                }
+                ui.same_line(0.0);
+                let mut watching = self.watch.is_some();
+                if ui.checkbox(im_str!("Reload on change"), &mut watching) {
+                    self.watch = if watching {
+                        let files = watch::sources(&self.rom, self.debugger.map());
+                        Some(Watch::new(files))
+                    } else {
+                        None
+                    };
+                }
+                if let Some(watch) = &self.watch {
+                    for file in watch.files() {
+                        ui.text(format!("  watching {}", file.display()));
+                    }
+                }
+                if CollapsingHeader::new(im_str!("Recent")).build(ui) {
+                    for path in self.options.config.recent.iter() {
+                        let label = ImString::new(path.display().to_string());
+                        if Selectable::new(&label).build(ui) {
+                            load = Some(path.clone());
+                        }
+                    }
+                }
+                ui.separator();
+                ui.input_text(im_str!("Search"), &mut self.search).build();
+                ui.same_line(0.0);
+                if ui.button(im_str!("Rescan"), [0.0, 0.0]) {
+                    self.library.scan();
+                }
+                if let Some(message) = &self.browser_message {
+                    ui.text(message.as_str());
+                }
+                let search = self.search.to_str().to_lowercase();
+                ui.columns(2, im_str!("browser"), true);
+                ChildWindow::new("roms").build(ui, || {
+                    for rom in self.library.roms.iter() {
+                        if !matches(rom, &search) {
+                            continue;
+                        }
+                        let label =
+                            ImString::new(format!("{}##{}", rom.title(), rom.path.display()));
+                        let is_selected = self.selected.as_ref() == Some(&rom.sha1);
+                        if Selectable::new(&label)
+                            .selected(is_selected)
+                            .allow_double_click(true)
+                            .build(ui)
+                        {
+                            selected = Some(rom.sha1.clone());
+                            if ui.is_mouse_double_clicked(MouseButton::Left) {
+                                load = Some(rom.path.clone());
+                            }
+                        }
+                    }
+                });
+                ui.next_column();
+                let rom = self
+                    .selected
+                    .as_ref()
+                    .and_then(|sha1| self.library.find(sha1))
+                    .cloned();
+                if let Some(rom) = rom {
+                    match self.thumbnail(&rom, display, renderer) {
+                        Ok(id) => Image::new(id, [256.0, 128.0])
+                            .uv0([0.0, 1.0])
+                            .uv1([1.0, 0.0])
+                            .build(ui),
+                        Err(e) => ui.text(format!("No thumbnail: {}", e)),
+                    }
+                    ui.text(rom.title());
+                    if let Some(metadata) = &rom.metadata {
+                        if let Some(author) = &metadata.author {
+                            ui.text(format!("by {}", author));
+                        }
+                        ui.text(metadata.platform.name());
+                        if let Some(keys) = &metadata.keys {
+                            ui.text(format!("Keys: {}", keys));
+                        }
+                    }
+                    ui.text(format!("{} bytes", rom.size));
+                    ui.text(format!("{}", rom.path.display()));
+                    ui.text(format!("SHA-1 {}", rom.sha1));
+                    if let Some(description) =
+                        rom.metadata.as_ref().and_then(|m| m.description.as_ref())
+                    {
+                        ui.separator();
+                        ui.text_wrapped(&ImString::new(description.as_str()));
+                    }
+                    if ui.button(im_str!("Load"), [0.0, 0.0]) {
+                        load = Some(rom.path.clone());
+                    }
+                }
+                ui.columns(1, im_str!("browser"), false);
+            });
+        if selected.is_some() {
+            self.selected = selected;
+        }
+        if reload {
+            self.reload();
+        }
+        if let Some(path) = load {
+            self.browser_message = match self.load(&path) {
+                Ok(()) => None,
+                Err(e) => Some(format!("Loading {} failed: {}", path.display(), e)),
+            };
+        }
+    }
 
-                varying lowp vec2 v_tex_coords;
+    /// Presses the CHIP-8 keys whose host keys are held down, unless the
+    /// UI is taking text input.
+    fn read_keys(&mut self, ui: &Ui) {
+        let io = ui.io();
+        let mut pressed = [false; 16];
+        let turbo_on = (self.frames / TURBO_FRAMES).is_multiple_of(2);
+        self.frames += 1;
+        if !io.want_text_input && !self.capturing {
+            for (code, key, turbo) in self.keys.iter() {
+                pressed[*key as usize] |= io.keys_down[*code as usize] && (turbo_on || !turbo);
+            }
+        }
+        for (key, pressed) in pressed.iter().enumerate() {
+            self.machine
+                .set_key(key as u8, *pressed || self.mouse_keys[key]);
+        }
+    }
 
-                void main() {
-                    gl_Position = matrix * vec4(position, 0.0, 1.0);
-                    v_tex_coords = tex_coords;
+    /// The hex keypad, lit where keys are held down. Holding the mouse on a
+    /// key presses it.
+    fn show_keypad(&mut self, ui: &Ui) {
+        let machine = &self.machine;
+        let mouse_keys = &mut self.mouse_keys;
+        *mouse_keys = [false; 16];
+        Window::new(im_str!("Keypad"))
+            .size([240.0, 280.0], Condition::FirstUseEver)
+            .build(ui, || {
+                for row in KEYPAD.iter() {
+                    for (column, key) in row.iter().enumerate() {
+                        if column > 0 {
+                            ui.same_line(0.0);
+                        }
+                        let color = if machine.key(*key) {
+                            Some(ui.push_style_color(StyleColor::Button, [0.9, 0.6, 0.2, 1.0]))
+                        } else {
+                            None
+                        };
+                        ui.button(&ImString::new(format!("{:X}", key)), [48.0, 48.0]);
+                        mouse_keys[*key as usize] = ui.is_item_active();
+                        if let Some(color) = color {
+                            color.pop(ui);
+                        }
+                    }
+                }
+                if let Some((opcode, key)) = tested_key(machine) {
+                    ui.text(format!(
+                        "{} tests key {:X}",
+                        Syntax::Chipper.format(opcode),
+                        key
+                    ));
                 }
-            ",
+            });
+    }
 
-            fragment: "
-                #version 100
-                uniform lowp sampler2D tex;
-                varying lowp vec2 v_tex_coords;
+    /// Runs the instructions due since the previous frame.
+    fn advance(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_frame);
+        self.last_frame = now;
+        let before = self.machine.cycles();
+        if self.machine.stop || self.debugger.paused {
+            self.pacer.reset();
+        } else if self.pacer.fast_forward {
+            // Leave some of the frame for drawing.
+            let deadline = now + Duration::from_millis(12);
+            while !self.machine.stop && !self.debugger.paused && Instant::now() < deadline {
+                self.debugger.run(&mut self.machine, 1000);
+            }
+        } else {
+            let due = self.pacer.due(elapsed);
+            self.debugger.run(&mut self.machine, due);
+        }
+        self.pacer.frame(now, self.machine.cycles() - before);
+        if let Some(recording) = &mut self.recording {
+            if !recording.push(&self.machine.video_mem, elapsed) {
+                self.stop_recording();
+            }
+        }
+    }
+
+    /// Saves the screen to a new PNG file.
+    fn screenshot(&mut self) {
+        let capture = &self.options.config.capture;
+        let path = capture.path(&self.rom, "png");
+        let result = capture::screenshot(
+            &path,
+            &self.machine.video_mem,
+            &self.options.config.palette,
+            capture.scale(),
+        );
+        self.capture_message = Some(match result {
+            Ok(()) => format!("Saved {}", path.display()),
+            Err(e) => format!("Saving {} failed: {}", path.display(), e),
+        });
+    }
+
+    fn start_recording(&mut self) {
+        self.recording = Some(Recording::new());
+        self.capture_message = Some("Recording...".to_string());
+    }
 
-                void main() {
-                    gl_FragColor = texture2D(tex, v_tex_coords);
+    /// Saves the recording, if any, to a new file.
+    fn stop_recording(&mut self) {
+        let recording = match self.recording.take() {
+            Some(recording) => recording,
+            None => return,
+        };
+        if recording.is_empty() {
+            self.capture_message = None;
+            return;
+        }
+        let capture = &self.options.config.capture;
+        let path = capture.path(&self.rom, "gif");
+        let result = recording.save(&path, &self.options.config.palette, capture.scale());
+        self.capture_message = Some(match result {
+            Ok(()) => format!(
+                "Saved {} ({:.1}s)",
+                path.display(),
+                recording.duration().as_secs_f64()
+            ),
+            Err(e) => format!("Saving {} failed: {}", path.display(), e),
+        });
+    }
+
+    fn show_speed(&mut self, ui: &Ui) {
+        let machine = &mut self.machine;
+        let debugger = &mut self.debugger;
+        let pacer = &mut self.pacer;
+        let roms = &mut self.options.config.roms;
+        let sha1 = &self.sha1;
+        Window::new(im_str!("Speed"))
+            .size([300.0, 200.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let label = if debugger.paused {
+                    im_str!("Resume")
+                } else {
+                    im_str!("Pause")
+                };
+                if ui.button(label, [0.0, 0.0]) {
+                    debugger.paused = !debugger.paused;
                 }
-            ",
-        },
-    )
-    .unwrap();
-    system.main_loop(move |_, ui, display, renderer, target| {
-        if !my_app.machine.stop {
-            my_app.machine.cycle();
-        }
-
-        let opengl_texture = generate_texture(&my_app.machine, display.get_context());
-        // building the uniforms
-        let uniforms = uniform! {
-            matrix: [
-                [1.0, 0.0, 0.0, 0.0],
-                [0.0, 1.0, 0.0, 0.0],
-                [0.0, 0.0, 1.0, 0.0],
-                [0.0, 0.0, 0.0, 1.0f32]
-            ],
-            tex:
-            glium::uniforms::Sampler::new(&opengl_texture)
-                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                ui.same_line(0.0);
+                if ui.button(im_str!("Advance frame"), [0.0, 0.0]) {
+                    debugger.paused = true;
+                    debugger.run(machine, pacer.ipf() as usize);
+                }
+
+                let mut ips = pacer.ips as i32;
+                if ui.input_int(im_str!("Instructions/s"), &mut ips).build() {
+                    pacer.ips = ips.max(1) as u32;
+                }
+                let mut ipf = pacer.ipf() as i32;
+                if ui
+                    .input_int(im_str!("Instructions/frame"), &mut ipf)
+                    .build()
+                {
+                    pacer.set_ipf(ipf.max(1) as u32);
+                }
+
+                ui.checkbox(im_str!("Fast-forward"), &mut pacer.fast_forward);
+                for speed in pacing::SPEEDS.iter() {
+                    ui.same_line(0.0);
+                    let label = ImString::new(format!("{}x", speed));
+                    if ui.radio_button_bool(&label, pacer.speed == *speed) {
+                        pacer.speed = *speed;
+                    }
+                }
+
+                ui.text(format!(
+                    "{:.0} FPS, {:.0} instructions/s",
+                    pacer.fps(),
+                    pacer.measured_ips()
+                ));
+
+                ui.separator();
+                if ui.button(im_str!("Remember for this ROM"), [0.0, 0.0]) {
+                    let rom = roms.entry(sha1.clone()).or_default();
+                    rom.ips = Some(pacer.ips);
+                    rom.quirks = machine.quirks().name().map(String::from);
+                }
+                if roms.contains_key(sha1) {
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Forget"), [0.0, 0.0]) {
+                        roms.remove(sha1);
+                    }
+                }
+            });
+    }
+
+    fn show_memory(&mut self, ui: &Ui) {
+        let machine = &mut self.machine;
+        let debugger = &self.debugger;
+        let rom = &self.rom;
+        let capture = &self.options.config.capture;
+        let message = &mut self.coverage_message;
+        Window::new(im_str!("Memory"))
+            .size([520.0, 400.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let range = 0x200..0x200 + machine.program_size();
+                if let Some(coverage) = machine.coverage() {
+                    let summary = coverage.summary(range.clone());
+                    ui.text(format!(
+                        "Coverage {:.1}%: {} code, {} data, {} untouched",
+                        summary.percent(),
+                        summary.code,
+                        summary.data,
+                        summary.untouched
+                    ));
+                    if ui.button(im_str!("Export LCOV"), [0.0, 0.0]) {
+                        let path = capture.path(rom, "info");
+                        let result = fs::File::create(&path).and_then(|mut file| {
+                            coverage.write_lcov(&mut file, rom, debugger.map(), range.clone())
+                        });
+                        *message = Some(match result {
+                            Ok(()) => format!("Wrote {}", path.display()),
+                            Err(e) => format!("Export failed: {}", e),
+                        });
+                    }
+                    ui.same_line(0.0);
+                }
+                if ui.button(im_str!("Reset coverage"), [0.0, 0.0]) {
+                    machine.set_coverage(Some(Coverage::new()));
+                }
+                if let Some(message) = message {
+                    ui.text(message.as_str());
+                }
+                ui.separator();
+                let coverage = machine.coverage();
+                let memory = machine.memory();
+                for row in range.step_by(16) {
+                    ui.text(format!("{:03X}:", row));
+                    let end = (row + 16).min(memory.len());
+                    for (addr, byte) in (row..end).zip(memory[row..end].iter()) {
+                        ui.same_line(0.0);
+                        let color = coverage_color(coverage, addr as u16);
+                        ui.text_colored(color, format!("{:02X}", byte));
+                    }
+                }
+            });
+    }
+
+    fn show_profiler(&mut self, ui: &Ui) {
+        let machine = &mut self.machine;
+        let debugger = &self.debugger;
+        let rom = &self.rom;
+        let capture = &self.options.config.capture;
+        let message = &mut self.profile_message;
+        Window::new(im_str!("Profiler"))
+            .size([400.0, 500.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let mut enabled = machine.profiler().is_some();
+                if ui.checkbox(im_str!("Enabled"), &mut enabled) {
+                    machine.set_profiler(if enabled { Some(Profiler::new()) } else { None });
+                }
+                let profiler = match machine.profiler_mut() {
+                    Some(profiler) => profiler,
+                    None => return,
+                };
+                ui.same_line(0.0);
+                if ui.button(im_str!("Reset"), [0.0, 0.0]) {
+                    profiler.reset();
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Export"), [0.0, 0.0]) {
+                    let paths = capture.paths(rom, &["profile.txt", "profile.csv"]);
+                    *message = Some(match export_profile(profiler, debugger, &paths) {
+                        Ok(()) => {
+                            format!("Wrote {} and {}", paths[0].display(), paths[1].display())
+                        }
+                        Err(e) => format!("Export failed: {}", e),
+                    });
+                }
+                if let Some(message) = message {
+                    ui.text(message.as_str());
+                }
+                ui.text(format!("{} instructions", profiler.total()));
+
+                ui.separator();
+                ui.text("Hotspots:");
+                let hotspots = profiler.hotspots(16);
+                let busiest = hotspots.first().map_or(1, |spot| spot.count) as f32;
+                for spot in hotspots {
+                    let overlay = ImString::new(format!(
+                        "{:03X} {:<16} {:5.1}%  {}",
+                        spot.addr,
+                        Syntax::Chipper.format(spot.opcode),
+                        profiler.percent(spot.count),
+                        debugger.symbolize(spot.addr)
+                    ));
+                    ProgressBar::new(spot.count as f32 / busiest)
+                        .overlay_text(&overlay)
+                        .build(ui);
+                }
+
+                ui.separator();
+                ui.text("Subroutines (calls, total, own):");
+                for (addr, sub) in profiler.subroutines().iter().take(10) {
+                    ui.text(format!(
+                        "  {:<16} {:6} {:8} {:8}",
+                        debugger.symbolize(*addr),
+                        sub.calls,
+                        sub.total,
+                        sub.own
+                    ));
+                }
+            });
+    }
+}
+
+/// The opcode at `pc`, unless it runs past the end of memory.
+fn fetch(machine: &Machine, pc: u16) -> Option<u16> {
+    let memory = machine.memory();
+    let pc = pc as usize;
+    Some((*memory.get(pc)? as u16) << 8 | *memory.get(pc + 1)? as u16)
+}
+
+/// The instruction at the PC and the key it checks, if it is EX9E or EXA1.
+fn tested_key(machine: &Machine) -> Option<(u16, u8)> {
+    let opcode = fetch(machine, machine.pc())?;
+    match opcode & 0xF0FF {
+        0xE09E | 0xE0A1 => {
+            let x = (opcode >> 8 & 0xF) as usize;
+            Some((opcode, machine.registers()[x] & 0xF))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `rom` matches a lowercase search for its title, file name or
+/// author.
+fn matches(rom: &library::Rom, search: &str) -> bool {
+    let author = rom.metadata.as_ref().and_then(|m| m.author.as_ref());
+    let fields = [
+        Some(rom.title()),
+        Some(rom.path.display().to_string()),
+        author.cloned(),
+    ];
+    fields
+        .iter()
+        .flatten()
+        .any(|field| field.to_lowercase().contains(search))
+}
+
+/// Writes the profile as text and CSV to `paths`.
+fn export_profile(profiler: &Profiler, debugger: &Debugger, paths: &[PathBuf]) -> io::Result<()> {
+    let name = |addr| debugger.symbolize(addr);
+    profiler.write_text(&mut fs::File::create(&paths[0])?, 64, &name)?;
+    profiler.write_csv(&mut fs::File::create(&paths[1])?, &name)
+}
+
+/// ROMs may be given as `ARCHIVE.zip:PATH/NAME` to load them from a ZIP archive.
+/// `run` reads and saves its settings in `--config`, by default
+/// `~/.config/chip8/settings.toml`.
+const USAGE: &str = "\
+usage: chip8 [run] [ROM] [--profile NAME] [--ips N] [--scale N] [--seed N]
+                         [--library DIR] [--watch] [--config FILE] [--syntax NAME]
+                         [--break SPEC] [--trace FILE] [--trace-range RANGE]
+                         [--trace-writes] [--gdb PORT] [--dap | --dap-port PORT]
+       chip8 disasm ROM [--syntax NAME]
+       chip8 asm SOURCE -o ROM
+       chip8 info (ROM | ARCHIVE.zip)
+       chip8 trace ROM [--frames N] [--profile NAME] [--ips N] [--seed N] [-o FILE]
+                   [--trace-range RANGE] [--trace-writes] [--coverage FILE]
+       chip8 capture ROM -o (FILE.png | FILE.gif) [--frames N] [--start N]
+                     [--scale N] [--palette NAME] [--profile NAME] [--ips N] [--seed N]
+       chip8 compare A.trace (B.trace | --rom ROM [--profile NAME] [--seed N])";
+
+/// Rejects ROMs too large to be loaded, which can't be disassembled.
+fn fits(path: &Path, rom: &[u8]) -> io::Result<()> {
+    if rom.len() > asm::MAX_SIZE {
+        let fault = Fault::ProgramTooLarge(rom.len());
+        return Err(invalid(format!("{}: {}", path.display(), fault)));
+    }
+    Ok(())
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+/// Parses the value following `flag`.
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> io::Result<T> {
+    let value = value.ok_or_else(|| invalid(format!("{} needs a value", flag)))?;
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid value '{}' for {}", value, flag)))
+}
+
+/// Settings of the machine that several subcommands accept. Those not
+/// given come from the user's settings and the ROM database.
+struct MachineOptions {
+    quirks: Option<Quirks>,
+    ips: Option<u32>,
+    seed: Option<u64>,
+    config: Config,
+}
+
+impl MachineOptions {
+    fn new() -> MachineOptions {
+        MachineOptions {
+            quirks: None,
+            ips: None,
+            seed: None,
+            config: Config::default(),
+        }
+    }
+
+    /// Takes the value of `flag` if it is one of ours.
+    fn parse(&mut self, flag: &str, args: &mut impl Iterator<Item = String>) -> io::Result<bool> {
+        match flag {
+            "--profile" => {
+                let name: String = value(flag, args.next())?;
+                self.quirks = Some(Quirks::from_name(&name).map_err(invalid)?);
+            }
+            "--ips" => self.ips = Some(value(flag, args.next())?),
+            "--seed" => self.seed = Some(value(flag, args.next())?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn settings(&self, rom: &[u8]) -> Settings {
+        let mut settings = Settings::detect(rom, &Database::bundled());
+        self.configure(&mut settings, &library::sha1(rom));
+        settings
+    }
+
+    /// Applies the user's settings and then the flags to the detected
+    /// settings of the ROM with hash `sha1`.
+    fn configure(&self, settings: &mut Settings, sha1: &str) {
+        self.config.apply(settings, sha1);
+        if let Some(quirks) = self.quirks {
+            settings.quirks = quirks;
+        }
+        if let Some(ips) = self.ips {
+            settings.ips = ips;
+        }
+    }
+
+    fn machine(&self, rom: &[u8]) -> Machine {
+        let mut machine = Machine::new(rom);
+        self.settings(rom).apply(&mut machine);
+        if let Some(seed) = self.seed {
+            machine.set_seed(seed);
+        }
+        machine
+    }
+}
+
+/// Takes the single positional argument of a subcommand.
+fn positional(path: &mut Option<PathBuf>, arg: String) -> io::Result<()> {
+    if arg.starts_with('-') || path.is_some() {
+        return Err(invalid(format!("unknown argument '{}'", arg)));
+    }
+    *path = Some(PathBuf::from(arg));
+    Ok(())
+}
+
+/// `chip8 disasm ROM`: prints a listing that assembles back into the ROM.
+fn disassemble(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut syntax = Syntax::Chipper;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                syntax = value::<String>(&arg, args.next())?
+                    .parse()
+                    .map_err(invalid)?
+            }
+            _ => positional(&mut path, arg)?,
+        }
+    }
+    let path = path.ok_or_else(|| invalid(USAGE))?;
+    let (rom, _) = asm::load(&path)?;
+    fits(&path, &rom)?;
+    print!("{}", disasm::listing(&rom, syntax));
+    Ok(())
+}
+
+/// `chip8 asm SOURCE -o ROM`: writes the ROM and its debug map.
+fn assemble(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut source = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(value::<PathBuf>(&arg, args.next())?),
+            _ => positional(&mut source, arg)?,
+        }
+    }
+    let (source, output) = match (source, output) {
+        (Some(source), Some(output)) => (source, output),
+        _ => return Err(invalid(USAGE)),
+    };
+    if !asm::is_source(&source) {
+        let message = format!("{} is not an assembler source", source.display());
+        return Err(invalid(message));
+    }
+    let (rom, map) = asm::load(&source)?;
+    fs::write(&output, &rom)?;
+    if let Some(map) = map {
+        map.save(&output)?;
+    }
+    Ok(())
+}
+
+/// `chip8 info ROM`: describes a ROM without running it. Given a ZIP
+/// archive, lists the ROMs inside instead.
+fn info(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut path = None;
+    if let Some(arg) = args.next() {
+        positional(&mut path, arg)?;
+    }
+    let path = path.ok_or_else(|| invalid(USAGE))?;
+    if archive::is_archive(&path) {
+        for rom in library::Rom::archived(&path, &Database::bundled())? {
+            println!("{:<48} {}", rom.path.display(), rom.title());
+        }
+        return Ok(());
+    }
+    let (rom, map) = asm::load(&path)?;
+    fits(&path, &rom)?;
+    println!("ROM:      {}", path.display());
+    println!(
+        "Size:     {} bytes ({:#05x}-{:#05x})",
+        rom.len(),
+        asm::PROGRAM_START,
+        asm::PROGRAM_START as usize + rom.len().max(1) - 1
+    );
+    let database = Database::bundled();
+    let settings = Settings::detect(&rom, &database);
+    if let Some(metadata) = database.get(&library::sha1(&rom)) {
+        println!("Title:    {}", metadata.title);
+        if let Some(author) = &metadata.author {
+            println!("Author:   {}", author);
+        }
+        if let Some(keys) = &metadata.keys {
+            println!("Keys:     {}", keys);
+        }
+    }
+    let guessed = if settings.known { "" } else { " (guessed)" };
+    println!("Platform: {}{}", settings.platform.name(), guessed);
+    let quirks = Quirks::PROFILES
+        .iter()
+        .find(|(_, quirks)| *quirks == settings.quirks)
+        .map_or("custom", |(name, _)| name);
+    println!("Quirks:   {}", quirks);
+    println!("Speed:    {} instructions/s", settings.ips);
+    println!(
+        "Code:     {} reachable instructions",
+        disasm::code_size(&rom)
+    );
+    if let Some(map) = map {
+        println!("Source:   {}", map.source.display());
+        println!("Labels:   {}", map.labels.len());
+    }
+    Ok(())
+}
+
+/// `chip8 trace ROM --frames N`: runs without a window, tracing every
+/// instruction. `--coverage FILE` also writes an LCOV tracefile.
+fn trace_rom(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut options = MachineOptions::new();
+    let mut frames: u64 = 60;
+    let mut path = None;
+    let mut output: Option<PathBuf> = None;
+    let mut range = None;
+    let mut writes = false;
+    let mut coverage: Option<PathBuf> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => frames = value(&arg, args.next())?,
+            "--trace-writes" => writes = true,
+            "--coverage" => coverage = Some(value(&arg, args.next())?),
+            "-o" => output = Some(value(&arg, args.next())?),
+            "--trace-range" => {
+                let text: String = value(&arg, args.next())?;
+                range = Some(trace::parse_range(&text).map_err(invalid)?);
+            }
+            _ if options.parse(&arg, &mut args)? => {}
+            _ => positional(&mut path, arg)?,
+        }
+    }
+    let path = path.ok_or_else(|| invalid(USAGE))?;
+    let (rom, map) = asm::load(&path)?;
+    let output: Box<dyn io::Write> = match output {
+        Some(output) => Box::new(LineWriter::new(fs::File::create(output)?)),
+        None => Box::new(io::stdout()),
+    };
+    let mut machine = options.machine(&rom);
+    let tracer = match range {
+        Some(range) => Tracer::with_range(output, range),
+        None => Tracer::new(output),
+    };
+    machine.set_tracer(Some(tracer.with_writes(writes)));
+    if coverage.is_some() {
+        machine.set_coverage(Some(Coverage::new()));
+    }
+    let instructions = frames * Pacer::new(options.settings(&rom).ips).ipf() as u64;
+    for _ in 0..instructions {
+        if machine.cycle() {
+            break;
+        }
+    }
+    if let Some(fault) = machine.fault() {
+        eprintln!("Stopped: {}", fault);
+    }
+    if let (Some(output), Some(coverage)) = (coverage, machine.coverage()) {
+        let range = 0x200..0x200 + machine.program_size();
+        let summary = coverage.summary(range.clone());
+        eprintln!(
+            "Coverage: {:.1}% ({} code, {} data, {} untouched)",
+            summary.percent(),
+            summary.code,
+            summary.data,
+            summary.untouched
+        );
+        let mut file = fs::File::create(output)?;
+        coverage.write_lcov(&mut file, &path, map.as_ref(), range)?;
+    }
+    Ok(())
+}
+
+/// `chip8 capture ROM -o FILE --frames N`: runs without a window and saves
+/// the last screen as a PNG file, or the frames from `--start` on as a GIF
+/// recording. Like `trace`, it leaves the user's settings alone.
+fn capture_rom(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut options = MachineOptions::new();
+    let mut frames: u32 = 60;
+    let mut start: u32 = 0;
+    let mut scale = None;
+    let mut palette = None;
+    let mut path = None;
+    let mut output: Option<PathBuf> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => frames = value(&arg, args.next())?,
+            "--start" => start = value(&arg, args.next())?,
+            "--scale" => scale = Some(value(&arg, args.next())?),
+            "--palette" => {
+                let name: String = value(&arg, args.next())?;
+                let preset = Palette::preset(&name);
+                palette =
+                    Some(preset.ok_or_else(|| invalid(format!("unknown palette '{}'", name)))?);
+            }
+            "-o" => output = Some(value(&arg, args.next())?),
+            _ if options.parse(&arg, &mut args)? => {}
+            _ => positional(&mut path, arg)?,
+        }
+    }
+    let path = path.ok_or_else(|| invalid(USAGE))?;
+    let output = output.ok_or_else(|| invalid(USAGE))?;
+    let animated = capture::is_gif(&output);
+    if !animated && output.extension().is_none_or(|e| e != "png") {
+        return Err(invalid("captures are .png or .gif"));
+    }
+    let (rom, _) = asm::load(&path)?;
+    let palette = palette.unwrap_or_default();
+    let scale = scale.unwrap_or_else(|| Capture::default().scale());
+
+    let mut machine = options.machine(&rom);
+    let ipf = Pacer::new(options.settings(&rom).ips).ipf();
+    let mut recording = Recording::new();
+    'frames: for frame in 0..frames {
+        for _ in 0..ipf {
+            if machine.cycle() {
+                break 'frames;
+            }
+        }
+        if frame >= start {
+            recording.push(
+                &machine.video_mem,
+                Duration::from_secs(1) / pacing::FRAME_RATE,
+            );
+        }
+    }
+    if let Some(fault) = machine.fault() {
+        eprintln!("Stopped: {}", fault);
+    }
+    if animated {
+        recording.save(&output, &palette, scale)
+    } else {
+        capture::screenshot(&output, &machine.video_mem, &palette, scale)
+    }
+}
+
+/// `chip8 compare A.trace B.trace` or `chip8 compare A.trace --rom ROM`:
+/// reports the first point at which two traces disagree.
+fn compare(args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut traces = Vec::new();
+    let mut rom: Option<PathBuf> = None;
+    let mut options = MachineOptions::new();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rom" => rom = Some(value(&arg, args.next())?),
+            _ if options.parse(&arg, &mut args)? => {}
+            _ => traces.push(PathBuf::from(arg)),
+        }
+    }
+    let lines = |path: &PathBuf| -> io::Result<Box<dyn Iterator<Item = String>>> {
+        let file = io::BufReader::new(fs::File::open(path)?);
+        Ok(Box::new(file.lines().map_while(Result::ok)))
+    };
+    let (left, right) = match (traces.as_slice(), rom) {
+        ([left, right], None) => (lines(left)?, lines(right)?),
+        ([left], Some(rom)) => {
+            // The live run is only followed as far as the recorded trace goes.
+            let (buffer, _) = asm::load(&rom)?;
+            let recorded: Vec<String> = lines(left)?.collect();
+            // Writes are only compared if the recorded trace lists them.
+            let writes = recorded.iter().any(|line| line.contains(" | M "));
+            let live = trace::Live::new(options.machine(&buffer), writes).take(recorded.len());
+            let left: Box<dyn Iterator<Item = String>> = Box::new(recorded.into_iter());
+            (left, Box::new(live) as Box<dyn Iterator<Item = String>>)
+        }
+        _ => return Err(invalid(USAGE)),
+    };
+    match trace::compare(left, right) {
+        Ok(count) => {
+            println!("traces match ({} instructions)", count);
+            Ok(())
+        }
+        Err(divergence) => {
+            print!("{}", divergence);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("run") | Some("disasm") | Some("asm") | Some("info") | Some("trace")
+        | Some("capture") | Some("compare") => args.next(),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        _ => None,
+    };
+    match command.as_deref() {
+        Some("disasm") => disassemble(args),
+        Some("asm") => assemble(args),
+        Some("info") => info(args),
+        Some("trace") => trace_rom(args),
+        Some("capture") => capture_rom(args),
+        Some("compare") => compare(args),
+        _ => run(args),
+    }
+}
+
+/// `chip8 run`, the default: opens the ROM in the debugger UI.
+fn run(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut options = MachineOptions::new();
+    let mut scale: Option<u32> = None;
+    let mut syntax = Syntax::PseudoC;
+    let mut path: Option<PathBuf> = None;
+    let mut breakpoints: Vec<String> = Vec::new();
+    let mut dap_port: Option<String> = None;
+    let mut gdb_port: Option<String> = None;
+    let mut trace_path: Option<PathBuf> = None;
+    let mut trace_range = None;
+    let mut trace_writes = false;
+    let mut watch_files = false;
+    let mut dap_stdio = false;
+    let mut config_path = config::default_path();
+    let mut library_dirs: Vec<PathBuf> = library::BUNDLED_DIRS.iter().map(PathBuf::from).collect();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--watch" => watch_files = true,
+            "--config" => config_path = Some(value(&arg, args.next())?),
+            "--library" => library_dirs.push(value(&arg, args.next())?),
+            "--scale" => scale = Some(value(&arg, args.next())?),
+            "--syntax" => {
+                syntax = value::<String>(&arg, args.next())?
+                    .parse()
+                    .map_err(invalid)?
+            }
+            "--break" => breakpoints.push(value(&arg, args.next())?),
+            "--dap" => dap_stdio = true,
+            "--dap-port" => dap_port = Some(value(&arg, args.next())?),
+            "--gdb" => gdb_port = Some(value(&arg, args.next())?),
+            "--trace" => trace_path = Some(value(&arg, args.next())?),
+            "--trace-writes" => trace_writes = true,
+            "--trace-range" => {
+                let text: String = value(&arg, args.next())?;
+                trace_range = Some(trace::parse_range(&text).map_err(invalid)?);
+            }
+            _ if options.parse(&arg, &mut args)? => {}
+            _ => positional(&mut path, arg)?,
+        }
+    }
+
+    if let Some(path) = &config_path {
+        options.config = Config::load(path)?;
+    }
+    if dap_stdio {
+        let stdin = io::BufReader::new(io::stdin());
+        return dap::serve(stdin, io::stdout(), options.config);
+    }
+    if let Some(port) = dap_port {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let input = io::BufReader::new(stream.try_clone()?);
+        return dap::serve(input, stream, options.config);
+    }
+
+    let (buffer, map) = match &path {
+        Some(path) => asm::load(path)?,
+        None => (read_game("INVADERS")?, None),
+    };
+    let mut debugger = Debugger::new(map);
+    for spec in breakpoints.iter() {
+        debugger
+            .add_breakpoint(spec)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+
+    let detected = Settings::detect(&buffer, &Database::bundled());
+    let settings = options.settings(&buffer);
+    let mut machine = options.machine(&buffer);
+    if let Some(path) = trace_path {
+        let output = Box::new(LineWriter::new(fs::File::create(path)?));
+        let tracer = match trace_range {
+            Some(range) => Tracer::with_range(output, range),
+            None => Tracer::new(output),
         };
-        // let mut target = display.draw();
-        target
-            .draw(
-                &vertex_buffer,
-                &index_buffer,
-                &program,
-                &uniforms,
-                &Default::default(),
-            )
-            .unwrap();
-        // target.finish().unwrap();
-
-        my_app.show_textures(ui)
-    });
+        machine.set_tracer(Some(tracer.with_writes(trace_writes)));
+    }
+
+    if let Some(port) = gdb_port {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        return gdb::serve(stream.try_clone()?, stream, machine, debugger);
+    }
+
+    machine.set_coverage(Some(Coverage::new()));
+    if let Some(path) = &path {
+        options.config.add_recent(path);
+    }
+    let rom = path.unwrap_or_else(|| PathBuf::from("assets/games/INVADERS"));
+    let watch = if watch_files {
+        Some(Watch::new(watch::sources(&rom, debugger.map())))
+    } else {
+        None
+    };
+    let mut my_app = CustomTexturesApp {
+        machine,
+        rom,
+        sha1: library::sha1(&buffer),
+        syntax,
+        debugger,
+        breakpoint: ImString::with_capacity(64),
+        breakpoint_error: None,
+        profile_message: None,
+        coverage_message: None,
+        pacer: Pacer::new(settings.ips),
+        last_frame: Instant::now(),
+        detected,
+        keymap: settings.keymap.clone(),
+        keys: resolve_keys(&settings.keymap),
+        frames: 0,
+        remap_key: 0x5,
+        remap_rom: false,
+        capturing: false,
+        mouse_keys: [false; 16],
+        options,
+        library: Library::new(library_dirs),
+        search: ImString::with_capacity(64),
+        selected: None,
+        thumbnails: HashMap::new(),
+        thumbnail_palette: Palette::default(),
+        docked_screen: None,
+        browser_message: None,
+        recording: None,
+        capture_message: None,
+        watch,
+        last_poll: Instant::now(),
+        config_path,
+    };
+    my_app.library.scan();
+
+    let size = match scale.or(my_app.options.config.scale) {
+        Some(scale) => [64.0 * scale as f64, 32.0 * scale as f64],
+        None => [1024.0, 768.0],
+    };
+    let system = support::init(file!(), size, my_app.options.config.layout.as_deref());
+
+    let mut screen = Screen::new(&system.display);
+    let app = Rc::new(RefCell::new(my_app));
+    let exiting = Rc::clone(&app);
+    system.main_loop(
+        move |_, ui, display, renderer, target, dropped| {
+            let mut my_app = app.borrow_mut();
+            my_app.drop_files(dropped);
+            my_app.poll_watch();
+            my_app.hotkeys(ui);
+            my_app.read_keys(ui);
+            my_app.advance();
+
+            let opengl_texture = generate_texture(&my_app.machine, display.get_context());
+            if my_app.options.config.effects.docked {
+                target.clear_color(0.1, 0.1, 0.1, 1.0);
+                my_app.show_screen(ui, display, renderer, &mut screen, opengl_texture);
+            } else {
+                let config = &my_app.options.config;
+                let (width, height) = target.get_dimensions();
+                let viewport = config
+                    .effects
+                    .viewport([width, height], effects::RESOLUTION);
+                screen.draw(
+                    target,
+                    viewport,
+                    opengl_texture,
+                    &config.palette,
+                    &config.effects,
+                );
+            }
+
+            my_app.show_textures(ui);
+            my_app.show_debugger(ui);
+            my_app.show_profiler(ui);
+            my_app.show_memory(ui);
+            my_app.show_speed(ui);
+            my_app.show_browser(ui, display, renderer);
+            my_app.show_keys(ui);
+            my_app.show_keypad(ui);
+            my_app.show_display(ui);
+        },
+        move |layout| {
+            let mut app = exiting.borrow_mut();
+            app.stop_recording();
+            app.save_config(layout);
+        },
+    );
     Ok(())
 }
@@ -0,0 +1,136 @@
+//! Emulation speed, independent of the display's refresh rate.
+//!
+//! The UI asks a `Pacer` every frame how many instructions are due for the
+//! time that passed, and reports back how many actually ran so it can show
+//! the measured frame rate and speed.
+
+use std::time::{Duration, Instant};
+
+/// Frame rate the instructions-per-frame setting is based on.
+pub const FRAME_RATE: u32 = 60;
+
+/// Speed multipliers offered besides fast-forward.
+pub const SPEEDS: [f64; 4] = [0.25, 0.5, 1.0, 2.0];
+
+/// Longest stretch of time made up for at once, so a stalled window does
+/// not cause a burst of instructions afterwards.
+const MAX_CATCH_UP: Duration = Duration::from_millis(100);
+
+pub struct Pacer {
+    /// Instructions per second at normal speed.
+    pub ips: u32,
+    pub speed: f64,
+    /// Run as many instructions as fit in a frame instead.
+    pub fast_forward: bool,
+    budget: f64,
+    window: Option<Instant>,
+    frames: u32,
+    instructions: u64,
+    fps: f64,
+    measured_ips: f64,
+}
+
+impl Pacer {
+    pub fn new(ips: u32) -> Pacer {
+        Pacer {
+            ips,
+            speed: 1.0,
+            fast_forward: false,
+            budget: 0.0,
+            window: None,
+            frames: 0,
+            instructions: 0,
+            fps: 0.0,
+            measured_ips: 0.0,
+        }
+    }
+
+    /// Instructions per frame at `FRAME_RATE`.
+    pub fn ipf(&self) -> u32 {
+        ((self.ips + FRAME_RATE / 2) / FRAME_RATE).max(1)
+    }
+
+    pub fn set_ipf(&mut self, ipf: u32) {
+        self.ips = ipf.max(1) * FRAME_RATE;
+    }
+
+    /// Number of instructions due after `elapsed`. Fractions carry over to
+    /// the next call.
+    pub fn due(&mut self, elapsed: Duration) -> usize {
+        let elapsed = elapsed.min(MAX_CATCH_UP).as_secs_f64();
+        self.budget += elapsed * self.ips as f64 * self.speed;
+        let due = self.budget.floor();
+        self.budget -= due;
+        due as usize
+    }
+
+    /// Drops the fractional instruction owed, e.g. after pausing.
+    pub fn reset(&mut self) {
+        self.budget = 0.0;
+    }
+
+    /// Records a finished frame in which `instructions` ran. The readout is
+    /// updated once a second.
+    pub fn frame(&mut self, now: Instant, instructions: u64) {
+        let start = match self.window {
+            Some(start) => start,
+            None => {
+                self.window = Some(now);
+                return;
+            }
+        };
+        self.frames += 1;
+        self.instructions += instructions;
+        let elapsed = now.duration_since(start).as_secs_f64();
+        if elapsed >= 1.0 {
+            self.fps = self.frames as f64 / elapsed;
+            self.measured_ips = self.instructions as f64 / elapsed;
+            self.window = Some(now);
+            self.frames = 0;
+            self.instructions = 0;
+        }
+    }
+
+    /// Measured frames per second.
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    /// Measured instructions per second.
+    pub fn measured_ips(&self) -> f64 {
+        self.measured_ips
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let mut pacer = Pacer::new(700);
+        let frame = Duration::from_secs(1) / 60;
+        let total: usize = (0..60).map(|_| pacer.due(frame)).sum();
+        assert!((699..=700).contains(&total));
+        assert_eq!(pacer.ipf(), 12);
+
+        pacer.speed = 0.25;
+        pacer.reset();
+        assert_eq!(pacer.due(Duration::from_secs(1) / 10), 17);
+        assert_eq!(pacer.due(Duration::from_secs(5)), 18);
+
+        pacer.set_ipf(10);
+        assert_eq!(pacer.ips, 600);
+    }
+
+    #[test]
+    fn test_readout() {
+        let mut pacer = Pacer::new(700);
+        let start = Instant::now();
+        for i in 0..=30 {
+            pacer.frame(start + Duration::from_millis(i * 40), 20);
+        }
+        assert_eq!(pacer.fps().round(), 25.0);
+        assert_eq!(pacer.measured_ips().round(), 500.0);
+    }
+}
@@ -0,0 +1,185 @@
+//! Screen colours.
+//!
+//! A pixel's colour is picked by the planes it is lit in: CHIP-8 draws on
+//! one plane, and XO-CHIP on two, so a palette has four colours.
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// An RGB colour, written as `#RRGGBB`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color(pub [u8; 3]);
+
+impl Color {
+    /// The colour as fractions, for shaders and imgui.
+    pub fn to_f32(self) -> [f32; 3] {
+        let [r, g, b] = self.0;
+        [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0]
+    }
+
+    pub fn from_f32(rgb: [f32; 3]) -> Color {
+        let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Color([channel(rgb[0]), channel(rgb[1]), channel(rgb[2])])
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Color, String> {
+        let invalid = || format!("invalid colour '{}', expected #RRGGBB", text);
+        let hex = text.strip_prefix('#').ok_or_else(invalid)?;
+        // Non-ASCII text could put a channel boundary inside a character.
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut color = [0; 3];
+        for (i, channel) in color.iter_mut().enumerate() {
+            *channel = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Color(color))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{:02X}{:02X}{:02X}", r, g, b)
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Palette {
+    /// Unlit pixels.
+    pub background: Color,
+    /// Pixels lit in the first plane, the only one CHIP-8 has.
+    pub foreground: Color,
+    /// Pixels lit in the second XO-CHIP plane.
+    pub plane2: Color,
+    /// Pixels lit in both XO-CHIP planes.
+    pub planes: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        PRESETS[0].1.clone()
+    }
+}
+
+/// Built-in palettes, by name.
+pub const PRESETS: [(&str, Palette); 5] = [
+    (
+        "Classic",
+        Palette {
+            background: Color([0x00, 0x00, 0x00]),
+            foreground: Color([0xFF, 0xFF, 0xFF]),
+            plane2: Color([0xAA, 0xAA, 0xAA]),
+            planes: Color([0x55, 0x55, 0x55]),
+        },
+    ),
+    (
+        "Green phosphor",
+        Palette {
+            background: Color([0x08, 0x18, 0x0C]),
+            foreground: Color([0x33, 0xFF, 0x66]),
+            plane2: Color([0x1A, 0x99, 0x3D]),
+            planes: Color([0xB3, 0xFF, 0xC6]),
+        },
+    ),
+    (
+        "Amber",
+        Palette {
+            background: Color([0x1A, 0x10, 0x00]),
+            foreground: Color([0xFF, 0xB0, 0x00]),
+            plane2: Color([0x99, 0x66, 0x00]),
+            planes: Color([0xFF, 0xE0, 0x99]),
+        },
+    ),
+    (
+        "LCD",
+        Palette {
+            background: Color([0x9B, 0xBC, 0x0F]),
+            foreground: Color([0x0F, 0x38, 0x0F]),
+            plane2: Color([0x8B, 0xAC, 0x0F]),
+            planes: Color([0x30, 0x62, 0x30]),
+        },
+    ),
+    (
+        "High contrast",
+        Palette {
+            background: Color([0x00, 0x00, 0x00]),
+            foreground: Color([0xFF, 0xFF, 0x00]),
+            plane2: Color([0x00, 0xFF, 0xFF]),
+            planes: Color([0xFF, 0xFF, 0xFF]),
+        },
+    ),
+];
+
+impl Palette {
+    pub fn preset(name: &str) -> Option<Palette> {
+        PRESETS
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, palette)| palette.clone())
+    }
+
+    /// The name of the preset with these colours, if any.
+    pub fn preset_name(&self) -> Option<&'static str> {
+        PRESETS
+            .iter()
+            .find(|(_, palette)| palette == self)
+            .map(|(name, _)| *name)
+    }
+
+    /// Colours by the planes a pixel is lit in, bit 0 for the first plane.
+    pub fn colors(&self) -> [Color; 4] {
+        [self.background, self.foreground, self.plane2, self.planes]
+    }
+
+    pub fn colors_mut(&mut self) -> [&mut Color; 4] {
+        [
+            &mut self.background,
+            &mut self.foreground,
+            &mut self.plane2,
+            &mut self.planes,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_colors() {
+        let color: Color = "#0a0B0c".parse().unwrap();
+        assert_eq!(color, Color([10, 11, 12]));
+        assert_eq!(color.to_string(), "#0A0B0C");
+        assert_eq!(Color::from_f32(color.to_f32()), color);
+        assert!("0A0B0C".parse::<Color>().is_err());
+        assert!("#0A0B0".parse::<Color>().is_err());
+        assert!("#aébbb".parse::<Color>().is_err());
+
+        let amber = Palette::preset("amber").unwrap();
+        assert_eq!(amber.preset_name(), Some("Amber"));
+        assert_eq!(amber.colors()[1], Color([0xFF, 0xB0, 0x00]));
+        assert_eq!(Palette::default().preset_name(), Some("Classic"));
+        assert_eq!(Palette::preset("sepia"), None);
+    }
+}
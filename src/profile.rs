@@ -0,0 +1,296 @@
+//! Execution profiler.
+//!
+//! A `Profiler` attached to a `Machine` counts how often each address and
+//! each opcode is executed, and times subroutines by pairing every CALL with
+//! the RET that ends it. Time is measured in executed instructions.
+
+use crate::disasm::{decode, Instruction, Syntax};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Cycles spent in one subroutine.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Subroutine {
+    pub calls: u64,
+    /// Instructions executed from the CALL to its RET, both included, along
+    /// with any nested calls.
+    pub total: u64,
+    /// Like `total`, without the nested calls.
+    pub own: u64,
+}
+
+/// An address and how often the instruction there ran.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hotspot {
+    pub addr: u16,
+    pub opcode: u16,
+    pub count: u64,
+}
+
+struct Frame {
+    target: u16,
+    start: u64,
+    children: u64,
+}
+
+pub struct Profiler {
+    total: u64,
+    addresses: Vec<u64>,
+    /// Last opcode seen at each address.
+    opcodes_at: Vec<u16>,
+    opcodes: Vec<u64>,
+    frames: Vec<Frame>,
+    subroutines: BTreeMap<u16, Subroutine>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            total: 0,
+            addresses: vec![0; 4096],
+            opcodes_at: vec![0; 4096],
+            opcodes: vec![0; 0x10000],
+            frames: Vec::new(),
+            subroutines: BTreeMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Profiler::new();
+    }
+
+    /// Counts the instruction `opcode` at `pc`, which is about to run.
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        let addr = pc as usize & 0xFFF;
+        self.total += 1;
+        self.addresses[addr] += 1;
+        self.opcodes_at[addr] = opcode;
+        self.opcodes[opcode as usize] += 1;
+        match decode(opcode) {
+            Instruction::Call(target) => self.frames.push(Frame {
+                target,
+                start: self.total - 1,
+                children: 0,
+            }),
+            Instruction::Return => {
+                if let Some(frame) = self.frames.pop() {
+                    let elapsed = self.total - frame.start;
+                    let entry = self.subroutines.entry(frame.target).or_default();
+                    entry.calls += 1;
+                    entry.total += elapsed;
+                    entry.own += elapsed - frame.children;
+                    if let Some(parent) = self.frames.last_mut() {
+                        parent.children += elapsed;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Number of instructions recorded.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The most executed addresses, busiest first.
+    pub fn hotspots(&self, limit: usize) -> Vec<Hotspot> {
+        let mut hotspots: Vec<Hotspot> = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(addr, count)| Hotspot {
+                addr: addr as u16,
+                opcode: self.opcodes_at[addr],
+                count: *count,
+            })
+            .collect();
+        hotspots.sort_by(|a, b| b.count.cmp(&a.count).then(a.addr.cmp(&b.addr)));
+        hotspots.truncate(limit);
+        hotspots
+    }
+
+    /// Executions per kind of instruction, most frequent first.
+    pub fn kinds(&self) -> Vec<(String, u64)> {
+        let mut kinds: BTreeMap<String, u64> = BTreeMap::new();
+        for (opcode, count) in self.opcodes.iter().enumerate() {
+            if *count > 0 {
+                *kinds.entry(kind(opcode as u16)).or_default() += count;
+            }
+        }
+        let mut kinds: Vec<(String, u64)> = kinds.into_iter().collect();
+        kinds.sort_by_key(|kind| Reverse(kind.1));
+        kinds
+    }
+
+    /// Subroutines by entry address, those taking the most time first.
+    /// Calls that have not returned yet are not included.
+    pub fn subroutines(&self) -> Vec<(u16, Subroutine)> {
+        let mut subroutines: Vec<(u16, Subroutine)> =
+            self.subroutines.iter().map(|(a, s)| (*a, *s)).collect();
+        subroutines.sort_by_key(|sub| Reverse(sub.1.total));
+        subroutines
+    }
+
+    /// Share of all recorded instructions, in percent.
+    pub fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    /// Writes a plain text report. `name` gives the label of an address.
+    pub fn write_text(
+        &self,
+        output: &mut dyn Write,
+        limit: usize,
+        name: &dyn Fn(u16) -> String,
+    ) -> io::Result<()> {
+        writeln!(output, "{} instructions", self.total)?;
+        writeln!(output)?;
+        writeln!(output, "Hotspots")?;
+        for spot in self.hotspots(limit) {
+            writeln!(
+                output,
+                "  {:03X} {:>10} {:>6.2}%  {:<20} {}",
+                spot.addr,
+                spot.count,
+                self.percent(spot.count),
+                Syntax::Chipper.format(spot.opcode),
+                name(spot.addr)
+            )?;
+        }
+        writeln!(output)?;
+        writeln!(output, "Instructions")?;
+        for (kind, count) in self.kinds() {
+            let percent = self.percent(count);
+            writeln!(output, "  {:<12} {:>10} {:>6.2}%", kind, count, percent)?;
+        }
+        writeln!(output)?;
+        writeln!(output, "Subroutines (calls, total, own)")?;
+        for (addr, sub) in self.subroutines() {
+            writeln!(
+                output,
+                "  {:03X} {:>8} {:>10} {:>10}  {}",
+                addr,
+                sub.calls,
+                sub.total,
+                sub.own,
+                name(addr)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes one row per executed address. The subroutine columns are
+    /// empty unless the address was the target of a CALL that returned.
+    pub fn write_csv(
+        &self,
+        output: &mut dyn Write,
+        name: &dyn Fn(u16) -> String,
+    ) -> io::Result<()> {
+        writeln!(
+            output,
+            "address,label,opcode,instruction,count,percent,calls,total,own"
+        )?;
+        let mut hotspots = self.hotspots(usize::MAX);
+        hotspots.sort_by_key(|spot| spot.addr);
+        for spot in hotspots {
+            let subroutine = match self.subroutines.get(&spot.addr) {
+                Some(sub) => format!("{},{},{}", sub.calls, sub.total, sub.own),
+                None => ",,".to_string(),
+            };
+            writeln!(
+                output,
+                "{:#05x},{},{:04X},\"{}\",{},{:.2},{}",
+                spot.addr,
+                name(spot.addr),
+                spot.opcode,
+                Syntax::Chipper.format(spot.opcode),
+                spot.count,
+                self.percent(spot.count),
+                subroutine
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the kind of instruction, e.g. `Draw` or `SkipEqImm`.
+pub fn kind(opcode: u16) -> String {
+    let name = format!("{:?}", decode(opcode));
+    match name.find('(') {
+        Some(end) => name[..end].to_string(),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chip::Machine;
+
+    // 200: CALL 206; 202: CALL 206; 204: JP 204
+    // 206: CALL 20C; 208: ADD V0, 1; 20A: RET; 20C: RET
+    const PROGRAM: [u8; 14] = [
+        0x22, 0x06, 0x22, 0x06, 0x12, 0x04, 0x22, 0x0C, 0x70, 0x01, 0x00, 0xEE, 0x00, 0xEE,
+    ];
+
+    fn run(cycles: usize) -> Profiler {
+        let mut machine = Machine::new(&PROGRAM);
+        machine.set_profiler(Some(Profiler::new()));
+        for _ in 0..cycles {
+            machine.cycle();
+        }
+        machine.set_profiler(None).unwrap()
+    }
+
+    #[test]
+    fn test_counts() {
+        let profiler = run(20);
+        assert_eq!(profiler.total(), 20);
+        let top = profiler.hotspots(1)[0];
+        assert_eq!((top.addr, top.opcode, top.count), (0x204, 0x1204, 10));
+        assert_eq!(profiler.kinds()[0], ("Jump".to_string(), 10));
+        assert_eq!(kind(0xD015), "Draw");
+        assert_eq!(kind(0x00E0), "Clear");
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let profiler = run(20);
+        let subroutines = profiler.subroutines();
+        let inner = Subroutine {
+            calls: 2,
+            total: 4,
+            own: 4,
+        };
+        let outer = Subroutine {
+            calls: 2,
+            total: 10,
+            own: 6,
+        };
+        assert_eq!(subroutines, vec![(0x206, outer), (0x20C, inner)]);
+    }
+
+    #[test]
+    fn test_csv() {
+        let profiler = run(20);
+        let mut output = Vec::new();
+        profiler.write_csv(&mut output, &|_| String::new()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[4], "0x206,,220C,\"CALL  #20C\",2,10.00,2,10,6");
+    }
+}
@@ -0,0 +1,404 @@
+//! Drawing the CHIP-8 screen into the window.
+//!
+//! A frame is drawn in passes. The first turns the planes each pixel is lit
+//! in into weights of the palette's colours, blending in earlier frames to
+//! keep erased pixels visible for a while. Scale2x then doubles the weights,
+//! when it is on, and the last pass colours them with the palette through
+//! the CRT effects.
+
+use chip8::effects::{Effects, Persistence, Scaler};
+use chip8::palette::Palette;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Sampler, SamplerWrapFunction};
+use glium::{
+    index, Display, DrawParameters, IndexBuffer, Program, Rect, Surface, Texture2d, VertexBuffer,
+};
+
+mod vertex {
+    #![allow(clippy::useless_transmute)]
+
+    #[derive(Copy, Clone)]
+    pub struct Vertex {
+        pub position: [f32; 2],
+        pub tex_coords: [f32; 2],
+    }
+
+    implement_vertex!(Vertex, position, tex_coords);
+}
+
+use vertex::Vertex;
+
+const IDENTITY: [[f32; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+const VERTEX: &str = "
+    uniform mat4 matrix;
+
+    attribute vec2 position;
+    attribute vec2 tex_coords;
+
+    varying vec2 v_tex_coords;
+
+    void main() {
+        gl_Position = matrix * vec4(position, 0.0, 1.0);
+        v_tex_coords = tex_coords;
+    }
+";
+
+/// Weights of the foreground, second plane and both planes colours.
+const BLEND: &str = "
+    uniform sampler2D screen;
+    uniform sampler2D previous;
+    uniform sampler2D history;
+    uniform int mode;
+    uniform float decay;
+
+    varying vec2 v_tex_coords;
+
+    vec3 weights(sampler2D planes) {
+        float lit = floor(texture2D(planes, v_tex_coords).r * 255.0 + 0.5);
+        return vec3(lit == 1.0 ? 1.0 : 0.0, lit == 2.0 ? 1.0 : 0.0, lit == 3.0 ? 1.0 : 0.0);
+    }
+
+    void main() {
+        vec3 now = weights(screen);
+        if (mode == 1) {
+            now = max(now, texture2D(history, v_tex_coords).rgb * decay);
+        } else if (mode == 2) {
+            now = max(now, weights(previous));
+        }
+        gl_FragColor = vec4(now, 1.0);
+    }
+";
+
+/// Scale2x: each pixel becomes four, taking the colour of two neighbours
+/// that agree on a diagonal edge.
+const SCALE2X: &str = "
+    uniform sampler2D weights;
+    uniform vec2 size;
+
+    varying vec2 v_tex_coords;
+
+    bool same(vec3 a, vec3 b) {
+        return distance(a, b) < 0.01;
+    }
+
+    void main() {
+        vec2 texel = 1.0 / size;
+        vec2 position = v_tex_coords * size;
+        vec2 center = (floor(position) + 0.5) * texel;
+        vec2 quadrant = fract(position);
+        vec3 p = texture2D(weights, center).rgb;
+        vec3 above = texture2D(weights, center + vec2(0.0, texel.y)).rgb;
+        vec3 right = texture2D(weights, center + vec2(texel.x, 0.0)).rgb;
+        vec3 left = texture2D(weights, center - vec2(texel.x, 0.0)).rgb;
+        vec3 below = texture2D(weights, center - vec2(0.0, texel.y)).rgb;
+        vec3 color = p;
+        if (quadrant.y >= 0.5) {
+            if (quadrant.x < 0.5) {
+                if (same(left, above) && !same(left, below) && !same(above, right)) color = above;
+            } else {
+                if (same(above, right) && !same(above, left) && !same(right, below)) color = right;
+            }
+        } else {
+            if (quadrant.x < 0.5) {
+                if (same(below, left) && !same(below, right) && !same(left, above)) color = left;
+            } else {
+                if (same(right, below) && !same(right, above) && !same(below, left)) color = below;
+            }
+        }
+        gl_FragColor = vec4(color, 1.0);
+    }
+";
+
+const COLOR: &str = "
+    uniform sampler2D weights;
+    uniform sampler2D glow;
+    uniform vec2 size;
+    uniform vec3 background;
+    uniform vec3 foreground;
+    uniform vec3 plane2;
+    uniform vec3 planes;
+    uniform float scanlines;
+    uniform float curvature;
+    uniform float bloom;
+    uniform float grid;
+
+    varying vec2 v_tex_coords;
+
+    vec3 shade(vec3 w) {
+        vec3 color = mix(background, foreground, w.r);
+        color = mix(color, plane2, w.g);
+        return mix(color, planes, w.b);
+    }
+
+    void main() {
+        vec2 uv = v_tex_coords * 2.0 - 1.0;
+        uv *= 1.0 + curvature * 0.25 * uv.yx * uv.yx;
+        uv = uv * 0.5 + 0.5;
+        if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+            gl_FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+            return;
+        }
+        vec3 color = shade(texture2D(weights, uv).rgb);
+        if (bloom > 0.0) {
+            vec2 texel = 1.0 / size;
+            vec3 sum = vec3(0.0);
+            for (int i = -2; i <= 2; i++) {
+                for (int j = -2; j <= 2; j++) {
+                    vec2 offset = vec2(float(i), float(j)) * texel;
+                    sum += shade(texture2D(glow, uv + offset).rgb);
+                }
+            }
+            color += bloom * max(sum / 25.0 - background, 0.0);
+        }
+        vec2 cell = fract(uv * vec2(64.0, 32.0));
+        float row = sin(cell.y * 3.14159265);
+        color *= 1.0 - scanlines * (1.0 - row * row);
+        vec2 edge = min(cell, 1.0 - cell);
+        color *= 1.0 - grid * (1.0 - smoothstep(0.0, 0.15, min(edge.x, edge.y)));
+        gl_FragColor = vec4(color, 1.0);
+    }
+";
+
+/// Shaders are written for GLSL 1.10; these make them build for the other
+/// versions.
+fn vertex_source(version: u32) -> String {
+    match version {
+        140 => {
+            let body = VERTEX.replace("attribute", "in").replace("varying", "out");
+            format!("#version 140\n{}", body)
+        }
+        _ => format!("#version {}\n{}", version, VERTEX),
+    }
+}
+
+fn fragment_source(version: u32, body: &str) -> String {
+    match version {
+        140 => {
+            let body = body
+                .replace("varying", "in")
+                .replace("texture2D", "texture")
+                .replace("gl_FragColor", "f_color");
+            format!("#version 140\nout vec4 f_color;\n{}", body)
+        }
+        100 => format!("#version 100\nprecision mediump float;\n{}", body),
+        _ => format!("#version {}\n{}", version, body),
+    }
+}
+
+fn program(display: &Display, fragment: &str) -> Program {
+    program!(display,
+        140 => {
+            vertex: &vertex_source(140),
+            fragment: &fragment_source(140, fragment),
+        },
+        110 => {
+            vertex: &vertex_source(110),
+            fragment: &fragment_source(110, fragment),
+        },
+        100 => {
+            vertex: &vertex_source(100),
+            fragment: &fragment_source(100, fragment),
+        },
+    )
+    .unwrap()
+}
+
+/// A texture to draw weights into, in half floats where they are supported
+/// so that slow fades don't get stuck.
+fn weights_texture(display: &Display, width: u32, height: u32) -> Texture2d {
+    let texture = Texture2d::empty_with_format(
+        display,
+        UncompressedFloatFormat::F16F16F16F16,
+        MipmapsOption::NoMipmap,
+        width,
+        height,
+    )
+    .or_else(|_| Texture2d::empty(display, width, height))
+    .unwrap();
+    texture.as_surface().clear_color(0.0, 0.0, 0.0, 1.0);
+    texture
+}
+
+fn nearest(texture: &Texture2d) -> Sampler<'_, Texture2d> {
+    texture
+        .sampled()
+        .wrap_function(SamplerWrapFunction::Clamp)
+        .magnify_filter(MagnifySamplerFilter::Nearest)
+        .minify_filter(MinifySamplerFilter::Nearest)
+}
+
+fn linear(texture: &Texture2d) -> Sampler<'_, Texture2d> {
+    texture
+        .sampled()
+        .wrap_function(SamplerWrapFunction::Clamp)
+        .magnify_filter(MagnifySamplerFilter::Linear)
+        .minify_filter(MinifySamplerFilter::Linear)
+}
+
+pub struct Screen {
+    vertex_buffer: VertexBuffer<Vertex>,
+    index_buffer: IndexBuffer<u16>,
+    blend: Program,
+    scale2x: Program,
+    color: Program,
+    /// Weights of the last two frames, drawn into in turn.
+    weights: [Texture2d; 2],
+    /// The last weights doubled by Scale2x.
+    scaled: Texture2d,
+    /// Which of `weights` holds the last frame.
+    last: usize,
+    /// Planes of the last frame.
+    previous: Option<Texture2d>,
+}
+
+impl Screen {
+    pub fn new(display: &Display) -> Screen {
+        let vertex_buffer = VertexBuffer::new(
+            display,
+            &[
+                Vertex {
+                    position: [-1.0, -1.0],
+                    tex_coords: [0.0, 0.0],
+                },
+                Vertex {
+                    position: [-1.0, 1.0],
+                    tex_coords: [0.0, 1.0],
+                },
+                Vertex {
+                    position: [1.0, 1.0],
+                    tex_coords: [1.0, 1.0],
+                },
+                Vertex {
+                    position: [1.0, -1.0],
+                    tex_coords: [1.0, 0.0],
+                },
+            ],
+        )
+        .unwrap();
+        let index_buffer = IndexBuffer::new(
+            display,
+            index::PrimitiveType::TriangleStrip,
+            &[1u16, 2, 0, 3],
+        )
+        .unwrap();
+        Screen {
+            vertex_buffer,
+            index_buffer,
+            blend: program(display, BLEND),
+            scale2x: program(display, SCALE2X),
+            color: program(display, COLOR),
+            weights: [
+                weights_texture(display, 64, 32),
+                weights_texture(display, 64, 32),
+            ],
+            scaled: weights_texture(display, 128, 64),
+            last: 0,
+            previous: None,
+        }
+    }
+
+    /// Draws a frame, given as the texture of its pixels' planes, into
+    /// `viewport` of `target`: left, bottom, width and height. The rest of
+    /// the target is cleared.
+    pub fn draw<S: Surface>(
+        &mut self,
+        target: &mut S,
+        viewport: [u32; 4],
+        planes: Texture2d,
+        palette: &Palette,
+        effects: &Effects,
+    ) {
+        let next = 1 - self.last;
+        let mode = match effects.persistence {
+            Persistence::Off => 0,
+            Persistence::Phosphor => 1,
+            Persistence::MaxOfTwo => 2,
+        };
+        let previous = self.previous.as_ref().unwrap_or(&planes);
+        let uniforms = uniform! {
+            matrix: IDENTITY,
+            screen: nearest(&planes),
+            previous: nearest(previous),
+            history: nearest(&self.weights[self.last]),
+            mode: mode,
+            decay: effects.decay(),
+        };
+        self.weights[next]
+            .as_surface()
+            .draw(
+                &self.vertex_buffer,
+                &self.index_buffer,
+                &self.blend,
+                &uniforms,
+                &Default::default(),
+            )
+            .unwrap();
+        self.last = next;
+        self.previous = Some(planes);
+
+        let mut weights = &self.weights[next];
+        if effects.scaler == Scaler::Scale2x {
+            let uniforms = uniform! {
+                matrix: IDENTITY,
+                weights: nearest(weights),
+                size: [64.0f32, 32.0],
+            };
+            self.scaled
+                .as_surface()
+                .draw(
+                    &self.vertex_buffer,
+                    &self.index_buffer,
+                    &self.scale2x,
+                    &uniforms,
+                    &Default::default(),
+                )
+                .unwrap();
+            weights = &self.scaled;
+        }
+
+        let sampler = match effects.scaler {
+            Scaler::Smooth => linear(weights),
+            Scaler::Nearest | Scaler::Scale2x => nearest(weights),
+        };
+        let [scanlines, curvature, bloom, grid] = effects.crt();
+        let uniforms = uniform! {
+            matrix: IDENTITY,
+            weights: sampler,
+            glow: linear(weights),
+            size: [weights.width() as f32, weights.height() as f32],
+            background: palette.background.to_f32(),
+            foreground: palette.foreground.to_f32(),
+            plane2: palette.plane2.to_f32(),
+            planes: palette.planes.to_f32(),
+            scanlines: scanlines,
+            curvature: curvature,
+            bloom: bloom,
+            grid: grid,
+        };
+        let [left, bottom, width, height] = viewport;
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
+        target
+            .draw(
+                &self.vertex_buffer,
+                &self.index_buffer,
+                &self.color,
+                &uniforms,
+                &DrawParameters {
+                    viewport: Some(Rect {
+                        left,
+                        bottom,
+                        width,
+                        height,
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+}
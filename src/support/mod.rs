@@ -2,10 +2,11 @@ use glium::glutin;
 use glium::glutin::event::{Event, WindowEvent};
 use glium::glutin::event_loop::{ControlFlow, EventLoop};
 use glium::glutin::window::WindowBuilder;
-use glium::{Display, Surface, Frame};
+use glium::{Display, Frame};
 use imgui::{Context, FontConfig, FontGlyphRanges, FontSource, Ui};
 use imgui_glium_renderer::Renderer;
 use imgui_winit_support::{HiDpiMode, WinitPlatform};
+use std::path::PathBuf;
 use std::time::Instant;
 
 mod clipboard;
@@ -16,10 +17,10 @@ pub struct System {
     pub imgui: Context,
     pub platform: WinitPlatform,
     pub renderer: Renderer,
-    pub font_size: f32,
 }
 
-pub fn init(title: &str) -> System {
+/// Opens the window, restoring the imgui window positions from `layout`.
+pub fn init(title: &str, size: [f64; 2], layout: Option<&str>) -> System {
     let title = match title.rfind('/') {
         Some(idx) => title.split_at(idx + 1).1,
         None => title,
@@ -28,12 +29,15 @@ pub fn init(title: &str) -> System {
     let context = glutin::ContextBuilder::new().with_vsync(true);
     let builder = WindowBuilder::new()
         .with_title(title.to_owned())
-        .with_inner_size(glutin::dpi::LogicalSize::new(1024f64, 768f64));
+        .with_inner_size(glutin::dpi::LogicalSize::new(size[0], size[1]));
     let display =
         Display::new(builder, context, &event_loop).expect("Failed to initialize display");
 
     let mut imgui = Context::create();
     imgui.set_ini_filename(None);
+    if let Some(layout) = layout {
+        imgui.load_ini_settings(layout);
+    }
 
     if let Some(backend) = clipboard::init() {
         imgui.set_clipboard_backend(Box::new(backend));
@@ -78,14 +82,20 @@ pub fn init(title: &str) -> System {
         imgui,
         platform,
         renderer,
-        font_size,
     }
 }
 
 impl System {
-    pub fn main_loop<F: FnMut(&mut bool, &mut Ui, &Display, &mut Renderer, &mut Frame) + 'static>(
+    /// Calls `run_ui` every frame, with the files dropped on the window since
+    /// the previous one, and `on_exit` with the window layout when the loop
+    /// ends.
+    pub fn main_loop<
+        F: FnMut(&mut bool, &mut Ui, &Display, &mut Renderer, &mut Frame, &[PathBuf]) + 'static,
+        E: FnOnce(String) + 'static,
+    >(
         self,
         mut run_ui: F,
+        on_exit: E,
     ) {
         let System {
             event_loop,
@@ -96,6 +106,8 @@ impl System {
             ..
         } = self;
         let mut last_frame = Instant::now();
+        let mut dropped = Vec::new();
+        let mut on_exit = Some(on_exit);
 
         event_loop.run(move |event, _, control_flow| match event {
             Event::NewEvents(_) => {
@@ -106,7 +118,7 @@ impl System {
             Event::MainEventsCleared => {
                 let gl_window = display.gl_window();
                 platform
-                    .prepare_frame(imgui.io_mut(), &gl_window.window())
+                    .prepare_frame(imgui.io_mut(), gl_window.window())
                     .expect("Failed to prepare frame");
                 gl_window.window().request_redraw();
             }
@@ -116,7 +128,8 @@ impl System {
                 let mut run = true;
                 let gl_window = display.gl_window();
                 let mut target = display.draw();
-                run_ui(&mut run, &mut ui, &display, &mut renderer, &mut target);
+                run_ui(&mut run, &mut ui, &display, &mut renderer, &mut target, &dropped);
+                dropped.clear();
                 if !run {
                     *control_flow = ControlFlow::Exit;
                 }
@@ -132,6 +145,17 @@ impl System {
                 event: WindowEvent::CloseRequested,
                 ..
             } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                event: WindowEvent::DroppedFile(path),
+                ..
+            } => dropped.push(path),
+            Event::LoopDestroyed => {
+                if let Some(on_exit) = on_exit.take() {
+                    let mut layout = String::new();
+                    imgui.save_ini_settings(&mut layout);
+                    on_exit(layout);
+                }
+            }
             event => {
                 let gl_window = display.gl_window();
                 platform.handle_event(imgui.io_mut(), gl_window.window(), &event);
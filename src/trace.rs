@@ -0,0 +1,460 @@
+//! Execution tracing.
+//!
+//! Each executed instruction is logged as one line holding the machine
+//! state just before the instruction runs:
+//!
+//! ```text
+//! 00000002 204 D015 DRW   V0, V1, 5      | V 05 0A 00 00 00 00 00 00 00 00 00 00 00 00 00 00 | I 050 SP 0 DT 00 ST 00
+//! ```
+//!
+//! The fields are the cycle count (decimal), PC, opcode, the Chipper
+//! disassembly, V0–VF, I, SP, DT and ST. Everything but the disassembly is
+//! fixed width, so traces of the same program can be compared with `diff`.
+//! Tracers made `with_writes` (`--trace-writes`) add a last field listing
+//! the memory the instruction wrote, e.g. `| M 2F0=05 2F1=00`.
+
+use crate::chip::Machine;
+use crate::disasm::Syntax;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+/// Formats the trace line for the instruction at the current PC.
+pub fn format(machine: &Machine) -> String {
+    let memory = machine.memory();
+    let pc = machine.pc() as usize;
+    let opcode = (memory[pc & 0xFFF] as u16) << 8 | memory[(pc + 1) & 0xFFF] as u16;
+    let registers: Vec<String> = machine
+        .registers()
+        .iter()
+        .map(|v| format!("{:02X}", v))
+        .collect();
+    format!(
+        "{:08} {:03X} {:04X} {:<20} | V {} | I {:03X} SP {:X} DT {:02X} ST {:02X}",
+        machine.cycles(),
+        pc,
+        opcode,
+        Syntax::Chipper.format(opcode),
+        registers.join(" "),
+        machine.index(),
+        machine.stack().len(),
+        machine.delay_timer(),
+        machine.sound_timer(),
+    )
+}
+
+pub struct Tracer {
+    output: Box<dyn Write>,
+    range: Option<RangeInclusive<u16>>,
+    writes: bool,
+    /// The line of the instruction being executed and the memory it wrote.
+    pending: Option<(String, Vec<(u16, u8)>)>,
+    /// The last line written.
+    last: Option<String>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Tracer {
+        Tracer {
+            output,
+            range: None,
+            writes: false,
+            pending: None,
+            last: None,
+        }
+    }
+
+    /// Only traces instructions whose address lies in `range`.
+    pub fn with_range(output: Box<dyn Write>, range: RangeInclusive<u16>) -> Tracer {
+        Tracer {
+            range: Some(range),
+            ..Tracer::new(output)
+        }
+    }
+
+    /// Whether lines list the memory their instruction wrote.
+    pub fn with_writes(self, writes: bool) -> Tracer {
+        Tracer { writes, ..self }
+    }
+
+    /// Called by the machine before executing the instruction at the PC.
+    pub fn begin(&mut self, machine: &Machine) {
+        self.pending = match &self.range {
+            Some(range) if !range.contains(&machine.pc()) => None,
+            _ => Some((format(machine), Vec::new())),
+        };
+    }
+
+    /// Called by the machine for every byte the instruction stores.
+    pub fn write(&mut self, addr: u16, value: u8) {
+        if let (true, Some((_, writes))) = (self.writes, &mut self.pending) {
+            writes.push((addr, value));
+        }
+    }
+
+    /// Called by the machine once the instruction has run.
+    pub fn end(&mut self) -> io::Result<()> {
+        let (mut line, writes) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        if !writes.is_empty() {
+            let writes: Vec<String> = writes
+                .iter()
+                .map(|(addr, value)| format!("{:03X}={:02X}", addr, value))
+                .collect();
+            line += &format!(" | M {}", writes.join(" "));
+        }
+        writeln!(self.output, "{}", line)?;
+        self.last = Some(line);
+        Ok(())
+    }
+
+    /// Takes the last line written, if not taken yet.
+    pub fn take_last(&mut self) -> Option<String> {
+        self.last.take()
+    }
+}
+
+/// One parsed trace line. The disassembly is not kept, as it depends on
+/// the syntax the trace was written with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub registers: [u8; 16],
+    pub index: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub writes: Vec<(u16, u8)>,
+}
+
+impl Record {
+    pub fn parse(line: &str) -> Result<Record, String> {
+        let error = || format!("malformed trace line '{}'", line);
+        let hex = |text: Option<&str>| {
+            text.and_then(|t| u16::from_str_radix(t, 16).ok())
+                .ok_or_else(error)
+        };
+        let fields: Vec<&str> = line.split(" | ").collect();
+        if fields.len() < 3 {
+            return Err(error());
+        }
+        let mut head = fields[0].split_whitespace();
+        let cycle = head.next().and_then(|c| c.parse().ok()).ok_or_else(error)?;
+        let pc = hex(head.next())?;
+        let opcode = hex(head.next())?;
+
+        let values: Vec<&str> = fields[1].split_whitespace().collect();
+        if values.len() != 17 || values[0] != "V" {
+            return Err(error());
+        }
+        let mut registers = [0; 16];
+        for (register, value) in registers.iter_mut().zip(values[1..].iter()) {
+            *register = hex(Some(value))? as u8;
+        }
+
+        let state: Vec<&str> = fields[2].split_whitespace().collect();
+        if state.len() != 8 || state[0] != "I" || state[2] != "SP" {
+            return Err(error());
+        }
+        let mut writes = Vec::new();
+        if let Some(memory) = fields.get(3) {
+            let mut values = memory.split_whitespace();
+            if values.next() != Some("M") {
+                return Err(error());
+            }
+            for write in values {
+                let (addr, value) = write.split_once('=').ok_or_else(error)?;
+                writes.push((hex(Some(addr))?, hex(Some(value))? as u8));
+            }
+        }
+        Ok(Record {
+            cycle,
+            pc,
+            opcode,
+            registers,
+            index: hex(state.get(1).copied())?,
+            sp: hex(state.get(3).copied())? as u8,
+            delay_timer: hex(state.get(5).copied())? as u8,
+            sound_timer: hex(state.get(7).copied())? as u8,
+            writes,
+        })
+    }
+
+    /// Describes the first field in which `other` differs from `self`.
+    pub fn difference(&self, other: &Record) -> Option<String> {
+        if self.pc != other.pc {
+            return Some(format!("PC {:03X} != {:03X}", self.pc, other.pc));
+        }
+        if self.opcode != other.opcode {
+            return Some(format!(
+                "opcode {:04X} != {:04X}",
+                self.opcode, other.opcode
+            ));
+        }
+        for (i, (a, b)) in self
+            .registers
+            .iter()
+            .zip(other.registers.iter())
+            .enumerate()
+        {
+            if a != b {
+                return Some(format!("V{:X} {:02X} != {:02X}", i, a, b));
+            }
+        }
+        let state = [
+            ("I", self.index, other.index),
+            ("SP", self.sp as u16, other.sp as u16),
+            ("DT", self.delay_timer as u16, other.delay_timer as u16),
+            ("ST", self.sound_timer as u16, other.sound_timer as u16),
+        ];
+        for (name, a, b) in state.iter() {
+            if a != b {
+                return Some(format!("{} {:X} != {:X}", name, a, b));
+            }
+        }
+        let written = |writes: &[(u16, u8)], addr: u16| {
+            writes
+                .iter()
+                .find(|(a, _)| *a == addr)
+                .map_or("unchanged".to_string(), |(_, v)| format!("{:02X}", v))
+        };
+        let mut addresses: Vec<u16> = self
+            .writes
+            .iter()
+            .chain(other.writes.iter())
+            .map(|w| w.0)
+            .collect();
+        addresses.sort_unstable();
+        for addr in addresses {
+            let (a, b) = (written(&self.writes, addr), written(&other.writes, addr));
+            if a != b {
+                return Some(format!("memory {:03X} {} != {}", addr, a, b));
+            }
+        }
+        None
+    }
+}
+
+/// Where two traces first differ.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Zero based index of the first differing line.
+    pub line: usize,
+    pub difference: String,
+    /// The last few lines up to and including the divergence.
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "traces diverge at line {}: {}",
+            self.line + 1,
+            self.difference
+        )?;
+        for (name, lines) in [("<", &self.left), (">", &self.right)].iter() {
+            for line in lines.iter() {
+                writeln!(f, "{} {}", name, line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of lines shown before a divergence.
+const CONTEXT: usize = 3;
+
+/// Compares two traces line by line. Returns the number of matching lines,
+/// or where they diverge. A trace ending early counts as a divergence.
+pub fn compare(
+    left: impl Iterator<Item = String>,
+    right: impl Iterator<Item = String>,
+) -> Result<usize, Divergence> {
+    let mut history: VecDeque<(String, String)> = VecDeque::new();
+    let mut left = left.fuse();
+    let mut right = right.fuse();
+    let mut line = 0;
+    loop {
+        let (a, b) = match (left.next(), right.next()) {
+            (None, None) => return Ok(line),
+            (a, b) => (a, b),
+        };
+        let difference = match (&a, &b) {
+            (Some(a), Some(b)) => match (Record::parse(a), Record::parse(b)) {
+                (Ok(x), Ok(y)) => x.difference(&y),
+                (Err(e), _) | (_, Err(e)) => Some(e),
+            },
+            (None, _) => Some("left trace ended".to_string()),
+            (_, None) => Some("right trace ended".to_string()),
+        };
+        let a = a.unwrap_or_default();
+        let b = b.unwrap_or_default();
+        if let Some(difference) = difference {
+            history.push_back((a, b));
+            let (left, right) = history.into_iter().unzip();
+            return Err(Divergence {
+                line,
+                difference,
+                left,
+                right,
+            });
+        }
+        history.push_back((a, b));
+        if history.len() > CONTEXT {
+            history.pop_front();
+        }
+        line += 1;
+    }
+}
+
+/// Produces the trace of a live run, one line per executed instruction,
+/// until the machine stops.
+pub struct Live {
+    machine: Machine,
+}
+
+impl Live {
+    pub fn new(mut machine: Machine, writes: bool) -> Live {
+        machine.set_tracer(Some(Tracer::new(Box::new(io::sink())).with_writes(writes)));
+        Live { machine }
+    }
+}
+
+impl Iterator for Live {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.machine.stop {
+            return None;
+        }
+        self.machine.cycle();
+        self.machine.tracer_mut()?.take_last()
+    }
+}
+
+/// Parses an address range such as `200-2FF` (hex, inclusive).
+pub fn parse_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    let error = || format!("invalid address range '{}'", text);
+    let (start, end) = text.split_once('-').ok_or_else(error)?;
+    let start = u16::from_str_radix(start.trim_start_matches("0x"), 16).map_err(|_| error())?;
+    let end = u16::from_str_radix(end.trim_start_matches("0x"), 16).map_err(|_| error())?;
+    Ok(start..=end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer whose contents stay readable after it was boxed.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(tracer: impl Fn(Box<dyn Write>) -> Tracer) -> Vec<String> {
+        let prog = [0x60, 0x05, 0x61, 0x0A, 0xA0, 0x50, 0x71, 0x01];
+        let output = Shared::default();
+        let mut machine = Machine::new(&prog);
+        machine.set_tracer(Some(tracer(Box::new(output.clone()))));
+        for _ in 0..4 {
+            machine.cycle();
+        }
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_format() {
+        let lines = run(Tracer::new);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[2],
+            "00000002 204 A050 LD    I, #050        \
+             | V 05 0A 00 00 00 00 00 00 00 00 00 00 00 00 00 00 | I 000 SP 0 DT 00 ST 00"
+        );
+        assert!(lines[3].starts_with("00000003 206 7101 ADD   V1, #01"));
+    }
+
+    #[test]
+    fn test_memory_writes() {
+        let prog = [0x60, 0x07, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06];
+        let lines: Vec<String> = Live::new(Machine::new(&prog), true).take(5).collect();
+        assert!(!lines[1].contains("| M"));
+        assert!(lines[2].ends_with(" | M 300=07"));
+        assert!(!lines[3].contains("| M"));
+        assert_eq!(
+            Record::parse(&lines[2]).unwrap().writes,
+            vec![(0x300, 0x07)]
+        );
+
+        // Without the option the format stays as it was.
+        let lines: Vec<String> = Live::new(Machine::new(&prog), false).take(5).collect();
+        assert!(lines.iter().all(|line| !line.contains("| M")));
+
+        // Writes made outside the range are not reported.
+        let output = Shared::default();
+        let tracer = Tracer::with_range(Box::new(output.clone()), 0x206..=0x206);
+        let mut machine = Machine::new(&prog);
+        machine.set_tracer(Some(tracer.with_writes(true)));
+        for _ in 0..5 {
+            machine.cycle();
+        }
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(!text.contains("| M"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let lines = run(Tracer::new);
+        let record = Record::parse(&lines[2]).unwrap();
+        assert_eq!((record.cycle, record.pc, record.opcode), (2, 0x204, 0xA050));
+        assert_eq!(&record.registers[..3], &[5, 10, 0]);
+        assert!(Record::parse("00000002 204 A050").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let lines = run(Tracer::new);
+        let same = compare(lines.clone().into_iter(), lines.clone().into_iter());
+        assert_eq!(same.unwrap(), 4);
+
+        let mut other = lines.clone();
+        other[3] = other[3].replace("| V 05 0A", "| V 05 0B");
+        let divergence = compare(lines.clone().into_iter(), other.into_iter()).unwrap_err();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.difference, "V1 0A != 0B");
+        assert_eq!(divergence.left.len(), 4);
+
+        let prog = [0x60, 0x05, 0x61, 0x0A, 0xA0, 0x50, 0x71, 0x01];
+        let live = Live::new(Machine::new(&prog), false);
+        let divergence = compare(lines.into_iter(), live).unwrap_err();
+        assert_eq!(divergence.line, 4);
+        assert_eq!(divergence.difference, "left trace ended");
+    }
+
+    #[test]
+    fn test_range() {
+        let lines = run(|output| Tracer::with_range(output, parse_range("202-204").unwrap()));
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("00000001 202"));
+        assert!(parse_range("200").is_err());
+    }
+}
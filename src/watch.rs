@@ -0,0 +1,105 @@
+//! Watching a loaded program for changes on disk.
+//!
+//! Files are polled by modification time, which is cheap enough to do a few
+//! times a second from the UI loop and needs no platform support.
+
+use crate::archive;
+use crate::asm::DebugMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub struct Watch {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Watch {
+    pub fn new(files: Vec<PathBuf>) -> Watch {
+        let files = files
+            .into_iter()
+            .map(|path| {
+                let time = modified(&path);
+                (path, time)
+            })
+            .collect();
+        Watch { files }
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Whether a file was modified, created or removed since the previous
+    /// call.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, time) in self.files.iter_mut() {
+            let now = modified(path);
+            if now != *time {
+                *time = now;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Files the program loaded from `path` is built from: the ROM, or the
+/// archive holding it, and the source named by its debug map.
+pub fn sources(path: &Path, map: Option<&DebugMap>) -> Vec<PathBuf> {
+    let mut files = vec![match archive::split(path) {
+        Some((archive, _)) => archive,
+        None => path.to_path_buf(),
+    }];
+    if let Some(map) = map {
+        if !files.contains(&map.source) {
+            files.push(map.source.clone());
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changes() {
+        let path = std::env::temp_dir().join(format!("chip8-watch-{}", std::process::id()));
+        fs::write(&path, [0x12, 0x00]).unwrap();
+        let mut watch = Watch::new(vec![path.clone()]);
+        assert!(!watch.changed());
+
+        // Rewritten within the timestamp resolution, the file would look
+        // unchanged, so it is given a time of its own.
+        fs::write(&path, [0x12, 0x02]).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+
+        fs::remove_file(&path).unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+    }
+
+    #[test]
+    fn test_sources() {
+        let pack = Path::new("assets/chp8_220.zip:CHIP8/GAMES/PONG");
+        assert_eq!(
+            sources(pack, None),
+            vec![PathBuf::from("assets/chp8_220.zip")]
+        );
+        let map = DebugMap {
+            source: PathBuf::from("games/PONG.SRC"),
+            ..DebugMap::default()
+        };
+        let files = sources(Path::new("games/PONG"), Some(&map));
+        assert_eq!(files, vec![PathBuf::from("games/PONG"), map.source.clone()]);
+        assert_eq!(sources(&map.source, Some(&map)), vec![map.source.clone()]);
+    }
+}